@@ -1,27 +1,44 @@
+use std::net::IpAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use anyhow::bail;
 use clap::{Clap, ValueHint};
 use cli_table::ColorChoice;
 
-use anyhow::bail;
 use hyperqueue::client::commands::jobs::{
-    cancel_job, get_last_job_id, output_job_detail, output_job_list,
+    cancel_job, follow_job, output_job_detail, output_job_list, resolve_job_ids,
+};
+use hyperqueue::client::commands::server::{
+    get_server_info, print_audit_log, print_server_log, rotate_keys,
 };
-use hyperqueue::client::commands::stop::stop_server;
+use hyperqueue::client::commands::stop::{drain_server, stop_server};
 use hyperqueue::client::commands::submit::{submit_computation, SubmitOpts};
+use hyperqueue::client::commands::top::run_dashboard;
 use hyperqueue::client::commands::worker::{get_worker_info, get_worker_list, stop_worker};
 use hyperqueue::client::globalsettings::GlobalSettings;
 use hyperqueue::client::job::Status;
 use hyperqueue::client::worker::print_worker_info;
+use hyperqueue::common::config::{
+    cli_or_optional_config, config_path, load_config, parse_config_value, Config, GlobalSection,
+    ServerSection, SubmitSection, WorkerSection,
+};
 use hyperqueue::common::fsutils::absolute_path;
+use hyperqueue::common::idset::IdSet;
+use hyperqueue::common::serverdir::validate_server_name;
 use hyperqueue::common::setup::setup_logging;
 use hyperqueue::common::timeutils::ArgDuration;
-use hyperqueue::server::bootstrap::{get_client_connection, init_hq_server, ServerConfig};
+use hyperqueue::server::audit::AuditFilter;
+use hyperqueue::server::bootstrap::{
+    export_access_record, get_client_connection, init_hq_server, print_server_info,
+    print_server_list, prune_server_instances, ServerConfig,
+};
+use hyperqueue::transfer::auth::AccessRole;
 use hyperqueue::transfer::messages::JobSelector;
 use hyperqueue::worker::hwdetect::{detect_resource, print_resource_descriptor};
-use hyperqueue::worker::output::print_worker_configuration;
+use hyperqueue::worker::output::{print_worker_configuration, print_worker_stats};
 use hyperqueue::worker::start::{start_hq_worker, WorkerStartOpts};
+use hyperqueue::worker::taskwrapper::{run_task_wrapper, RunTaskOpts};
 use hyperqueue::{JobId, WorkerId};
 
 #[global_allocator]
@@ -35,9 +52,13 @@ struct CommonOpts {
     #[clap(long, global = true, value_hint = ValueHint::DirPath)]
     server_dir: Option<PathBuf>,
 
-    /// Console color policy.
-    #[clap(long, default_value = "auto", possible_values = & ["auto", "always", "never"])]
-    colors: ColorPolicy,
+    /// Name of the server, servers with different names can share a server directory
+    #[clap(long, global = true)]
+    server_name: Option<String>,
+
+    /// Console color policy. (default: "auto")
+    #[clap(long, possible_values = & ["auto", "always", "never"])]
+    colors: Option<String>,
 }
 
 // Root CLI options
@@ -67,6 +88,10 @@ enum SubCommand {
     Cancel(CancelOpts),
     /// Commands for controlling HyperQueue workers
     Worker(WorkerOpts),
+    /// Interactive dashboard with jobs, workers and recent task failures
+    Top(TopOpts),
+    /// Commands for inspecting the HyperQueue configuration
+    Config(ConfigOpts),
 }
 
 // Server CLI options
@@ -79,11 +104,127 @@ struct ServerStartOpts {
 
     #[clap(long)]
     idle_timeout: Option<ArgDuration>,
+
+    /// IP address on which the server listens for clients and workers (default: "0.0.0.0")
+    #[clap(long)]
+    bind: Option<IpAddr>,
+
+    /// Port (e.g. `6000`) or ports (e.g. `6000-6010`) for connections of clients,
+    /// the first free port is used (default: any free port)
+    #[clap(long)]
+    client_port: Option<IdSet>,
+
+    /// Port (e.g. `6001`) or ports (e.g. `6011-6020`) for connections of workers,
+    /// the first free port is used (default: any free port)
+    #[clap(long)]
+    worker_port: Option<IdSet>,
+
+    /// Port of a HTTP endpoint that serves metrics of the server in the Prometheus format
+    /// at `/metrics` (default: disabled)
+    #[clap(long)]
+    metrics_port: Option<u16>,
+
+    /// IP address on which the metrics endpoint listens (default: "127.0.0.1")
+    #[clap(long)]
+    metrics_bind: Option<IpAddr>,
+
+    /// Shell command executed on the server host whenever a job ends
+    #[clap(long)]
+    on_job_finish: Option<String>,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ServerStopOpts {
+    /// Refuse new jobs, do not start tasks that have not started yet, wait for running tasks,
+    /// stop workers and then stop the server
+    #[clap(long)]
+    drain: bool,
+
+    /// Maximal time of waiting for running tasks when draining (default: no limit)
+    #[clap(long, requires("drain"))]
+    timeout: Option<ArgDuration>,
+
+    /// Cancel tasks that have not started yet when draining, so that their jobs end
+    /// (e.g. to run finish hooks of the jobs)
+    #[clap(long, requires("drain"))]
+    cancel_waiting: bool,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ServerExportAccessOpts {
+    /// Role of the exported access: monitor, submit, admin or worker
+    #[clap(long, default_value = "monitor")]
+    role: AccessRole,
+
+    /// Directory where the access file is written.
+    /// It can be then passed to `--server-dir` of other HyperQueue commands.
+    #[clap(value_hint = ValueHint::DirPath)]
+    target_dir: PathBuf,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ServerInfoOpts {
+    /// Print the information as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ServerRotateKeysOpts {
+    /// Replace also the key of workers. Connected workers are disconnected and their running
+    /// tasks are rescheduled; workers started with `--reconnect` connect again with the new key.
+    #[clap(long)]
+    workers: bool,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ServerListOpts {}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ServerLogsOpts {
+    /// Keep printing new lines of the log until the command is interrupted
+    #[clap(long)]
+    follow: bool,
 }
 
 #[derive(Clap)]
 #[clap(setting = clap::AppSettings::ColoredHelp)]
-struct ServerStopOpts {}
+struct ServerPruneOpts {
+    /// Remove also stopped instances that are active (i.e. pointed to by `hq-current` symlinks)
+    #[clap(long)]
+    force: bool,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ServerAuditOpts {
+    /// Show only operations of the given user
+    #[clap(long)]
+    user: Option<String>,
+
+    /// Show only operations of the given type
+    /// (submit, cancel, stop-worker, stop-server, drain-server, rotate-keys)
+    #[clap(long)]
+    operation: Option<String>,
+
+    /// Show only operations that submitted or canceled the given job
+    #[clap(long)]
+    job: Option<JobId>,
+
+    /// Maximal number of shown entries, the newest entries are shown
+    #[clap(long, default_value = "50")]
+    limit: usize,
+
+    /// Number of the newest entries that are skipped (for paging)
+    #[clap(long, default_value = "0")]
+    offset: usize,
+}
 
 #[derive(Clap)]
 #[clap(setting = clap::AppSettings::ColoredHelp)]
@@ -98,6 +239,20 @@ enum ServerCommand {
     Start(ServerStartOpts),
     /// Stop the HyperQueue server, if it is running
     Stop(ServerStopOpts),
+    /// Show information and statistics of a running HyperQueue server
+    Info(ServerInfoOpts),
+    /// Export an access file with restricted permissions (e.g. read-only monitoring)
+    ExportAccess(ServerExportAccessOpts),
+    /// Generate new keys of clients, previously exported access files stop working
+    RotateKeys(ServerRotateKeysOpts),
+    /// Show the log of operations that changed the state of the server
+    Audit(ServerAuditOpts),
+    /// List all servers stored in the server directory
+    List(ServerListOpts),
+    /// Remove directories of servers that are offline
+    Prune(ServerPruneOpts),
+    /// Show the log of the server
+    Logs(ServerLogsOpts),
 }
 
 // Worker CLI options
@@ -117,6 +272,10 @@ struct WorkerListOpts {
     /// shows offline workers
     #[clap(long)]
     offline: bool,
+
+    /// shows the last reported utilization of workers
+    #[clap(long)]
+    stats: bool,
 }
 
 #[derive(Clap)]
@@ -152,6 +311,9 @@ enum WorkerCommand {
     Info(WorkerInfoOpts),
     /// Print worker's hostname
     Address(WorkerAddressOpts),
+    /// Run a task program and record its resource usage (used internally by workers)
+    #[clap(setting = clap::AppSettings::Hidden)]
+    RunTask(RunTaskOpts),
 }
 
 // Job CLI options
@@ -160,129 +322,262 @@ enum WorkerCommand {
 #[clap(setting = clap::AppSettings::ColoredHelp)]
 struct JobListOpts {
     job_filters: Vec<Status>,
-}
-
-enum JobSelectorArg {
-    All,
-    Last,
-    Id(JobId),
-}
-
-impl FromStr for JobSelectorArg {
-    type Err = anyhow::Error;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "last" => Ok(JobSelectorArg::Last),
-            "all" => Ok(JobSelectorArg::All),
-            _ => Ok(JobSelectorArg::Id(FromStr::from_str(s)?)),
-        }
-    }
+    /// Show only jobs matching a selector (e.g. `3-10`, `last5` or `name:prep*`)
+    #[clap(long)]
+    select: Option<JobSelector>,
 }
 
 #[derive(Clap)]
 #[clap(setting = clap::AppSettings::ColoredHelp)]
 struct JobDetailOpts {
-    /// Numeric job id or `last` to display the most recently submitted job
-    job_specifier: JobSelectorArg,
+    /// Selector of jobs to display: job id(s) (e.g. `5`, `3-10`, `4,7,9`), `last`, `lastN`,
+    /// `all` or `name:<pattern>`
+    job_selector: JobSelector,
 
     // Include task info in the output
     #[clap(long)]
     tasks: bool,
+
+    /// Show aggregated resource usage of tasks of the job
+    #[clap(long)]
+    summary: bool,
+
+    /// Wait until the job ends and show its progress in the meantime.
+    /// The command fails if any task of the job does not finish successfully.
+    #[clap(long, conflicts_with_all = &["tasks", "summary"])]
+    follow: bool,
 }
 
 #[derive(Clap)]
 #[clap(setting = clap::AppSettings::ColoredHelp)]
 struct CancelOpts {
-    job_specifier: JobSelectorArg,
+    /// Selector of jobs to cancel: job id(s) (e.g. `5`, `3-10`, `4,7,9`), `last`, `lastN`,
+    /// `all` or `name:<pattern>`
+    job_selector: JobSelector,
+
+    /// Cancel only the selected tasks of the jobs (e.g. `10-20,35`)
+    #[clap(long)]
+    tasks: Option<IdSet>,
+}
+
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct TopOpts {
+    /// How often is the dashboard refreshed
+    #[clap(long, default_value = "1s")]
+    refresh: ArgDuration,
+}
+
+// Config CLI options
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::ColoredHelp)]
+struct ConfigOpts {
+    #[clap(subcommand)]
+    subcmd: ConfigCommand,
+}
+
+#[derive(Clap)]
+enum ConfigCommand {
+    /// Print the effective configuration (configuration file merged with built-in defaults)
+    Show,
 }
 
 // Commands
 async fn command_server_start(
     gsettings: GlobalSettings,
     opts: ServerStartOpts,
+    config: &ServerSection,
 ) -> anyhow::Result<()> {
+    let host = opts.host.or_else(|| config.host.clone());
+    let idle_timeout: Option<ArgDuration> = cli_or_optional_config(
+        opts.idle_timeout,
+        "server.idle-timeout",
+        config.idle_timeout.as_ref(),
+    )?;
+    let bind: IpAddr = cli_or_config(
+        opts.bind,
+        "server.bind",
+        config.bind.as_deref().unwrap_or("0.0.0.0"),
+    )?;
+    let metrics_bind: IpAddr = cli_or_config(
+        opts.metrics_bind,
+        "server.metrics-bind",
+        config.metrics_bind.as_deref().unwrap_or("127.0.0.1"),
+    )?;
+    let client_ports = cli_or_optional_config(
+        opts.client_port,
+        "server.client-port",
+        config.client_port.as_ref(),
+    )?;
+    let worker_ports = cli_or_optional_config(
+        opts.worker_port,
+        "server.worker-port",
+        config.worker_port.as_ref(),
+    )?;
+    // A server bound to a specific address is visible under this address by default
+    let host = host.unwrap_or_else(|| {
+        if bind.is_unspecified() {
+            gethostname::gethostname().into_string().unwrap()
+        } else {
+            bind.to_string()
+        }
+    });
     let server_cfg = ServerConfig {
-        host: opts
-            .host
-            .unwrap_or_else(|| gethostname::gethostname().into_string().unwrap()),
-        idle_timeout: opts.idle_timeout.map(|x| x.into_duration()),
+        host,
+        idle_timeout: idle_timeout.map(|x| x.into_duration()),
+        bind,
+        client_ports,
+        worker_ports,
+        metrics_port: opts.metrics_port.or(config.metrics_port),
+        metrics_bind,
+        on_job_finish: opts.on_job_finish.or_else(|| config.on_job_finish.clone()),
     };
     init_hq_server(&gsettings, server_cfg).await
 }
 
 async fn command_server_stop(
     gsettings: GlobalSettings,
-    _opts: ServerStopOpts,
+    opts: ServerStopOpts,
 ) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
-    stop_server(&mut connection).await?;
+    let mut connection = get_client_connection(&gsettings).await?;
+    if opts.drain {
+        drain_server(
+            &mut connection,
+            opts.timeout.map(|t| t.into_duration()),
+            opts.cancel_waiting,
+        )
+        .await?;
+    } else {
+        stop_server(&mut connection).await?;
+    }
     Ok(())
 }
 
+async fn command_server_info(
+    gsettings: GlobalSettings,
+    opts: ServerInfoOpts,
+) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(&gsettings).await?;
+    let response = get_server_info(&mut connection).await?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+    } else {
+        print_server_info(&gsettings, &response.info, Some(&response.stats));
+    }
+    Ok(())
+}
+
+fn command_server_export_access(
+    gsettings: GlobalSettings,
+    opts: ServerExportAccessOpts,
+) -> anyhow::Result<()> {
+    let path = export_access_record(&gsettings, opts.role, &opts.target_dir)?;
+    println!(
+        "Access file with the {} role was written to {}",
+        opts.role,
+        path.display()
+    );
+    Ok(())
+}
+
+async fn command_server_rotate_keys(
+    gsettings: GlobalSettings,
+    opts: ServerRotateKeysOpts,
+) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(&gsettings).await?;
+    rotate_keys(&mut connection, opts.workers).await?;
+    if opts.workers {
+        println!("Keys of clients and workers were rotated, workers were disconnected");
+    } else {
+        println!("Keys of clients were rotated, existing connections are not affected");
+    }
+    Ok(())
+}
+
+fn command_server_audit(gsettings: GlobalSettings, opts: ServerAuditOpts) -> anyhow::Result<()> {
+    let filter = AuditFilter {
+        user: opts.user,
+        operation: opts.operation,
+        job: opts.job,
+    };
+    print_audit_log(&gsettings, &filter, opts.limit, opts.offset)
+}
+
 async fn command_job_list(gsettings: GlobalSettings, opts: JobListOpts) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
-    output_job_list(&gsettings, &mut connection, opts.job_filters)
+    let mut connection = get_client_connection(&gsettings).await?;
+    output_job_list(&gsettings, &mut connection, opts.job_filters, opts.select)
         .await
         .map_err(|e| e.into())
 }
 
 async fn command_job_detail(gsettings: GlobalSettings, opts: JobDetailOpts) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
-
-    let job_id = match opts.job_specifier {
-        JobSelectorArg::Id(job_id) => job_id,
-        JobSelectorArg::Last => {
-            let id = get_last_job_id(&mut connection).await?;
-            match id {
-                Some(id) => id,
-                None => {
-                    log::warn!("No jobs were found");
-                    return Ok(());
-                }
-            }
-        }
-        JobSelectorArg::All => {
-            bail!("Specifier all is not implemented for job details, did you mean: job list?")
-        }
-    };
+    let mut connection = get_client_connection(&gsettings).await?;
+
+    if opts.follow {
+        let job_ids = resolve_job_ids(&mut connection, opts.job_selector).await?;
+        return match job_ids.as_slice() {
+            [job_id] => follow_job(&gsettings, &mut connection, *job_id).await,
+            [] => bail!("No jobs were found"),
+            _ => bail!("Only a single job can be followed"),
+        };
+    }
 
-    output_job_detail(&gsettings, &mut connection, job_id, opts.tasks)
-        .await
-        .map_err(|e| e.into())
+    output_job_detail(
+        &gsettings,
+        &mut connection,
+        opts.job_selector,
+        opts.tasks,
+        opts.summary,
+    )
+    .await
+    .map_err(|e| e.into())
 }
 
-async fn command_submit(gsettings: GlobalSettings, opts: SubmitOpts) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
-    submit_computation(&gsettings, &mut connection, opts).await
+async fn command_submit(
+    gsettings: GlobalSettings,
+    opts: SubmitOpts,
+    config: &SubmitSection,
+) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(&gsettings).await?;
+    submit_computation(&gsettings, &mut connection, opts, config).await
 }
 
 async fn command_cancel(gsettings: GlobalSettings, opts: CancelOpts) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
-
-    let selector: JobSelector = match opts.job_specifier {
-        JobSelectorArg::Id(job_id) => JobSelector::Specific(vec![job_id]),
-        JobSelectorArg::Last => JobSelector::LastN(1),
-        JobSelectorArg::All => JobSelector::All,
-    };
+    let mut connection = get_client_connection(&gsettings).await?;
 
-    cancel_job(&gsettings, &mut connection, selector)
+    cancel_job(&gsettings, &mut connection, opts.job_selector, opts.tasks)
         .await
         .map_err(|e| e.into())
 }
 
+async fn command_top(gsettings: GlobalSettings, opts: TopOpts) -> anyhow::Result<()> {
+    let mut connection = get_client_connection(&gsettings).await?;
+    run_dashboard(&mut connection, opts.refresh.into_duration()).await
+}
+
 async fn command_worker_start(
     gsettings: GlobalSettings,
     opts: WorkerStartOpts,
+    config: &WorkerSection,
 ) -> anyhow::Result<()> {
-    start_hq_worker(&gsettings, opts).await
+    start_hq_worker(&gsettings, opts, config).await
+}
+
+fn command_config_show(config: &Config) -> anyhow::Result<()> {
+    match config_path() {
+        Some(path) if path.exists() => println!("# Configuration file: {}", path.display()),
+        _ => println!("# No configuration file found, using built-in defaults"),
+    }
+    print!("{}", toml::to_string(config)?);
+    Ok(())
 }
 
 async fn command_worker_stop(
     gsettings: GlobalSettings,
     opts: WorkerStopOpts,
 ) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
+    let mut connection = get_client_connection(&gsettings).await?;
     stop_worker(&mut connection, opts.worker_id)
         .await
         .map_err(|e| e.into())
@@ -292,7 +587,7 @@ async fn command_worker_list(
     gsettings: GlobalSettings,
     opts: WorkerListOpts,
 ) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
+    let mut connection = get_client_connection(&gsettings).await?;
 
     // If --running and --offline was not set then show all workers
     let (online, offline) = if !opts.running && !opts.offline {
@@ -301,7 +596,7 @@ async fn command_worker_list(
         (opts.running, opts.offline)
     };
     let workers = get_worker_list(&mut connection, online, offline).await?;
-    print_worker_info(workers, &gsettings);
+    print_worker_info(workers, &gsettings, opts.stats);
     Ok(())
 }
 
@@ -309,17 +604,25 @@ async fn command_worker_info(
     gsettings: GlobalSettings,
     opts: WorkerInfoOpts,
 ) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
+    let mut connection = get_client_connection(&gsettings).await?;
     let response = get_worker_info(&mut connection, opts.worker_id).await?;
 
     if let Some(worker) = response {
         print_worker_configuration(&gsettings, opts.worker_id, worker.configuration);
+        if let Some(stats) = worker.stats {
+            print_worker_stats(&gsettings, &stats);
+        }
     } else {
         log::error!("Worker {} not found", opts.worker_id);
     }
     Ok(())
 }
 
+fn command_worker_run_task(opts: RunTaskOpts) -> anyhow::Result<()> {
+    let code = run_task_wrapper(opts)?;
+    std::process::exit(code);
+}
+
 fn command_worker_hwdetect() -> anyhow::Result<()> {
     let descriptor = detect_resource()?;
     print_resource_descriptor(&descriptor);
@@ -330,7 +633,7 @@ async fn command_worker_address(
     gsettings: GlobalSettings,
     opts: WorkerAddressOpts,
 ) -> anyhow::Result<()> {
-    let mut connection = get_client_connection(&gsettings.server_directory()).await?;
+    let mut connection = get_client_connection(&gsettings).await?;
     let response = get_worker_info(&mut connection, opts.worker_id).await?;
 
     match response {
@@ -360,14 +663,21 @@ impl FromStr for ColorPolicy {
     }
 }
 
-fn make_global_settings(opts: CommonOpts) -> GlobalSettings {
+/// Merges global options into the configuration and creates settings from them
+fn make_global_settings(
+    opts: CommonOpts,
+    config: &mut GlobalSection,
+) -> anyhow::Result<GlobalSettings> {
     fn default_server_directory_path() -> PathBuf {
         let mut home = dirs::home_dir().unwrap_or_else(std::env::temp_dir);
         home.push(".hq-server");
         home
     }
 
-    let color_policy = match opts.colors {
+    if let Some(colors) = opts.colors {
+        config.colors = colors;
+    }
+    let color_policy = match parse_config_value::<ColorPolicy>("global.colors", &config.colors)? {
         ColorPolicy::Always => ColorChoice::AlwaysAnsi,
         ColorPolicy::Auto => {
             if atty::is(atty::Stream::Stdout) {
@@ -381,10 +691,23 @@ fn make_global_settings(opts: CommonOpts) -> GlobalSettings {
 
     let server_dir = absolute_path(
         opts.server_dir
+            .or_else(|| config.server_dir.take())
             .unwrap_or_else(default_server_directory_path),
     );
+    config.server_dir = Some(server_dir.clone());
+
+    if let Some(name) = opts.server_name {
+        config.server_name = Some(name);
+    }
+    if let Some(name) = &config.server_name {
+        validate_server_name(name)?;
+    }
 
-    GlobalSettings::new(server_dir, color_policy)
+    Ok(GlobalSettings::new(
+        server_dir,
+        config.server_name.clone(),
+        color_policy,
+    ))
 }
 
 fn set_colored_settings(settings: &GlobalSettings) {
@@ -395,25 +718,43 @@ fn set_colored_settings(settings: &GlobalSettings) {
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> hyperqueue::Result<()> {
-    let top_opts: Opts = Opts::parse();
-    setup_logging();
-
-    let gsettings = make_global_settings(top_opts.common);
+async fn run_command(common: CommonOpts, subcmd: SubCommand) -> anyhow::Result<()> {
+    let mut config = load_config()?;
+    let gsettings = make_global_settings(common, &mut config.global)?;
     set_colored_settings(&gsettings);
 
-    let result = match top_opts.subcmd {
+    match subcmd {
         SubCommand::Server(ServerOpts {
             subcmd: ServerCommand::Start(opts),
-        }) => command_server_start(gsettings, opts).await,
+        }) => command_server_start(gsettings, opts, &config.server).await,
         SubCommand::Server(ServerOpts {
             subcmd: ServerCommand::Stop(opts),
         }) => command_server_stop(gsettings, opts).await,
+        SubCommand::Server(ServerOpts {
+            subcmd: ServerCommand::Info(opts),
+        }) => command_server_info(gsettings, opts).await,
+        SubCommand::Server(ServerOpts {
+            subcmd: ServerCommand::ExportAccess(opts),
+        }) => command_server_export_access(gsettings, opts),
+        SubCommand::Server(ServerOpts {
+            subcmd: ServerCommand::RotateKeys(opts),
+        }) => command_server_rotate_keys(gsettings, opts).await,
+        SubCommand::Server(ServerOpts {
+            subcmd: ServerCommand::Audit(opts),
+        }) => command_server_audit(gsettings, opts),
+        SubCommand::Server(ServerOpts {
+            subcmd: ServerCommand::List(_),
+        }) => print_server_list(&gsettings).await,
+        SubCommand::Server(ServerOpts {
+            subcmd: ServerCommand::Prune(opts),
+        }) => prune_server_instances(&gsettings, opts.force).await,
+        SubCommand::Server(ServerOpts {
+            subcmd: ServerCommand::Logs(opts),
+        }) => print_server_log(&gsettings, opts.follow).await,
 
         SubCommand::Worker(WorkerOpts {
             subcmd: WorkerCommand::Start(opts),
-        }) => command_worker_start(gsettings, opts).await,
+        }) => command_worker_start(gsettings, opts, &config.worker).await,
         SubCommand::Worker(WorkerOpts {
             subcmd: WorkerCommand::Stop(opts),
         }) => command_worker_stop(gsettings, opts).await,
@@ -429,10 +770,31 @@ async fn main() -> hyperqueue::Result<()> {
         SubCommand::Worker(WorkerOpts {
             subcmd: WorkerCommand::Address(opts),
         }) => command_worker_address(gsettings, opts).await,
+        SubCommand::Worker(WorkerOpts {
+            subcmd: WorkerCommand::RunTask(opts),
+        }) => command_worker_run_task(opts),
         SubCommand::Jobs(opts) => command_job_list(gsettings, opts).await,
         SubCommand::Job(opts) => command_job_detail(gsettings, opts).await,
-        SubCommand::Submit(opts) => command_submit(gsettings, opts).await,
+        SubCommand::Submit(opts) => command_submit(gsettings, opts, &config.submit).await,
         SubCommand::Cancel(opts) => command_cancel(gsettings, opts).await,
+        SubCommand::Top(opts) => command_top(gsettings, opts).await,
+        SubCommand::Config(ConfigOpts {
+            subcmd: ConfigCommand::Show,
+        }) => command_config_show(&config),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> hyperqueue::Result<()> {
+    let top_opts: Opts = Opts::parse();
+    setup_logging();
+
+    let result = match top_opts.subcmd {
+        // Task wrappers do not need any configuration, they only run the task program
+        SubCommand::Worker(WorkerOpts {
+            subcmd: WorkerCommand::RunTask(opts),
+        }) => command_worker_run_task(opts),
+        subcmd => run_command(top_opts.common, subcmd).await,
     };
     if let Err(e) = result {
         eprintln!("{:?}", e);
@@ -1,12 +1,19 @@
 use crate::client::globalsettings::GlobalSettings;
-use crate::client::job::{job_status, print_job_detail, print_job_list, Status};
+use std::io::Write;
+use std::time::Duration;
+
+use crate::client::job::{
+    job_progress_bar, job_status, print_job_detail, print_job_list, print_job_tasks,
+    print_task_usage_summary, Status,
+};
+use crate::common::idset::IdSet;
 use crate::rpc_call;
 use crate::transfer::connection::ClientConnection;
 use crate::transfer::messages::{
-    CancelJobResponse, CancelRequest, FromClientMessage, JobDetailRequest, JobInfoRequest,
+    CancelJobResponse, CancelRequest, FromClientMessage, JobDetailRequest, JobInfo, JobInfoRequest,
     JobSelector, ToClientMessage,
 };
-use crate::JobId;
+use crate::{JobId, JobTaskId};
 
 pub async fn get_last_job_id(connection: &mut ClientConnection) -> crate::Result<Option<JobId>> {
     let message = FromClientMessage::JobInfo(JobInfoRequest {
@@ -34,9 +41,10 @@ pub async fn output_job_list(
     gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
     job_filters: Vec<Status>,
+    selector: Option<JobSelector>,
 ) -> crate::Result<()> {
     let message = FromClientMessage::JobInfo(JobInfoRequest {
-        selector: JobSelector::All,
+        selector: selector.unwrap_or(JobSelector::All),
     });
     let mut response =
         rpc_call!(connection, message, ToClientMessage::JobInfoResponse(r) => r).await?;
@@ -54,31 +62,49 @@ pub async fn output_job_list(
 pub async fn output_job_detail(
     gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
-    job_id: JobId,
+    selector: JobSelector,
     show_tasks: bool,
+    show_summary: bool,
 ) -> crate::Result<()> {
     let message = FromClientMessage::JobDetail(JobDetailRequest {
-        job_id,
-        include_tasks: true,
+        selector,
+        include_tasks: show_tasks,
     });
-    let response =
+    let responses =
         rpc_call!(connection, message, ToClientMessage::JobDetailResponse(r) => r).await?;
 
-    if let Some(job) = response {
-        print_job_detail(gsettings, job, false, show_tasks);
-    } else {
-        log::error!("Job {} not found", job_id);
+    if responses.is_empty() {
+        log::warn!("No jobs were found");
+    }
+
+    for (job_id, job) in responses {
+        if let Some(job) = job {
+            let summary = show_summary.then(|| job.usage_summary.clone());
+            print_job_detail(gsettings, job, false, show_tasks);
+            if let Some(summary) = summary {
+                print_task_usage_summary(gsettings, &summary);
+            }
+        } else {
+            log::error!("Job {} not found", job_id);
+        }
     }
     Ok(())
 }
 
+fn format_task_ids(ids: &[JobTaskId]) -> String {
+    IdSet::from_sorted_ids(ids.iter().map(|id| *id as u64)).to_string()
+}
+
 pub async fn cancel_job(
     _gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
     selector: JobSelector,
+    tasks: Option<IdSet>,
 ) -> crate::Result<()> {
+    let selected_tasks = tasks.is_some();
     let mut responses = rpc_call!(connection, FromClientMessage::Cancel(CancelRequest {
          selector,
+         tasks,
     }), ToClientMessage::CancelJobResponse(r) => r)
     .await?;
     responses.sort_unstable_by_key(|x| x.0);
@@ -89,17 +115,56 @@ pub async fn cancel_job(
 
     for (job_id, response) in responses {
         match response {
-            CancelJobResponse::Canceled(canceled, already_finished) if !canceled.is_empty() => {
+            CancelJobResponse::Canceled(canceled, already_finished, already_canceled)
+                if selected_tasks =>
+            {
+                if !canceled.is_empty() {
+                    log::info!(
+                        "Job {}: tasks {} canceled",
+                        job_id,
+                        format_task_ids(&canceled)
+                    )
+                }
+                if !already_finished.is_empty() {
+                    log::warn!(
+                        "Job {}: tasks {} are already finished",
+                        job_id,
+                        format_task_ids(&already_finished)
+                    )
+                }
+                if !already_canceled.is_empty() {
+                    log::warn!(
+                        "Job {}: tasks {} are already canceled",
+                        job_id,
+                        format_task_ids(&already_canceled)
+                    )
+                }
+                if canceled.is_empty() && already_finished.is_empty() && already_canceled.is_empty()
+                {
+                    log::error!(
+                        "Canceling tasks of job {} failed; no selected task found",
+                        job_id
+                    )
+                }
+            }
+            CancelJobResponse::Canceled(canceled, already_finished, already_canceled)
+                if !canceled.is_empty() =>
+            {
                 log::info!(
-                    "Job {} canceled ({} tasks canceled, {} tasks already finished)",
+                    "Job {} canceled ({} tasks canceled, {} tasks already finished, \
+                    {} tasks already canceled)",
                     job_id,
                     canceled.len(),
-                    already_finished
+                    already_finished.len(),
+                    already_canceled.len()
                 )
             }
-            CancelJobResponse::Canceled(_, _) => {
+            CancelJobResponse::Canceled(_, already_finished, _) if already_finished.is_empty() => {
+                log::error!("Canceling job {} failed; job is already canceled", job_id)
+            }
+            CancelJobResponse::Canceled(_, _, _) => {
                 log::error!(
-                    "Canceling job {} failed; all tasks are already finished",
+                    "Canceling job {} failed; all tasks are already finished or canceled",
                     job_id
                 )
             }
@@ -113,3 +178,88 @@ pub async fn cancel_job(
     }
     Ok(())
 }
+
+/// How often is the state of a followed job refreshed
+const FOLLOW_REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+fn job_progress_line(info: &JobInfo) -> String {
+    format!(
+        "{} {}/{} finished, {} running, {} failed, {} canceled",
+        job_progress_bar(info),
+        info.counters.n_finished_tasks,
+        info.n_tasks,
+        info.counters.n_running_tasks,
+        info.counters.n_failed_tasks,
+        info.counters.n_canceled_tasks
+    )
+}
+
+/// Returns ids of jobs matched by the selector
+pub async fn resolve_job_ids(
+    connection: &mut ClientConnection,
+    selector: JobSelector,
+) -> crate::Result<Vec<JobId>> {
+    let message = FromClientMessage::JobInfo(JobInfoRequest { selector });
+    let response = rpc_call!(connection, message, ToClientMessage::JobInfoResponse(r) => r).await?;
+    Ok(response.jobs.iter().map(|job| job.id).collect())
+}
+
+/// Redraws the progress of the job until all of its tasks end, then prints failed tasks.
+/// Returns an error if any task of the job has not finished successfully.
+pub async fn follow_job(
+    gsettings: &GlobalSettings,
+    connection: &mut ClientConnection,
+    job_id: JobId,
+) -> anyhow::Result<()> {
+    let redraw = atty::is(atty::Stream::Stdout);
+    let mut last_line = String::new();
+    let mut interval = tokio::time::interval(FOLLOW_REFRESH_INTERVAL);
+
+    let info = loop {
+        interval.tick().await;
+        let message = FromClientMessage::JobInfo(JobInfoRequest {
+            selector: JobSelector::Specific(IdSet::single(job_id)),
+        });
+        let response =
+            rpc_call!(connection, message, ToClientMessage::JobInfoResponse(r) => r).await?;
+        let info = match response.jobs.into_iter().next() {
+            Some(info) => info,
+            None => anyhow::bail!("Job {} not found", job_id),
+        };
+
+        let line = job_progress_line(&info);
+        if redraw {
+            // Return to the beginning of the line and clear it
+            print!("\r{}\x1b[K", line);
+            std::io::stdout().flush()?;
+        } else if line != last_line {
+            println!("{}", line);
+        }
+        last_line = line;
+
+        if info.counters.n_waiting_tasks(info.n_tasks) == 0 && info.counters.n_running_tasks == 0 {
+            break info;
+        }
+    };
+    if redraw {
+        println!();
+    }
+
+    if info.counters.n_failed_tasks > 0 {
+        let message = FromClientMessage::JobDetail(JobDetailRequest {
+            selector: JobSelector::Specific(IdSet::single(job_id)),
+            include_tasks: false,
+        });
+        let response =
+            rpc_call!(connection, message, ToClientMessage::JobDetailResponse(r) => r).await?;
+        if let Some((_, Some(detail))) = response.into_iter().next() {
+            print_job_tasks(gsettings, detail.tasks, false, &detail.info.counters);
+        }
+    }
+
+    match job_status(&info) {
+        Status::Finished => Ok(()),
+        Status::Failed => anyhow::bail!("Job {} failed", job_id),
+        _ => anyhow::bail!("Job {} was canceled", job_id),
+    }
+}
@@ -1,4 +1,6 @@
 pub mod jobs;
+pub mod server;
 pub mod stop;
 pub mod submit;
+pub mod top;
 pub mod worker;
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Local;
+use cli_table::{print_stdout, Cell, Color, Style, Table};
+
+use crate::client::globalsettings::GlobalSettings;
+use crate::rpc_call;
+use crate::server::audit::{read_audit_log, AuditEntry, AuditFilter};
+use crate::transfer::connection::ClientConnection;
+use crate::transfer::messages::{
+    FromClientMessage, RotateKeysRequest, ServerInfoResponse, ToClientMessage,
+};
+
+pub async fn get_server_info(
+    connection: &mut ClientConnection,
+) -> crate::Result<ServerInfoResponse> {
+    rpc_call!(
+        connection,
+        FromClientMessage::ServerInfo,
+        ToClientMessage::ServerInfoResponse(r) => r
+    )
+    .await
+}
+
+pub async fn rotate_keys(connection: &mut ClientConnection, workers: bool) -> crate::Result<()> {
+    rpc_call!(
+        connection,
+        FromClientMessage::RotateKeys(RotateKeysRequest { workers }),
+        ToClientMessage::RotateKeysResponse => ()
+    )
+    .await
+}
+
+/// Prints the newest entries of the audit log that match `filter`.
+/// `offset` newest matching entries are skipped.
+pub fn print_audit_log(
+    gsettings: &GlobalSettings,
+    filter: &AuditFilter,
+    limit: usize,
+    offset: usize,
+) -> anyhow::Result<()> {
+    let server_dir = gsettings
+        .open_server_dir()
+        .context("No running instance of HQ found")?;
+    let path = server_dir.audit_log_path();
+    let entries =
+        read_audit_log(&path).with_context(|| format!("Cannot read audit log {:?}", path))?;
+    let mut entries: Vec<AuditEntry> = entries
+        .into_iter()
+        .rev()
+        .filter(|entry| filter.matches(entry))
+        .skip(offset)
+        .take(limit)
+        .collect();
+    entries.reverse();
+
+    let rows: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            let result = match &entry.error {
+                Some(error) => error.as_str().cell().foreground_color(Some(Color::Red)),
+                None => "ok".cell().foreground_color(Some(Color::Green)),
+            };
+            vec![
+                entry
+                    .time
+                    .with_timezone(&Local)
+                    .format("%F %T")
+                    .to_string()
+                    .cell(),
+                entry.user.as_str().cell(),
+                entry.role.cell(),
+                entry.peer.as_str().cell(),
+                entry.operation.name().cell(),
+                entry.operation.details().cell(),
+                result,
+            ]
+        })
+        .collect();
+
+    let table = rows
+        .table()
+        .color_choice(gsettings.color_policy())
+        .title(vec![
+            "Time".cell().bold(true),
+            "User".cell().bold(true),
+            "Role".cell().bold(true),
+            "Peer".cell().bold(true),
+            "Operation".cell().bold(true),
+            "Details".cell().bold(true),
+            "Result".cell().bold(true),
+        ]);
+    assert!(print_stdout(table).is_ok());
+    Ok(())
+}
+
+/// How often `hq server logs --follow` checks for new lines
+const LOG_FOLLOW_PERIOD: Duration = Duration::from_millis(500);
+
+/// Prints the log file of the server. With `follow`, new lines are printed until the command
+/// is interrupted; rotations of the log file are followed.
+pub async fn print_server_log(gsettings: &GlobalSettings, follow: bool) -> anyhow::Result<()> {
+    let server_dir = gsettings
+        .open_server_dir()
+        .context("No running instance of HQ found")?;
+    let path = server_dir.server_log_path();
+    let open = || File::open(&path).with_context(|| format!("Cannot open server log {:?}", path));
+    let mut file = open()?;
+    let mut stdout = std::io::stdout();
+    loop {
+        std::io::copy(&mut file, &mut stdout)?;
+        stdout.flush()?;
+        if !follow {
+            return Ok(());
+        }
+        let rotated = std::fs::metadata(&path)
+            .map(|metadata| metadata.ino() != file.metadata().map_or(0, |m| m.ino()))
+            .unwrap_or(false);
+        if rotated {
+            // Lines written before the rotation are printed from the old file first
+            std::io::copy(&mut file, &mut stdout)?;
+            file = open()?;
+        } else {
+            tokio::time::sleep(LOG_FOLLOW_PERIOD).await;
+        }
+    }
+}
@@ -1,8 +1,68 @@
+use std::time::Duration;
+
+use crate::common::error::error;
 use crate::transfer::connection::ClientConnection;
-use crate::transfer::messages::FromClientMessage;
+use crate::transfer::messages::{DrainRequest, FromClientMessage, ToClientMessage};
 
 pub async fn stop_server(connection: &mut ClientConnection) -> crate::Result<()> {
-    connection.send(FromClientMessage::Stop).await?;
-    log::info!("Stopping server");
-    Ok(())
+    match connection.send_and_receive(FromClientMessage::Stop).await? {
+        ToClientMessage::StopResponse => {
+            log::info!("Stopping server");
+            Ok(())
+        }
+        ToClientMessage::Error(e) => error(e),
+        msg => error(format!("Received an invalid message {:?}", msg)),
+    }
+}
+
+/// Stops the server after its running tasks end (or after `timeout`) and reports the progress
+pub async fn drain_server(
+    connection: &mut ClientConnection,
+    timeout: Option<Duration>,
+    cancel_waiting: bool,
+) -> crate::Result<()> {
+    connection
+        .send(FromClientMessage::Drain(DrainRequest {
+            timeout,
+            cancel_waiting,
+        }))
+        .await?;
+    log::info!("Draining server, new jobs are refused");
+    let mut last_running = None;
+    loop {
+        match connection.receive().await {
+            Some(Ok(ToClientMessage::DrainProgress(progress))) => {
+                if last_running != Some(progress.running_tasks) {
+                    log::info!("Waiting for {} running task(s)", progress.running_tasks);
+                    last_running = Some(progress.running_tasks);
+                }
+            }
+            Some(Ok(ToClientMessage::DrainResponse(response))) => {
+                if response.canceled_tasks > 0 {
+                    log::info!(
+                        "{} task(s) that had not started were canceled",
+                        response.canceled_tasks
+                    );
+                }
+                if response.waiting_tasks > 0 {
+                    log::info!(
+                        "{} task(s) that had not started were not executed",
+                        response.waiting_tasks
+                    );
+                }
+                if response.unfinished_tasks > 0 {
+                    log::warn!(
+                        "Timeout expired, {} task(s) were still running",
+                        response.unfinished_tasks
+                    );
+                }
+                log::info!("Workers were stopped, stopping server");
+                return Ok(());
+            }
+            Some(Ok(ToClientMessage::Error(e))) => return error(e),
+            Some(Ok(msg)) => return error(format!("Received an invalid message {:?}", msg)),
+            Some(Err(e)) => return Err(e),
+            None => return error("Connection to the server was closed".into()),
+        }
+    }
 }
@@ -1,23 +1,33 @@
 use std::io::BufRead;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::{fs, io};
 
-use anyhow::anyhow;
-use bstr::BString;
+use anyhow::{anyhow, Context};
+use bstr::{BString, ByteSlice};
 use clap::Clap;
 use hashbrown::HashMap;
 use tako::common::resources::{CpuRequest, ResourceRequest};
 use tako::messages::common::ProgramDefinition;
 
+use crate::client::commands::jobs::follow_job;
 use crate::client::globalsettings::GlobalSettings;
 use crate::client::job::print_job_detail;
 use crate::client::resources::parse_cpu_request;
 use crate::common::arraydef::ArrayDef;
+use crate::common::config::{cli_or_config, SubmitSection};
+use crate::common::env::{is_hq_env, HQ_CLEAN_ENV, HQ_STDIN, HQ_STDIN_ENTRY};
+use crate::common::placeholders::{parse_template, Placeholder, TemplatePart};
 use crate::transfer::connection::ClientConnection;
 use crate::transfer::messages::{FromClientMessage, JobType, SubmitRequest, ToClientMessage};
 use crate::{rpc_call, JobTaskCount};
 
+/// Maximal number of entries sent to the server in a single message
+const UPLOAD_CHUNK_ENTRIES: usize = 100_000;
+/// Maximal total size (in bytes) of entries sent to the server in a single message
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
 struct ArgCpuRequest(CpuRequest);
 
 impl FromStr for ArgCpuRequest {
@@ -55,6 +65,96 @@ impl FromStr for ArgEnvironmentVar {
     }
 }
 
+/// Selection of variables from the environment of the submitting process
+#[derive(Debug, PartialEq)]
+pub enum ArgExportEnv {
+    All,
+    None,
+    /// Names of variables, a name ending with `*` matches all variables with the given prefix
+    Names(Vec<String>),
+}
+
+impl ArgExportEnv {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            ArgExportEnv::All => true,
+            ArgExportEnv::None => false,
+            ArgExportEnv::Names(names) => names.iter().any(|n| match n.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == n,
+            }),
+        }
+    }
+
+    /// Returns the selected variables of the current process, HQ variables are never exported
+    fn export(&self) -> Vec<(BString, BString)> {
+        std::env::vars_os()
+            .filter(|(name, _)| self.matches(&name.to_string_lossy()))
+            .map(|(name, value)| {
+                (
+                    BString::from(name.as_bytes()),
+                    BString::from(value.as_bytes()),
+                )
+            })
+            .filter(|(name, _)| !is_hq_env(name))
+            .collect()
+    }
+}
+
+impl FromStr for ArgExportEnv {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(ArgExportEnv::All),
+            "none" => Ok(ArgExportEnv::None),
+            _ => {
+                let names: Vec<String> = s.split(',').map(|n| n.trim().to_string()).collect();
+                if names.iter().any(|n| n.is_empty() || n.contains('=')) {
+                    anyhow::bail!("Invalid list of environment variables: {:?}", s);
+                }
+                Ok(ArgExportEnv::Names(names))
+            }
+        }
+    }
+}
+
+/// Parses a dotenv-style file: `KEY=VALUE` lines with optional `export ` prefixes,
+/// quoted values, comments and empty lines
+fn parse_env_file(content: &str) -> anyhow::Result<Vec<(BString, BString)>> {
+    let mut result = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.find('=') {
+            Some(position) => (line[..position].trim(), line[position + 1..].trim()),
+            None => anyhow::bail!("Line {}: expected KEY=VALUE", i + 1),
+        };
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            anyhow::bail!("Line {}: invalid variable name {:?}", i + 1, key);
+        }
+        let value = if value.len() >= 2
+            && ((value.starts_with('"') && value.ends_with('"'))
+                || (value.starts_with('\'') && value.ends_with('\'')))
+        {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+        result.push((key.into(), value.into()));
+    }
+    Ok(result)
+}
+
+fn read_env_file(path: &Path) -> anyhow::Result<Vec<(BString, BString)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read environment file {:?}", path))?;
+    parse_env_file(&content).with_context(|| format!("Invalid environment file {:?}", path))
+}
+
 /// Represents a filepath. If "none" is passed to it, it will behave as if no path is needed.
 struct OptionalPath(Option<PathBuf>);
 
@@ -76,9 +176,9 @@ pub struct SubmitOpts {
     command: String,
     args: Vec<String>,
 
-    /// Number and placement of CPUs for each job
-    #[clap(long, default_value = "1")]
-    cpus: ArgCpuRequest,
+    /// Number and placement of CPUs for each job (default: "1")
+    #[clap(long)]
+    cpus: Option<ArgCpuRequest>,
 
     /// Name of the job
     #[clap(long)]
@@ -88,20 +188,25 @@ pub struct SubmitOpts {
     #[clap(long)]
     pin: bool,
 
-    /// Working directory for the submitted job
+    /// Working directory for the submitted job (default: "%{SUBMIT_DIR}")
     /// The path must be accessible from a worker node
-    #[clap(long, default_value("%{SUBMIT_DIR}"))]
-    cwd: PathBuf,
+    #[clap(long)]
+    cwd: Option<PathBuf>,
 
     /// Path where the standard output of the job will be stored
-    /// The path must be accessible from a worker node
-    #[clap(long, default_value("stdout.%{JOB_ID}.%{TASK_ID}"))]
-    stdout: OptionalPath,
+    /// The path must be accessible from a worker node (default: "stdout.%{JOB_ID}.%{TASK_ID}")
+    #[clap(long)]
+    stdout: Option<OptionalPath>,
 
     /// Path where the standard error of the job will be stored
-    /// The path must be accessible from a worker node
-    #[clap(long, default_value("stderr.%{JOB_ID}.%{TASK_ID}"))]
-    stderr: OptionalPath,
+    /// The path must be accessible from a worker node (default: "stderr.%{JOB_ID}.%{TASK_ID}")
+    #[clap(long)]
+    stderr: Option<OptionalPath>,
+
+    /// File passed to the standard input of the job
+    /// The path must be accessible from a worker node, placeholders such as %{TASK_ID} are supported
+    #[clap(long)]
+    stdin: Option<PathBuf>,
 
     /// Specify additional environment variable for the job
     /// You can pass this flag multiple times to pass multiple variables
@@ -110,6 +215,23 @@ pub struct SubmitOpts {
     #[clap(long, multiple_occurrences(true))]
     pub env: Vec<ArgEnvironmentVar>,
 
+    /// Variables from the current environment that are passed to the job (default: "none")
+    ///
+    /// `--export-env=all` - pass all variables
+    ///
+    /// `--export-env=PATH,LD_*` - pass the listed variables, `*` at the end matches a prefix
+    #[clap(long)]
+    export_env: Option<ArgExportEnv>,
+
+    /// Load environment variables for the job from a file with `KEY=VALUE` lines
+    #[clap(long, value_hint = clap::ValueHint::FilePath)]
+    env_file: Option<PathBuf>,
+
+    /// Start tasks only with variables provided by HQ and variables passed by
+    /// `--env`, `--env-file` or `--export-env`, instead of the environment of the worker
+    #[clap(long)]
+    clean_env: bool,
+
     // Parameters for creating array jobs
     /// Create a task array where a task will be created for each line of the given file.
     /// The corresponding line will be passed to the task in environment variable `HQ_ENTRY`.
@@ -125,34 +247,35 @@ pub struct SubmitOpts {
     /// `--array=3-5` - create task array with three jobs with task IDs 3, 4, 5
     array: Option<ArrayDef>,
 
+    /// Pass the line of the file given by `--each-line` to the standard input of each task
+    /// instead of the environment variable `HQ_ENTRY`
+    #[clap(long, requires("each-line"), conflicts_with("stdin"))]
+    stdin_from_entry: bool,
+
     #[clap(long)]
     max_fails: Option<JobTaskCount>,
-}
 
-impl SubmitOpts {
-    fn resource_request(&self) -> ResourceRequest {
-        ResourceRequest::new(self.cpus.0.clone())
-    }
+    /// Shell command executed on the server host when the job ends (requires the admin role).
+    /// It receives `HQ_JOB_ID`, `HQ_JOB_NAME`, `HQ_JOB_STATUS` and counts of tasks
+    /// in environment variables.
+    #[clap(long)]
+    on_finish: Option<String>,
+
+    /// Wait until the job ends and show its progress in the meantime.
+    /// The command fails if any task of the job does not finish successfully.
+    #[clap(long)]
+    progress: bool,
 }
 
 pub async fn submit_computation(
     gsettings: &GlobalSettings,
     connection: &mut ClientConnection,
     opts: SubmitOpts,
+    config: &SubmitSection,
 ) -> anyhow::Result<()> {
-    let resources = opts.resource_request();
+    let cpus = cli_or_config(opts.cpus, "submit.cpus", &config.cpus)?;
+    let resources = ResourceRequest::new(cpus.0);
     resources.validate()?;
-    let (job_type, entries) = if let Some(filename) = opts.each_line {
-        let lines = read_lines(&filename)?;
-        let def = ArrayDef::simple_range(0, lines.len() as JobTaskCount);
-        (JobType::Array(def), Some(lines))
-    } else {
-        (
-            opts.array.map(JobType::Array).unwrap_or(JobType::Simple),
-            None,
-        )
-    };
-
     let name = if let Some(name) = opts.name {
         validate_name(name)?
     } else {
@@ -169,23 +292,67 @@ pub async fn submit_computation(
         .collect();
     args.insert(0, opts.command.into());
 
-    let cwd = Some(opts.cwd);
-    let stdout = opts.stdout.0;
-    let stderr = opts.stderr.0;
+    let cwd = Some(cli_or_config(opts.cwd, "submit.cwd", &config.cwd)?);
+    let stdout = cli_or_config(opts.stdout, "submit.stdout", &config.stdout)?.0;
+    let stderr = cli_or_config(opts.stderr, "submit.stderr", &config.stderr)?.0;
+
+    let has_entries = opts.each_line.is_some();
+    for arg in &args {
+        if let Ok(arg) = arg.to_str() {
+            check_placeholders(arg, "arguments", has_entries)?;
+        }
+    }
+    let paths = [
+        ("--cwd", cwd.as_ref()),
+        ("--stdout", stdout.as_ref()),
+        ("--stderr", stderr.as_ref()),
+        ("--stdin", opts.stdin.as_ref()),
+    ];
+    for (option, path) in paths.iter() {
+        if let Some(path) = path.and_then(|p| p.to_str()) {
+            check_placeholders(path, option, has_entries)?;
+        }
+    }
 
     let env_count = opts.env.len();
-    let env: HashMap<_, _> = opts
+    let explicit_env: HashMap<_, _> = opts
         .env
         .into_iter()
         .map(|env| (env.key, env.value))
         .collect();
 
-    if env.len() != env_count {
+    if explicit_env.len() != env_count {
         log::warn!(
             "Some environment variables were ignored. Check if you haven't used duplicate keys."
         )
     }
 
+    // Explicitly passed variables take precedence over the env file and the exported variables
+    let mut env: HashMap<BString, BString> = opts
+        .export_env
+        .map(|export| export.export().into_iter().collect())
+        .unwrap_or_default();
+    if let Some(path) = opts.env_file {
+        env.extend(read_env_file(&path)?);
+    }
+    env.extend(explicit_env);
+    if let Some(path) = opts.stdin {
+        env.insert(HQ_STDIN.into(), path.to_string_lossy().as_bytes().into());
+    }
+    if opts.stdin_from_entry {
+        env.insert(HQ_STDIN_ENTRY.into(), "1".into());
+    }
+    if opts.clean_env {
+        env.insert(HQ_CLEAN_ENV.into(), "1".into());
+    }
+
+    let job_type = if let Some(filename) = opts.each_line {
+        let count = upload_entries(connection, &filename).await?;
+        JobType::Array(ArrayDef::simple_range(0, count))
+    } else {
+        opts.array.map(JobType::Array).unwrap_or(JobType::Simple)
+    };
+
     let message = FromClientMessage::Submit(SubmitRequest {
         job_type,
         name,
@@ -198,12 +365,36 @@ pub async fn submit_computation(
         },
         resources,
         pin: opts.pin,
-        entries,
-        max_fails: opts.max_fails,
+        entries: None,
+        max_fails: opts.max_fails.or(config.max_fails),
         submit_dir: std::env::current_dir().unwrap().to_str().unwrap().into(),
+        on_finish: opts.on_finish,
+        uploaded_entries: has_entries,
     });
     let response = rpc_call!(connection, message, ToClientMessage::SubmitResponse(r) => r).await?;
+    let job_id = response.job.info.id;
     print_job_detail(gsettings, response.job, true, false);
+    if opts.progress {
+        follow_job(gsettings, connection, job_id).await?;
+    }
+    Ok(())
+}
+
+/// Rejects unknown placeholders and placeholders that cannot be used in the given value
+fn check_placeholders(value: &str, option: &str, has_entries: bool) -> anyhow::Result<()> {
+    let parts =
+        parse_template(value).with_context(|| format!("Invalid placeholder in {}", option))?;
+    for part in parts {
+        match part {
+            TemplatePart::Placeholder(Placeholder::Cwd, _) if option == "--cwd" => {
+                anyhow::bail!("Placeholder %{{CWD}} cannot be used in --cwd")
+            }
+            TemplatePart::Placeholder(Placeholder::Entry, _) if !has_entries => {
+                anyhow::bail!("Placeholder %{{ENTRY}} can be used only with --each-line")
+            }
+            _ => {}
+        }
+    }
     Ok(())
 }
 
@@ -217,25 +408,44 @@ fn validate_name(name: String) -> anyhow::Result<String, anyhow::Error> {
     }
 }
 
+/// Streams lines of the file to the server in chunks, so that no single message has to hold
+/// all entries of a large task array. Returns the number of uploaded entries.
 // We need to read it as bytes, because not all our users uses UTF-8
-fn read_lines(filename: &Path) -> anyhow::Result<Vec<BString>> {
-    log::info!("Reading file: {}", filename.display());
-    if fs::metadata(filename)?.len() > 100 << 20 {
-        log::warn!("Reading file bigger then 100MB");
-    };
+async fn upload_entries(
+    connection: &mut ClientConnection,
+    filename: &Path,
+) -> anyhow::Result<JobTaskCount> {
+    log::info!("Uploading entries from file: {}", filename.display());
     let file = std::fs::File::open(filename)?;
-    let results: Result<Vec<BString>, std::io::Error> = io::BufReader::new(file)
-        .split(b'\n')
-        .map(|x| x.map(BString::from))
-        .collect();
-    Ok(results?)
+    let mut count: JobTaskCount = 0;
+    let mut chunk: Vec<BString> = Vec::new();
+    let mut chunk_size = 0;
+    for line in io::BufReader::new(file).split(b'\n') {
+        let line = line?;
+        chunk_size += line.len();
+        chunk.push(line.into());
+        if chunk.len() >= UPLOAD_CHUNK_ENTRIES || chunk_size >= UPLOAD_CHUNK_SIZE {
+            count += chunk.len() as JobTaskCount;
+            connection
+                .send(FromClientMessage::UploadEntries(std::mem::take(&mut chunk)))
+                .await?;
+            chunk_size = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        count += chunk.len() as JobTaskCount;
+        connection
+            .send(FromClientMessage::UploadEntries(chunk))
+            .await?;
+    }
+    Ok(count)
 }
 
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
-    use super::ArgEnvironmentVar;
+    use super::{parse_env_file, ArgEnvironmentVar, ArgExportEnv};
 
     #[test]
     fn test_parse_env_empty() {
@@ -270,4 +480,42 @@ mod tests {
         assert_eq!(env.key, "key");
         assert_eq!(env.value, "value=value2");
     }
+
+    #[test]
+    fn test_parse_export_env() {
+        assert_eq!(ArgExportEnv::from_str("all").unwrap(), ArgExportEnv::All);
+        assert_eq!(ArgExportEnv::from_str("none").unwrap(), ArgExportEnv::None);
+        let export = ArgExportEnv::from_str("PATH,LD_*").unwrap();
+        assert!(export.matches("PATH"));
+        assert!(export.matches("LD_LIBRARY_PATH"));
+        assert!(!export.matches("PATHX"));
+        assert!(!export.matches("HOME"));
+        assert!(ArgExportEnv::from_str("PATH,").is_err());
+        assert!(ArgExportEnv::from_str("A=B").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let env = parse_env_file(
+            "# comment\n\nA=1\nexport B = two words\nC=\"quoted # value\"\nD='x'\nE=\n",
+        )
+        .unwrap();
+        let env: Vec<(String, String)> = env
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(
+            env,
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "two words".to_string()),
+                ("C".to_string(), "quoted # value".to_string()),
+                ("D".to_string(), "x".to_string()),
+                ("E".to_string(), "".to_string()),
+            ]
+        );
+        assert!(parse_env_file("A").is_err());
+        assert!(parse_env_file("=1").is_err());
+        assert!(parse_env_file("A B=1").is_err());
+    }
 }
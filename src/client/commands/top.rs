@@ -0,0 +1,593 @@
+use std::io::{Stdout, Write};
+use std::time::Duration;
+
+use chrono::Local;
+use colored::{Color, ColoredString, Colorize};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::{execute, queue};
+use futures::StreamExt;
+
+use crate::client::commands::worker::get_worker_list;
+use crate::client::job::{job_progress_bar, job_status, Status};
+use crate::client::resources::cpu_request_to_string;
+use crate::client::worker::worker_stats_summary;
+use crate::common::idset::IdSet;
+use crate::rpc_call;
+use crate::transfer::connection::ClientConnection;
+use crate::transfer::messages::{
+    CancelJobResponse, CancelRequest, FromClientMessage, JobDetail, JobDetailRequest, JobInfo,
+    JobInfoRequest, JobSelector, LostWorkerReasonInfo, TaskFailureInfo, ToClientMessage,
+    WorkerInfo,
+};
+use crate::JobId;
+
+/// Terminals narrower than this do not show job progress bars
+const MIN_WIDTH_FOR_PROGRESS: usize = 100;
+
+/// Switches the terminal into a full-screen mode and restores it when dropped
+struct TerminalGuard {
+    stdout: Stdout,
+}
+
+impl TerminalGuard {
+    fn new() -> anyhow::Result<Self> {
+        let mut stdout = std::io::stdout();
+        enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide)?;
+        Ok(TerminalGuard { stdout })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(self.stdout, Show, LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+enum View {
+    Overview,
+    Job(JobId),
+}
+
+enum Action {
+    None,
+    Quit,
+    Refresh,
+    Cancel(JobId),
+}
+
+struct Dashboard {
+    jobs: Vec<JobInfo>,
+    workers: Vec<WorkerInfo>,
+    failures: Vec<TaskFailureInfo>,
+    job_detail: Option<JobDetail>,
+
+    view: View,
+    /// Index of the selected job in `jobs`
+    selected: usize,
+    /// Job whose cancellation waits for a confirmation
+    confirm_cancel: Option<JobId>,
+    /// Message shown in the status line
+    message: Option<String>,
+}
+
+impl Dashboard {
+    fn new() -> Self {
+        Dashboard {
+            jobs: Vec::new(),
+            workers: Vec::new(),
+            failures: Vec::new(),
+            job_detail: None,
+            view: View::Overview,
+            selected: 0,
+            confirm_cancel: None,
+            message: None,
+        }
+    }
+
+    fn selected_job_id(&self) -> Option<JobId> {
+        match self.view {
+            View::Overview => self.jobs.get(self.selected).map(|j| j.id),
+            View::Job(job_id) => Some(job_id),
+        }
+    }
+
+    async fn refresh(&mut self, connection: &mut ClientConnection) -> crate::Result<()> {
+        let selected_id = self.selected_job_id();
+
+        let message = FromClientMessage::JobInfo(JobInfoRequest {
+            selector: JobSelector::All,
+        });
+        let mut response =
+            rpc_call!(connection, message, ToClientMessage::JobInfoResponse(r) => r).await?;
+        // The newest jobs are the most interesting ones
+        response
+            .jobs
+            .sort_unstable_by_key(|j| std::cmp::Reverse(j.id));
+        self.jobs = response.jobs;
+
+        // Keep the selection on the same job even if new jobs were submitted
+        if let Some(position) = selected_id.and_then(|id| self.jobs.iter().position(|j| j.id == id))
+        {
+            self.selected = position;
+        }
+        self.selected = self.selected.min(self.jobs.len().saturating_sub(1));
+
+        let mut workers = get_worker_list(connection, true, true).await?;
+        workers.sort_by_key(|w| (w.ended.is_some(), w.id));
+        self.workers = workers;
+
+        self.failures = rpc_call!(
+            connection,
+            FromClientMessage::RecentFailures,
+            ToClientMessage::RecentFailuresResponse(r) => r
+        )
+        .await?;
+
+        self.job_detail = match self.view {
+            View::Overview => None,
+            View::Job(job_id) => {
+                let message = FromClientMessage::JobDetail(JobDetailRequest {
+                    selector: JobSelector::Specific(IdSet::single(job_id)),
+                    include_tasks: false,
+                });
+                let response =
+                    rpc_call!(connection, message, ToClientMessage::JobDetailResponse(r) => r)
+                        .await?;
+                response.into_iter().next().and_then(|(_, detail)| detail)
+            }
+        };
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Action::Quit;
+        }
+        if let Some(job_id) = self.confirm_cancel.take() {
+            return match key.code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => Action::Cancel(job_id),
+                _ => {
+                    self.message = None;
+                    Action::None
+                }
+            };
+        }
+
+        match key.code {
+            KeyCode::Char('q') => return Action::Quit,
+            KeyCode::Char('c') => {
+                if let Some(job_id) = self.selected_job_id() {
+                    self.confirm_cancel = Some(job_id);
+                    self.message = Some(format!("Cancel job {}? [y/N]", job_id));
+                }
+                return Action::None;
+            }
+            _ => {}
+        }
+
+        match self.view {
+            View::Overview => match key.code {
+                KeyCode::Up | KeyCode::Char('k') => {
+                    self.selected = self.selected.saturating_sub(1);
+                    Action::None
+                }
+                KeyCode::Down | KeyCode::Char('j') => {
+                    if self.selected + 1 < self.jobs.len() {
+                        self.selected += 1;
+                    }
+                    Action::None
+                }
+                KeyCode::Enter => match self.selected_job_id() {
+                    Some(job_id) => {
+                        self.view = View::Job(job_id);
+                        Action::Refresh
+                    }
+                    None => Action::None,
+                },
+                _ => Action::None,
+            },
+            View::Job(_) => match key.code {
+                KeyCode::Esc | KeyCode::Backspace | KeyCode::Left => {
+                    self.view = View::Overview;
+                    self.job_detail = None;
+                    Action::None
+                }
+                _ => Action::None,
+            },
+        }
+    }
+
+    fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let mut lines = vec![self.render_header(width), String::new()];
+        let body_height = height.saturating_sub(lines.len() + 2);
+        match self.view {
+            View::Overview => self.render_overview(&mut lines, width, body_height),
+            View::Job(job_id) => self.render_job(&mut lines, job_id, width, body_height),
+        }
+        lines.truncate(height.saturating_sub(2));
+        lines.resize(height.saturating_sub(1), String::new());
+        lines.push(self.render_footer(width));
+        // Lines wrapped by the terminal would shift the whole screen
+        lines.iter().map(|line| fit(line, width)).collect()
+    }
+
+    fn render_header(&self, width: usize) -> String {
+        let count_jobs =
+            |status: Status| self.jobs.iter().filter(|j| job_status(j) == status).count();
+        let summary = format!(
+            "HyperQueue | jobs: {} ({} running, {} waiting) | workers: {} online",
+            self.jobs.len(),
+            count_jobs(Status::Running),
+            count_jobs(Status::Waiting),
+            self.workers.iter().filter(|w| w.ended.is_none()).count()
+        );
+        let time = Local::now().format("%H:%M:%S").to_string();
+        let summary = fit(&summary, width.saturating_sub(time.len() + 1));
+        format!("{} {}", summary.bold(), time)
+    }
+
+    fn render_footer(&self, width: usize) -> String {
+        let keys = match self.view {
+            View::Overview => "[Up/Down] select  [Enter] detail  [c] cancel job  [q] quit",
+            View::Job(_) => "[Esc] back  [c] cancel job  [q] quit",
+        };
+        let text = match &self.message {
+            Some(message) => format!("{}  | {}", keys, message),
+            None => keys.to_string(),
+        };
+        fit(&text, width).reversed().to_string()
+    }
+
+    fn render_overview(&self, lines: &mut Vec<String>, width: usize, height: usize) {
+        // Each section has a title and a header line
+        let available = height.saturating_sub(8);
+        let worker_rows = (available / 4).max(1).min(self.workers.len().max(1));
+        let failure_rows = (available / 4).max(1).min(self.failures.len().max(1));
+        let job_rows = available.saturating_sub(worker_rows + failure_rows).max(1);
+
+        let show_progress = width >= MIN_WIDTH_FOR_PROGRESS;
+        lines.push(section_title("Jobs", self.jobs.len()));
+        let mut header = format!("  {:>6} {} {}", "ID", fit("NAME", 24), fit("STATE", 9));
+        if show_progress {
+            header.push_str(&format!(" {}", fit("PROGRESS", 42)));
+        }
+        header.push_str(" TASKS");
+        lines.push(fit(&header, width).bold().to_string());
+        let first = scroll_offset(self.selected, job_rows, self.jobs.len());
+        for (index, job) in self.jobs.iter().enumerate().skip(first).take(job_rows) {
+            let marker = if index == self.selected { ">" } else { " " };
+            let mut line = format!(
+                "{} {:>6} {} {}",
+                marker,
+                job.id,
+                fit(&job.name, 24),
+                status_label(job_status(job))
+            );
+            if show_progress {
+                line.push_str(&format!(" {} ", job_progress_bar(job)));
+            }
+            line.push_str(&format!(
+                " {}/{}",
+                job.counters.n_finished_tasks, job.n_tasks
+            ));
+            if index == self.selected {
+                line = line.bold().to_string();
+            }
+            lines.push(line);
+        }
+        if self.jobs.is_empty() {
+            lines.push("  No jobs".dimmed().to_string());
+        }
+        lines.push(String::new());
+
+        lines.push(section_title("Workers", self.workers.len()));
+        lines.push(
+            fit(
+                &format!(
+                    "  {:>6} {} {} {} UTILIZATION",
+                    "ID",
+                    fit("HOSTNAME", 24),
+                    fit("STATE", 15),
+                    fit("RESOURCES", 24)
+                ),
+                width,
+            )
+            .bold()
+            .to_string(),
+        );
+        for worker in self.workers.iter().take(worker_rows) {
+            lines.push(format!(
+                "  {:>6} {} {} {} {}",
+                worker.id,
+                fit(&worker.configuration.hostname, 24),
+                worker_state_label(worker),
+                fit(&worker.configuration.resources.summary(), 24),
+                worker_stats_summary(worker.stats.as_ref())
+            ));
+        }
+        if self.workers.is_empty() {
+            lines.push("  No workers".dimmed().to_string());
+        }
+        lines.push(String::new());
+
+        self.render_failures(lines, width, failure_rows, None);
+    }
+
+    fn render_failures(
+        &self,
+        lines: &mut Vec<String>,
+        width: usize,
+        rows: usize,
+        job_id: Option<JobId>,
+    ) {
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .filter(|f| job_id.map(|id| f.job_id == id).unwrap_or(true))
+            .collect();
+        lines.push(section_title("Recent task failures", failures.len()));
+        lines.push(
+            fit(
+                &format!("  {} {:>6} {:>6} ERROR", fit("TIME", 8), "JOB", "TASK"),
+                width,
+            )
+            .bold()
+            .to_string(),
+        );
+        for failure in failures.iter().take(rows) {
+            let line = format!(
+                "  {} {:>6} {:>6} {}",
+                failure.time.with_timezone(&Local).format("%H:%M:%S"),
+                failure.job_id,
+                failure.task_id,
+                failure.error.lines().next().unwrap_or("")
+            );
+            lines.push(fit(&line, width).red().to_string());
+        }
+        if failures.is_empty() {
+            lines.push("  No failures".dimmed().to_string());
+        }
+    }
+
+    fn render_job(&self, lines: &mut Vec<String>, job_id: JobId, width: usize, height: usize) {
+        let first_line = lines.len();
+        let detail = match &self.job_detail {
+            Some(detail) => detail,
+            None => {
+                lines.push(format!("Job {} not found", job_id));
+                return;
+            }
+        };
+        let info = &detail.info;
+        let counters = &info.counters;
+        lines.push(format!("Job {}", info.id).bold().underline().to_string());
+        lines.push(format!(
+            "  Name:      {}",
+            fit(&info.name, width.saturating_sub(13))
+        ));
+        lines.push(format!("  State:     {}", status_label(job_status(info))));
+        lines.push(format!(
+            "  Tasks:     {} (waiting {}, running {}, finished {}, failed {}, canceled {})",
+            info.n_tasks,
+            counters.n_waiting_tasks(info.n_tasks),
+            counters.n_running_tasks,
+            counters.n_finished_tasks,
+            counters.n_failed_tasks,
+            counters.n_canceled_tasks
+        ));
+        if width >= MIN_WIDTH_FOR_PROGRESS / 2 {
+            lines.push(format!("  Progress:  {}", job_progress_bar(info)));
+        }
+        lines.push(format!(
+            "  Resources: {}",
+            cpu_request_to_string(detail.resources.cpus())
+        ));
+        let command = detail
+            .program_def
+            .args
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        lines.push(fit(&format!("  Command:   {}", command), width));
+        lines.push(String::new());
+
+        let failed_tasks = &detail.tasks.errors;
+        let rows = height.saturating_sub(lines.len() - first_line + 2);
+        lines.push(section_title("Failed tasks", failed_tasks.len()));
+        for (task_id, error) in failed_tasks.iter().take(rows) {
+            let line = format!("  {:>6} {}", task_id, error.lines().next().unwrap_or(""));
+            lines.push(fit(&line, width).red().to_string());
+        }
+        if failed_tasks.is_empty() {
+            lines.push("  No failed tasks".dimmed().to_string());
+        }
+    }
+}
+
+fn section_title(title: &str, count: usize) -> String {
+    format!("{} ({})", title, count)
+        .bold()
+        .underline()
+        .to_string()
+}
+
+/// Truncates or pads `text` to exactly `width` visible characters.
+/// Color escape sequences in `text` are kept and they do not count into the width.
+fn fit(text: &str, width: usize) -> String {
+    let count = visible_chars(text).count();
+    if count <= width {
+        return format!("{}{}", text, " ".repeat(width - count));
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut visible = 0;
+    let mut in_escape = false;
+    for c in text.chars() {
+        if c == '\x1b' || in_escape {
+            in_escape = c != 'm';
+        } else if visible + 1 < width {
+            visible += 1;
+        } else {
+            break;
+        }
+        result.push(c);
+    }
+    if width > 0 {
+        result.push('…');
+    }
+    if result.contains('\x1b') {
+        result.push_str("\x1b[0m");
+    }
+    result
+}
+
+/// Characters of `text` without color escape sequences
+fn visible_chars(text: &str) -> impl Iterator<Item = char> + '_ {
+    let mut in_escape = false;
+    text.chars().filter(move |c| {
+        if *c == '\x1b' || in_escape {
+            in_escape = *c != 'm';
+            false
+        } else {
+            true
+        }
+    })
+}
+
+/// Returns the index of the first row shown so that the selected row is visible
+fn scroll_offset(selected: usize, rows: usize, count: usize) -> usize {
+    if selected < rows {
+        0
+    } else {
+        (selected + 1 - rows).min(count.saturating_sub(rows))
+    }
+}
+
+fn status_label(status: Status) -> ColoredString {
+    let (name, color) = match status {
+        Status::Waiting => ("WAITING", Color::Cyan),
+        Status::Running => ("RUNNING", Color::Yellow),
+        Status::Finished => ("FINISHED", Color::Green),
+        Status::Failed => ("FAILED", Color::Red),
+        Status::Canceled => ("CANCELED", Color::Magenta),
+    };
+    fit(name, 9).color(color)
+}
+
+fn worker_state_label(worker: &WorkerInfo) -> ColoredString {
+    let (name, color) = match worker.ended.as_ref().map(|e| &e.reason) {
+        None => ("RUNNING", Color::Green),
+        Some(LostWorkerReasonInfo::ConnectionLost) => ("CONNECTION LOST", Color::Red),
+        Some(LostWorkerReasonInfo::HeartbeatLost) => ("HEARTBEAT LOST", Color::Red),
+        Some(LostWorkerReasonInfo::IdleTimeout) => ("IDLE TIMEOUT", Color::Cyan),
+        Some(LostWorkerReasonInfo::Stopped) => ("STOPPED", Color::Magenta),
+    };
+    fit(name, 15).color(color)
+}
+
+async fn cancel_job(connection: &mut ClientConnection, job_id: JobId) -> crate::Result<String> {
+    let message = FromClientMessage::Cancel(CancelRequest {
+        selector: JobSelector::Specific(IdSet::single(job_id)),
+        tasks: None,
+    });
+    let responses =
+        rpc_call!(connection, message, ToClientMessage::CancelJobResponse(r) => r).await?;
+    Ok(match responses.into_iter().next().map(|r| r.1) {
+        Some(CancelJobResponse::Canceled(canceled, _, _)) if !canceled.is_empty() => {
+            format!("Job {} canceled ({} tasks)", job_id, canceled.len())
+        }
+        Some(CancelJobResponse::Canceled(_, _, _)) => {
+            format!("Job {} is already finished", job_id)
+        }
+        Some(CancelJobResponse::Failed(error)) => {
+            format!("Canceling job {} failed; {}", job_id, error)
+        }
+        Some(CancelJobResponse::InvalidJob) | None => format!("Job {} not found", job_id),
+    })
+}
+
+fn draw(stdout: &mut Stdout, dashboard: &Dashboard) -> anyhow::Result<()> {
+    let (width, height) = crossterm::terminal::size()?;
+    queue!(stdout, Clear(ClearType::All))?;
+    for (row, line) in dashboard
+        .render(width as usize, height as usize)
+        .into_iter()
+        .enumerate()
+    {
+        queue!(stdout, MoveTo(0, row as u16), Print(line))?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Runs an interactive dashboard that shows jobs, workers and recent task failures
+/// and refreshes them every `refresh_interval`
+pub async fn run_dashboard(
+    connection: &mut ClientConnection,
+    refresh_interval: Duration,
+) -> anyhow::Result<()> {
+    let mut dashboard = Dashboard::new();
+    dashboard.refresh(connection).await?;
+
+    let mut terminal = TerminalGuard::new()?;
+    let mut events = EventStream::new();
+    let mut interval = tokio::time::interval(refresh_interval);
+
+    loop {
+        draw(&mut terminal.stdout, &dashboard)?;
+        tokio::select! {
+            _ = interval.tick() => {
+                dashboard.refresh(connection).await?;
+            }
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) => match dashboard.handle_key(key) {
+                    Action::None => {}
+                    Action::Quit => break,
+                    Action::Refresh => dashboard.refresh(connection).await?,
+                    Action::Cancel(job_id) => {
+                        dashboard.message = Some(cancel_job(connection, job_id).await?);
+                        dashboard.refresh(connection).await?;
+                    }
+                },
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit, scroll_offset};
+
+    #[test]
+    fn test_fit() {
+        assert_eq!(fit("abc", 5), "abc  ");
+        assert_eq!(fit("abcdef", 4), "abc…");
+        assert_eq!(fit("abc", 3), "abc");
+        assert_eq!(fit("abc", 0), "");
+    }
+
+    #[test]
+    fn test_fit_colored() {
+        assert_eq!(fit("\x1b[31mabc\x1b[0m", 5), "\x1b[31mabc\x1b[0m  ");
+        assert_eq!(fit("\x1b[1mab\x1b[0mcdef", 4), "\x1b[1mab\x1b[0mc…\x1b[0m");
+    }
+
+    #[test]
+    fn test_scroll_offset() {
+        assert_eq!(scroll_offset(0, 5, 20), 0);
+        assert_eq!(scroll_offset(4, 5, 20), 0);
+        assert_eq!(scroll_offset(5, 5, 20), 1);
+        assert_eq!(scroll_offset(19, 5, 20), 15);
+    }
+}
@@ -2,16 +2,25 @@ use std::path::{Path, PathBuf};
 
 use cli_table::ColorChoice;
 
+use crate::common::serverdir::ServerDir;
+
+#[derive(Clone)]
 pub struct GlobalSettings {
     color_policy: ColorChoice,
     server_dir: PathBuf,
+    server_name: Option<String>,
 }
 
 impl GlobalSettings {
-    pub fn new(server_dir: PathBuf, color_policy: ColorChoice) -> Self {
+    pub fn new(
+        server_dir: PathBuf,
+        server_name: Option<String>,
+        color_policy: ColorChoice,
+    ) -> Self {
         GlobalSettings {
             color_policy,
             server_dir,
+            server_name,
         }
     }
 
@@ -22,4 +31,14 @@ impl GlobalSettings {
     pub fn server_directory(&self) -> &Path {
         &self.server_dir
     }
+
+    /// Name of the server inside the server directory, `None` for the default server
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Opens the active instance of the selected server
+    pub fn open_server_dir(&self) -> crate::Result<ServerDir> {
+        ServerDir::open(&self.server_dir, self.server_name())
+    }
 }
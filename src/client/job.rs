@@ -3,9 +3,10 @@ use cli_table::{print_stdout, Cell, CellStruct, Color, Style, Table};
 
 use crate::client::globalsettings::GlobalSettings;
 use crate::client::resources::cpu_request_to_string;
+use crate::client::utils::format_memory;
 use crate::common::env::is_hq_env;
-use crate::server::job::{JobTaskCounters, JobTaskInfo, JobTaskState};
-use crate::transfer::messages::{JobDetail, JobInfo, JobType};
+use crate::server::job::{JobTaskCounters, JobTaskState, JobTaskStates};
+use crate::transfer::messages::{JobDetail, JobInfo, JobType, JobUsageSummary};
 use crate::JobTaskCount;
 use colored::Colorize;
 use std::fmt::Write;
@@ -57,7 +58,7 @@ fn task_status(status: &JobTaskState) -> Status {
         JobTaskState::Waiting => Status::Waiting,
         JobTaskState::Running => Status::Running,
         JobTaskState::Finished => Status::Finished,
-        JobTaskState::Failed(_) => Status::Failed,
+        JobTaskState::Failed => Status::Failed,
         JobTaskState::Canceled => Status::Canceled,
     }
 }
@@ -73,7 +74,7 @@ fn status_cell(status: Status) -> CellStruct {
 }
 
 /// Draws a colored progress bar that depicts counts of tasks with individual states
-fn job_progress_bar(info: &JobInfo) -> String {
+pub fn job_progress_bar(info: &JobInfo) -> String {
     let mut buffer = String::from("[");
 
     let width: usize = 40;
@@ -292,33 +293,45 @@ pub fn print_job_detail(
     }
 }
 
-fn print_job_tasks(
+pub fn print_job_tasks(
     gsettings: &GlobalSettings,
-    mut tasks: Vec<JobTaskInfo>,
+    tasks: JobTaskStates,
     show_tasks: bool,
     counters: &JobTaskCounters,
 ) {
-    tasks.sort_unstable_by_key(|t| t.task_id);
-
-    let make_error_row = |t: &JobTaskInfo| match &t.state {
-        JobTaskState::Failed(e) => Some(vec![
-            t.task_id.cell(),
-            e.cell().foreground_color(Some(Color::Red)),
-        ]),
-        _ => None,
-    };
-
     if show_tasks {
         let rows: Vec<_> = tasks
-            .iter()
+            .tasks()
+            .into_iter()
             .map(|t| {
                 vec![
                     t.task_id.cell(),
                     status_cell(task_status(&t.state)),
-                    match &t.state {
-                        JobTaskState::Failed(e) => e.cell().foreground_color(Some(Color::Red)),
-                        _ => "".cell(),
+                    match &t.error {
+                        Some(e) => e.cell().foreground_color(Some(Color::Red)),
+                        None => "".cell(),
                     },
+                    t.usage
+                        .as_ref()
+                        .map(|u| {
+                            format!(
+                                "{:.2}s / {:.2}s",
+                                u.user_time.as_secs_f64(),
+                                u.system_time.as_secs_f64()
+                            )
+                        })
+                        .unwrap_or_else(|| "N/A".to_string())
+                        .cell(),
+                    t.usage
+                        .as_ref()
+                        .map(|u| format_memory(u.max_rss))
+                        .unwrap_or_else(|| "N/A".to_string())
+                        .cell(),
+                    t.usage
+                        .as_ref()
+                        .map(|u| format!("{} / {}", u.input_blocks, u.output_blocks))
+                        .unwrap_or_else(|| "N/A".to_string())
+                        .cell(),
                 ]
             })
             .collect();
@@ -329,14 +342,23 @@ fn print_job_tasks(
                 "Task Id".cell().bold(true),
                 "State".cell().bold(true),
                 "Message".cell().bold(true),
+                "CPU time (user / sys)".cell().bold(true),
+                "Max RSS".cell().bold(true),
+                "I/O blocks (in / out)".cell().bold(true),
             ]);
         assert!(print_stdout(table).is_ok());
     } else {
         const SHOWN_TASKS: usize = 5;
         let fail_rows: Vec<_> = tasks
+            .errors
             .iter()
-            .filter_map(make_error_row)
             .take(SHOWN_TASKS)
+            .map(|(task_id, error)| {
+                vec![
+                    task_id.cell(),
+                    error.cell().foreground_color(Some(Color::Red)),
+                ]
+            })
             .collect();
 
         if !fail_rows.is_empty() {
@@ -358,3 +380,31 @@ fn print_job_tasks(
         }
     }
 }
+
+pub fn print_task_usage_summary(gsettings: &GlobalSettings, summary: &JobUsageSummary) {
+    if summary.n_tasks == 0 {
+        println!("No resource usage was reported for tasks of the job");
+        return;
+    }
+    let rows = vec![
+        vec!["Tasks with usage".cell().bold(true), summary.n_tasks.cell()],
+        vec![
+            "CPU time (total)".cell().bold(true),
+            format!("{:.4} CPU hours", summary.cpu_time.as_secs_f64() / 3600.0).cell(),
+        ],
+        vec![
+            "Max RSS (mean)".cell().bold(true),
+            format_memory(summary.mean_max_rss()).cell(),
+        ],
+        vec![
+            "Max RSS (max)".cell().bold(true),
+            format_memory(summary.max_max_rss).cell(),
+        ],
+        vec![
+            "I/O blocks (in / out)".cell().bold(true),
+            format!("{} / {}", summary.input_blocks, summary.output_blocks).cell(),
+        ],
+    ];
+    let table = rows.table().color_choice(gsettings.color_policy());
+    assert!(print_stdout(table).is_ok());
+}
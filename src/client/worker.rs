@@ -2,7 +2,7 @@ use cli_table::format::Justify;
 use cli_table::{print_stdout, Cell, CellStruct, Color, Style, Table};
 
 use crate::client::globalsettings::GlobalSettings;
-use crate::transfer::messages::{LostWorkerReasonInfo, WorkerExitInfo, WorkerInfo};
+use crate::transfer::messages::{LostWorkerReasonInfo, WorkerExitInfo, WorkerInfo, WorkerStats};
 
 pub enum WorkerState {
     Running,
@@ -32,11 +32,23 @@ fn worker_state(worker: &WorkerInfo) -> CellStruct {
     }
 }
 
-pub fn print_worker_info(workers: Vec<WorkerInfo>, gsettings: &GlobalSettings) {
+pub fn worker_stats_summary(stats: Option<&WorkerStats>) -> String {
+    match stats {
+        Some(stats) => format!(
+            "CPU {:.0}% | Mem {:.0}% | Load {:.2}",
+            stats.average_cpu_usage(),
+            stats.memory_usage(),
+            stats.load_average[0]
+        ),
+        None => "N/A".to_string(),
+    }
+}
+
+pub fn print_worker_info(workers: Vec<WorkerInfo>, gsettings: &GlobalSettings, show_stats: bool) {
     let rows: Vec<_> = workers
         .into_iter()
         .map(|w| {
-            vec![
+            let mut row = vec![
                 w.id.cell().justify(Justify::Right),
                 worker_state(&w),
                 w.configuration.hostname.cell(),
@@ -53,20 +65,29 @@ pub fn print_worker_info(workers: Vec<WorkerInfo>, gsettings: &GlobalSettings) {
                     .map(|x| x.as_str())
                     .unwrap_or("N/A")
                     .cell(),
-            ]
+            ];
+            if show_stats {
+                row.push(worker_stats_summary(w.stats.as_ref()).cell());
+            }
+            row
         })
         .collect();
 
+    let mut header = vec![
+        "Id".cell(),
+        "State".cell().bold(true),
+        "Hostname".cell().bold(true),
+        "Resources".cell().bold(true),
+        "Manager".cell().bold(true),
+        "Manager Job Id".cell().bold(true),
+    ];
+    if show_stats {
+        header.push("Stats".cell().bold(true));
+    }
+
     let table = rows
         .table()
         .color_choice(gsettings.color_policy())
-        .title(vec![
-            "Id".cell(),
-            "State".cell().bold(true),
-            "Hostname".cell().bold(true),
-            "Resources".cell().bold(true),
-            "Manager".cell().bold(true),
-            "Manager Job Id".cell().bold(true),
-        ]);
+        .title(header);
     assert!(print_stdout(table).is_ok());
 }
@@ -42,6 +42,21 @@ impl ArrayDef {
     pub fn iter(&self) -> impl Iterator<Item = JobTaskId> {
         self.range.start..self.range.start + self.range.count
     }
+
+    /// Returns the id of the task at the given position in the array
+    pub fn task_id(&self, index: JobTaskCount) -> JobTaskId {
+        debug_assert!(index < self.range.count);
+        self.range.start + index
+    }
+
+    /// Returns the position of `task_id` in the array, if the array contains it
+    pub fn task_index(&self, task_id: JobTaskId) -> Option<JobTaskCount> {
+        if task_id >= self.range.start && task_id - self.range.start < self.range.count {
+            Some(task_id - self.range.start)
+        } else {
+            None
+        }
+    }
 }
 
 impl FromStr for ArrayDef {
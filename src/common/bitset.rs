@@ -0,0 +1,66 @@
+/// Set of indices from a fixed range `0..len`, stored as a single bit per index
+#[derive(Debug, Clone, Default)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    pub fn new(len: usize) -> BitSet {
+        BitSet {
+            words: vec![0; (len + 63) / 64],
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, index: usize) -> bool {
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    #[inline]
+    pub fn insert(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    #[inline]
+    pub fn remove(&mut self, index: usize) {
+        self.words[index / 64] &= !(1 << (index % 64));
+    }
+
+    /// Returns the contained indices in ascending order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words
+            .iter()
+            .enumerate()
+            .filter(|(_, word)| **word != 0)
+            .flat_map(|(i, word)| {
+                (0..64)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| i * 64 + bit)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitSet;
+
+    #[test]
+    fn test_bitset() {
+        let mut set = BitSet::new(130);
+        assert!(!set.contains(0));
+        assert_eq!(set.iter().count(), 0);
+
+        set.insert(0);
+        set.insert(63);
+        set.insert(64);
+        set.insert(129);
+        assert!(set.contains(63));
+        assert!(!set.contains(62));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0, 63, 64, 129]);
+
+        set.remove(63);
+        set.remove(5);
+        assert!(!set.contains(63));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![0, 64, 129]);
+    }
+}
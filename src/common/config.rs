@@ -0,0 +1,208 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::JobTaskCount;
+
+/// Environment variable with a path to the configuration file
+pub const HQ_CONFIG: &str = "HQ_CONFIG";
+
+/// Defaults of HyperQueue settings, loaded from a TOML configuration file.
+/// Options given on the command line take precedence over them.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub global: GlobalSection,
+    pub server: ServerSection,
+    pub worker: WorkerSection,
+    pub submit: SubmitSection,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GlobalSection {
+    pub server_dir: Option<PathBuf>,
+    pub server_name: Option<String>,
+    pub colors: String,
+}
+
+impl Default for GlobalSection {
+    fn default() -> Self {
+        GlobalSection {
+            server_dir: None,
+            server_name: None,
+            colors: "auto".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerSection {
+    pub host: Option<String>,
+    pub idle_timeout: Option<String>,
+    pub bind: Option<String>,
+    pub client_port: Option<String>,
+    pub worker_port: Option<String>,
+    pub metrics_port: Option<u16>,
+    pub metrics_bind: Option<String>,
+    pub on_job_finish: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WorkerSection {
+    pub cpus: Option<String>,
+    pub heartbeat: String,
+    pub idle_timeout: Option<String>,
+    pub manager: String,
+    pub reconnect: bool,
+    pub server_host: Option<String>,
+    pub server_port: Option<u16>,
+}
+
+impl Default for WorkerSection {
+    fn default() -> Self {
+        WorkerSection {
+            cpus: None,
+            heartbeat: "8s".to_string(),
+            idle_timeout: None,
+            manager: "detect".to_string(),
+            reconnect: false,
+            server_host: None,
+            server_port: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SubmitSection {
+    pub cpus: String,
+    pub cwd: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub max_fails: Option<JobTaskCount>,
+}
+
+impl Default for SubmitSection {
+    fn default() -> Self {
+        SubmitSection {
+            cpus: "1".to_string(),
+            cwd: "%{SUBMIT_DIR}".to_string(),
+            stdout: "stdout.%{JOB_ID}.%{TASK_ID}".to_string(),
+            stderr: "stderr.%{JOB_ID}.%{TASK_ID}".to_string(),
+            max_fails: None,
+        }
+    }
+}
+
+/// Returns the path given by `HQ_CONFIG` or `~/.config/hq/config.toml`
+pub fn config_path() -> Option<PathBuf> {
+    match std::env::var_os(HQ_CONFIG) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::config_dir().map(|dir| dir.join("hq").join("config.toml")),
+    }
+}
+
+/// Loads the configuration file. A missing file in the default location is not an error,
+/// built-in defaults are used instead.
+pub fn load_config() -> anyhow::Result<Config> {
+    let path = match config_path() {
+        Some(path) => path,
+        None => return Ok(Config::default()),
+    };
+    if std::env::var_os(HQ_CONFIG).is_none() && !path.exists() {
+        return Ok(Config::default());
+    }
+    parse_config_file(&path)
+}
+
+fn parse_config_file(path: &Path) -> anyhow::Result<Config> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read configuration file {:?}", path))?;
+    toml::from_str(&content).with_context(|| format!("Invalid configuration file {:?}", path))
+}
+
+/// Parses a configuration value in the same way as the corresponding command line option
+pub fn parse_config_value<T: FromStr>(key: &str, value: &str) -> anyhow::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| {
+        anyhow!(
+            "Invalid value {:?} of `{}` in configuration: {}",
+            value,
+            key,
+            e
+        )
+    })
+}
+
+/// Returns the value given on the command line or the (parsed) value from the configuration
+pub fn cli_or_config<T: FromStr>(cli: Option<T>, key: &str, value: &str) -> anyhow::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    match cli {
+        Some(value) => Ok(value),
+        None => parse_config_value(key, value),
+    }
+}
+
+/// Same as [`cli_or_config`] for options that do not have any default value
+pub fn cli_or_optional_config<T: FromStr>(
+    cli: Option<T>,
+    key: &str,
+    value: Option<&String>,
+) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match (cli, value) {
+        (Some(value), _) => Ok(Some(value)),
+        (None, Some(value)) => parse_config_value(key, value).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cli_or_config, Config};
+
+    #[test]
+    fn test_parse_partial_config() {
+        let config: Config = toml::from_str(
+            r#"
+[worker]
+heartbeat = "2s"
+
+[submit]
+stdout = "out.%{TASK_ID}"
+max-fails = 3
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.worker.heartbeat, "2s");
+        assert_eq!(config.worker.manager, "detect");
+        assert_eq!(config.submit.stdout, "out.%{TASK_ID}");
+        assert_eq!(config.submit.stderr, "stderr.%{JOB_ID}.%{TASK_ID}");
+        assert_eq!(config.submit.max_fails, Some(3));
+        assert_eq!(config.global.colors, "auto");
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        assert!(toml::from_str::<Config>("[worker]\nheartbeet = \"2s\"").is_err());
+        assert!(toml::from_str::<Config>("[workers]").is_err());
+    }
+
+    #[test]
+    fn test_cli_or_config() {
+        assert_eq!(cli_or_config(Some(5u32), "x", "10").unwrap(), 5);
+        assert_eq!(cli_or_config::<u32>(None, "x", "10").unwrap(), 10);
+        assert!(cli_or_config::<u32>(None, "x", "ten").is_err());
+    }
+}
@@ -16,6 +16,20 @@ pub fn is_hq_env(name: &BString) -> bool {
 pub const HQ_JOB_ID: &str = create_hq_env!("JOB_ID");
 pub const HQ_TASK_ID: &str = create_hq_env!("TASK_ID");
 pub const HQ_SUBMIT_DIR: &str = create_hq_env!("SUBMIT_DIR");
+pub const HQ_JOB_NAME: &str = create_hq_env!("JOB_NAME");
 pub const HQ_ENTRY: &str = create_hq_env!("ENTRY");
 pub const HQ_PIN: &str = create_hq_env!("PIN");
 pub const HQ_CPUS: &str = create_hq_env!("CPUS");
+/// Marks tasks that should be started only with variables provided by HQ
+pub const HQ_CLEAN_ENV: &str = create_hq_env!("CLEAN_ENV");
+/// Path of a file that is passed to the standard input of tasks
+pub const HQ_STDIN: &str = create_hq_env!("STDIN");
+/// Marks tasks that receive their entry on the standard input instead of `HQ_ENTRY`
+pub const HQ_STDIN_ENTRY: &str = create_hq_env!("STDIN_ENTRY");
+
+/// Variables passed to hooks of finished jobs
+pub const HQ_JOB_STATUS: &str = create_hq_env!("JOB_STATUS");
+pub const HQ_TASK_COUNT: &str = create_hq_env!("TASK_COUNT");
+pub const HQ_FINISHED_TASKS: &str = create_hq_env!("FINISHED_TASKS");
+pub const HQ_FAILED_TASKS: &str = create_hq_env!("FAILED_TASKS");
+pub const HQ_CANCELED_TASKS: &str = create_hq_env!("CANCELED_TASKS");
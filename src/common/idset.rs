@@ -0,0 +1,98 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::selectorparser::parse_id_set;
+
+/// Inclusive range of ids
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IdRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl IdRange {
+    pub fn new(start: u64, end: u64) -> IdRange {
+        IdRange { start, end }
+    }
+}
+
+/// Set of ids given by a user, e.g. `3-10,12`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct IdSet {
+    ranges: Vec<IdRange>,
+}
+
+impl IdSet {
+    pub fn new(ranges: Vec<IdRange>) -> IdSet {
+        IdSet { ranges }
+    }
+
+    pub fn single(id: u64) -> IdSet {
+        IdSet::new(vec![IdRange::new(id, id)])
+    }
+
+    /// Creates a set from ascending ids, consecutive ids are merged into ranges
+    pub fn from_sorted_ids(ids: impl IntoIterator<Item = u64>) -> IdSet {
+        let mut ranges: Vec<IdRange> = Vec::new();
+        for id in ids {
+            match ranges.last_mut() {
+                Some(range) if range.end + 1 == id => range.end = id,
+                _ => ranges.push(IdRange::new(id, id)),
+            }
+        }
+        IdSet { ranges }
+    }
+
+    pub fn ranges(&self) -> &[IdRange] {
+        &self.ranges
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.ranges.iter().any(|r| r.start <= id && id <= r.end)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges.iter().flat_map(|r| r.start..=r.end)
+    }
+}
+
+impl FromStr for IdSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_id_set(s)
+    }
+}
+
+impl fmt::Display for IdSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if range.start == range.end {
+                write!(f, "{}", range.start)?;
+            } else {
+                write!(f, "{}-{}", range.start, range.end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdSet;
+
+    #[test]
+    fn test_from_sorted_ids() {
+        assert_eq!(IdSet::from_sorted_ids(vec![]).to_string(), "");
+        assert_eq!(
+            IdSet::from_sorted_ids(vec![1, 2, 3, 5, 7, 8]).to_string(),
+            "1-3,5,7-8"
+        );
+        assert_eq!(IdSet::from_sorted_ids(vec![4]).to_string(), "4");
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// File that is rotated when its size would exceed `max_size`.
+/// The current file is renamed to `<path>.1`, `<path>.1` to `<path>.2`, etc.,
+/// and only `max_backups` rotated files are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_backups: u32,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, max_backups: u32) -> std::io::Result<RotatingFile> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            max_backups: max_backups.max(1),
+        })
+    }
+
+    fn backup_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..self.max_backups).rev() {
+            let backup = self.backup_path(index);
+            if backup.exists() {
+                std::fs::rename(&backup, self.backup_path(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.backup_path(1))?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use super::RotatingFile;
+
+    #[test]
+    fn test_rotating_file() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let path = tmp_dir.path().join("server.log");
+        let read = |name: &str| std::fs::read_to_string(tmp_dir.path().join(name)).unwrap();
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        file.write_all(b"aaaa\n").unwrap();
+        file.write_all(b"bbbb\n").unwrap();
+        assert_eq!(read("server.log"), "aaaa\nbbbb\n");
+
+        file.write_all(b"cccc\n").unwrap();
+        assert_eq!(read("server.log"), "cccc\n");
+        assert_eq!(read("server.log.1"), "aaaa\nbbbb\n");
+
+        file.write_all(b"dddddddddddd\n").unwrap();
+        file.write_all(b"eeee\n").unwrap();
+        assert_eq!(read("server.log"), "eeee\n");
+        assert_eq!(read("server.log.1"), "dddddddddddd\n");
+        assert_eq!(read("server.log.2"), "cccc\n");
+        assert!(!tmp_dir.path().join("server.log.3").exists());
+
+        // The size of an existing file is taken into account
+        drop(file);
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        file.write_all(b"ffffff\n").unwrap();
+        assert_eq!(read("server.log"), "ffffff\n");
+        assert_eq!(read("server.log.1"), "eeee\n");
+    }
+}
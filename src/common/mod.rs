@@ -2,10 +2,16 @@ pub use wrapped::WrappedRcRefCell;
 
 pub mod arraydef;
 pub mod arrayparser;
+pub mod bitset;
+pub mod config;
 pub mod env;
 pub mod error;
 pub mod fsutils;
+pub mod idset;
+pub mod logfile;
 pub mod parser;
+pub mod placeholders;
+pub mod selectorparser;
 pub mod serverdir;
 pub mod setup;
 pub mod timeutils;
@@ -50,3 +50,7 @@ pub type NomResult<'a, Ret> = IResult<&'a str, Ret, ParserError<&'a str>>;
 pub fn p_uint(input: &str) -> NomResult<u32> {
     map_res(digit1, |digit_str: &str| digit_str.parse::<u32>())(input)
 }
+
+pub fn p_u64(input: &str) -> NomResult<u64> {
+    map_res(digit1, |digit_str: &str| digit_str.parse::<u64>())(input)
+}
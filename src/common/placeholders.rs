@@ -0,0 +1,205 @@
+use std::fmt;
+
+use anyhow::{anyhow, bail};
+use nom::branch::alt;
+use nom::bytes::complete::{is_not, tag, take_until};
+use nom::combinator::{all_consuming, cut, map, map_res};
+use nom::multi::many0;
+use nom::sequence::{preceded, terminated};
+
+use crate::common::parser::{format_parse_error, NomResult};
+
+/// Placeholders that can be used in paths and arguments of tasks, e.g. `%{TASK_ID}`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Placeholder {
+    JobId,
+    TaskId,
+    JobName,
+    Entry,
+    SubmitDir,
+    Cwd,
+    Date,
+    Hostname,
+    WorkerId,
+    /// Environment variable of the task, `%{ENV:NAME}`
+    Env(String),
+}
+
+impl fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Placeholder::JobId => "JOB_ID",
+            Placeholder::TaskId => "TASK_ID",
+            Placeholder::JobName => "JOB_NAME",
+            Placeholder::Entry => "ENTRY",
+            Placeholder::SubmitDir => "SUBMIT_DIR",
+            Placeholder::Cwd => "CWD",
+            Placeholder::Date => "DATE",
+            Placeholder::Hostname => "HOSTNAME",
+            Placeholder::WorkerId => "WORKER_ID",
+            Placeholder::Env(name) => return write!(f, "%{{ENV:{}}}", name),
+        };
+        write!(f, "%{{{}}}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart<'a> {
+    Text(&'a str),
+    /// Placeholder with an optional width of zero-padding
+    Placeholder(Placeholder, Option<usize>),
+}
+
+fn parse_placeholder(content: &str) -> anyhow::Result<(Placeholder, Option<usize>)> {
+    if let Some(name) = content.strip_prefix("ENV:") {
+        if name.is_empty() {
+            bail!("Missing name of an environment variable");
+        }
+        return Ok((Placeholder::Env(name.to_string()), None));
+    }
+    let (name, format) = match content.find(':') {
+        Some(position) => (&content[..position], Some(&content[position + 1..])),
+        None => (content, None),
+    };
+    let placeholder = match name {
+        "JOB_ID" => Placeholder::JobId,
+        "TASK_ID" => Placeholder::TaskId,
+        "JOB_NAME" => Placeholder::JobName,
+        "ENTRY" => Placeholder::Entry,
+        "SUBMIT_DIR" => Placeholder::SubmitDir,
+        "CWD" => Placeholder::Cwd,
+        "DATE" => Placeholder::Date,
+        "HOSTNAME" => Placeholder::Hostname,
+        "WORKER_ID" => Placeholder::WorkerId,
+        _ => bail!("Unknown placeholder `{}`", name),
+    };
+    let width = match format {
+        None => None,
+        Some(format) => {
+            if !matches!(
+                placeholder,
+                Placeholder::JobId | Placeholder::TaskId | Placeholder::WorkerId
+            ) {
+                bail!("Placeholder `{}` does not support formatting", name);
+            }
+            match format.strip_prefix('0').map(|width| width.parse::<usize>()) {
+                Some(Ok(width)) => Some(width),
+                _ => bail!(
+                    "Invalid format `{}`, expected zero-padding, e.g. `05`",
+                    format
+                ),
+            }
+        }
+    };
+    Ok((placeholder, width))
+}
+
+fn p_template(input: &str) -> NomResult<Vec<TemplatePart>> {
+    many0(alt((
+        map(is_not("%"), TemplatePart::Text),
+        // `%%{` is an escaped literal `%{`
+        map(tag("%%{"), |_| TemplatePart::Text("%{")),
+        preceded(
+            tag("%{"),
+            cut(terminated(
+                map_res(take_until("}"), |content| {
+                    parse_placeholder(content)
+                        .map(|(placeholder, width)| TemplatePart::Placeholder(placeholder, width))
+                }),
+                tag("}"),
+            )),
+        ),
+        map(tag("%"), TemplatePart::Text),
+    )))(input)
+}
+
+/// Parses a string with placeholders, unknown placeholders are rejected
+pub fn parse_template(input: &str) -> anyhow::Result<Vec<TemplatePart>> {
+    all_consuming(p_template)(input)
+        .map(|r| r.1)
+        .map_err(format_parse_error)
+}
+
+/// Replaces placeholders in `input` by values returned by `resolve`.
+/// Fails if `resolve` does not know the value of a used placeholder.
+pub fn fill_template(
+    input: &str,
+    resolve: impl Fn(&Placeholder) -> Option<String>,
+) -> anyhow::Result<String> {
+    let mut result = String::with_capacity(input.len());
+    for part in parse_template(input)? {
+        match part {
+            TemplatePart::Text(text) => result.push_str(text),
+            TemplatePart::Placeholder(placeholder, width) => {
+                let value = resolve(&placeholder)
+                    .ok_or_else(|| anyhow!("Placeholder {} is not available", placeholder))?;
+                match width {
+                    Some(width) => result.push_str(&format!("{:0>width$}", value, width = width)),
+                    None => result.push_str(&value),
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fill_template, parse_template, Placeholder, TemplatePart};
+
+    fn resolve(placeholder: &Placeholder) -> Option<String> {
+        match placeholder {
+            Placeholder::JobId => Some("5".to_string()),
+            Placeholder::TaskId => Some("12".to_string()),
+            Placeholder::Env(name) if name == "HOME" => Some("/home/user".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_template() {
+        assert_eq!(
+            parse_template("out-%{TASK_ID:03}.%{ENV:USER}%").unwrap(),
+            vec![
+                TemplatePart::Text("out-"),
+                TemplatePart::Placeholder(Placeholder::TaskId, Some(3)),
+                TemplatePart::Text("."),
+                TemplatePart::Placeholder(Placeholder::Env("USER".to_string()), None),
+                TemplatePart::Text("%"),
+            ]
+        );
+        assert_eq!(parse_template("").unwrap(), vec![]);
+        assert_eq!(
+            parse_template("%%{FOO}%%").unwrap(),
+            vec![
+                TemplatePart::Text("%{"),
+                TemplatePart::Text("FOO}"),
+                TemplatePart::Text("%"),
+                TemplatePart::Text("%"),
+            ]
+        );
+        assert!(parse_template("%{FOO}").is_err());
+        assert!(parse_template("%{TASK_ID").is_err());
+        assert!(parse_template("%{}").is_err());
+        assert!(parse_template("%{ENV:}").is_err());
+        assert!(parse_template("%{TASK_ID:5}").is_err());
+        assert!(parse_template("%{DATE:05}").is_err());
+    }
+
+    #[test]
+    fn test_fill_template() {
+        assert_eq!(
+            fill_template("%{ENV:HOME}/%{JOB_ID}-%{TASK_ID:04}.out", resolve).unwrap(),
+            "/home/user/5-0012.out"
+        );
+        assert_eq!(
+            fill_template("date +%Y %{TASK_ID:01}", resolve).unwrap(),
+            "date +%Y 12"
+        );
+        assert_eq!(
+            fill_template("%%{TASK_ID} %{TASK_ID} %%%{TASK_ID}", resolve).unwrap(),
+            "%{TASK_ID} 12 %%{TASK_ID}"
+        );
+        assert!(fill_template("%{CWD}", resolve).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::anyhow;
+use nom::branch::alt;
+use nom::bytes::complete::tag;
+use nom::combinator::{all_consuming, map, map_res, opt, rest, verify};
+use nom::multi::separated_list1;
+use nom::sequence::{preceded, tuple};
+
+use crate::common::idset::{IdRange, IdSet};
+use crate::common::parser::{format_parse_error, p_u64, NomResult};
+use crate::transfer::messages::JobSelector;
+
+fn p_id_range(input: &str) -> NomResult<IdRange> {
+    map_res(
+        tuple((p_u64, opt(preceded(tag("-"), p_u64)))),
+        |r| match r {
+            (v, None) => Ok(IdRange::new(v, v)),
+            (v, Some(w)) if w >= v => Ok(IdRange::new(v, w)),
+            _ => Err(anyhow!("Invalid range")),
+        },
+    )(input)
+}
+
+fn p_id_set(input: &str) -> NomResult<IdSet> {
+    map(separated_list1(tag(","), p_id_range), IdSet::new)(input)
+}
+
+fn p_job_selector(input: &str) -> NomResult<JobSelector> {
+    alt((
+        map(tag("all"), |_| JobSelector::All),
+        map_res(preceded(tag("last"), opt(p_u64)), |n| match n {
+            Some(0) => Err(anyhow!("Selecting zero jobs is not allowed")),
+            n => Ok(JobSelector::LastN(n.unwrap_or(1))),
+        }),
+        map(
+            preceded(tag("name:"), verify(rest, |s: &str| !s.is_empty())),
+            |pattern: &str| JobSelector::Name(pattern.to_string()),
+        ),
+        map(p_id_set, JobSelector::Specific),
+    ))(input)
+}
+
+/// Parses a set of ids, e.g. `5`, `3-10` or `4,7,9-12`
+pub fn parse_id_set(input: &str) -> anyhow::Result<IdSet> {
+    all_consuming(p_id_set)(input)
+        .map(|r| r.1)
+        .map_err(format_parse_error)
+}
+
+/// Parses a job selector: a set of ids, `all`, `last`, `lastN` or `name:<pattern>`
+pub fn parse_job_selector(input: &str) -> anyhow::Result<JobSelector> {
+    all_consuming(p_job_selector)(input)
+        .map(|r| r.1)
+        .map_err(format_parse_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_id_set() {
+        assert_eq!(
+            parse_id_set("5").unwrap().iter().collect::<Vec<_>>(),
+            vec![5]
+        );
+        assert_eq!(
+            parse_id_set("3-6").unwrap().iter().collect::<Vec<_>>(),
+            vec![3, 4, 5, 6]
+        );
+        assert_eq!(
+            parse_id_set("4,7,9-10").unwrap().iter().collect::<Vec<_>>(),
+            vec![4, 7, 9, 10]
+        );
+        assert!(parse_id_set("").is_err());
+        assert!(parse_id_set("4,").is_err());
+        assert!(parse_id_set("10-9").is_err());
+        assert!(parse_id_set("x").is_err());
+    }
+
+    #[test]
+    fn test_parse_job_selector() {
+        assert!(matches!(
+            parse_job_selector("all").unwrap(),
+            JobSelector::All
+        ));
+        assert!(matches!(
+            parse_job_selector("last").unwrap(),
+            JobSelector::LastN(1)
+        ));
+        assert!(matches!(
+            parse_job_selector("last5").unwrap(),
+            JobSelector::LastN(5)
+        ));
+        assert!(parse_job_selector("last0").is_err());
+        assert!(matches!(
+            parse_job_selector("name:pre*").unwrap(),
+            JobSelector::Name(p) if p == "pre*"
+        ));
+        assert!(parse_job_selector("name:").is_err());
+        assert!(matches!(
+            parse_job_selector("3-10,12").unwrap(),
+            JobSelector::Specific(ids) if ids.to_string() == "3-10,12"
+        ));
+        assert!(parse_job_selector("lastx").is_err());
+        assert!(parse_job_selector("first").is_err());
+    }
+}
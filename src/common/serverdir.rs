@@ -10,7 +10,8 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::common::error::error;
 use crate::common::fsutils::{absolute_path, create_symlink};
-use crate::transfer::auth::{deserialize_key, serialize_key};
+use crate::transfer::auth::{deserialize_key, serialize_key, AccessRole, RoleKeys};
+use crate::WorkerId;
 
 #[derive(Clone)]
 pub struct ServerDir {
@@ -18,11 +19,41 @@ pub struct ServerDir {
 }
 
 pub const SYMLINK_PATH: &str = "hq-current";
-const ACCESS_FILE: &str = "access.json";
+pub const ACCESS_FILE: &str = "access.json";
+/// Directory with the local socket, it is accessible only by the owner of the server
+pub const LOCAL_SOCKET_DIR: &str = "local";
+pub const LOCAL_SOCKET: &str = "hq.sock";
+pub const AUDIT_FILE: &str = "audit.log";
+pub const SERVER_LOG_FILE: &str = "server.log";
+
+/// Name of the symlink that points to the active instance of the server with the given name
+pub fn symlink_name(server_name: Option<&str>) -> String {
+    match server_name {
+        Some(name) => format!("{}-{}", SYMLINK_PATH, name),
+        None => SYMLINK_PATH.to_string(),
+    }
+}
+
+/// Names of servers are used in names of files, so only a limited set of characters is allowed
+pub fn validate_server_name(name: &str) -> crate::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return error(format!(
+            "Invalid server name `{}`, only letters, digits, `-` and `_` are allowed",
+            name
+        ));
+    }
+    Ok(())
+}
 
 impl ServerDir {
-    pub fn open(directory: &Path) -> crate::Result<Self> {
-        let path = resolve_active_directory(directory);
+    /// Opens the active instance of the server with the given name inside `directory`,
+    /// or `directory` itself if it does not contain any such instance
+    pub fn open(directory: &Path, server_name: Option<&str>) -> crate::Result<Self> {
+        let path = resolve_active_directory(directory, server_name);
         if !path.is_dir() {
             return error(format!("{:?} is not a directory", path));
         }
@@ -32,18 +63,56 @@ impl ServerDir {
     }
 
     pub fn create(directory: &Path, record: &AccessRecord) -> crate::Result<ServerDir> {
-        let dir_path = directory.join(record.start_date().format("%Y-%m-%d-%H-%M-%S").to_string());
+        let timestamp = record.start_date().format("%Y-%m-%d-%H-%M-%S");
+        let dir_name = match record.name() {
+            Some(name) => format!("{}-{}", name, timestamp),
+            None => timestamp.to_string(),
+        };
+        let dir_path = directory.join(dir_name);
         std::fs::create_dir_all(&dir_path)?;
 
-        let server_dir = ServerDir::open(&dir_path)?;
+        let server_dir = ServerDir::open(&dir_path, None)?;
         let access_file_path = server_dir.access_filename();
         log::info!("Saving access file as '{:?}'", access_file_path);
         store_access_record(&record, access_file_path)?;
-        create_symlink(&directory.join(SYMLINK_PATH), &dir_path)?;
+        create_symlink(&directory.join(symlink_name(record.name())), &dir_path)?;
 
         Ok(server_dir)
     }
 
+    /// Returns all instances of servers (active or not) stored in `directory`
+    pub fn list_instances(directory: &Path) -> crate::Result<Vec<ServerDir>> {
+        let mut instances = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && entry.path().join(ACCESS_FILE).is_file() {
+                instances.push(ServerDir {
+                    path: absolute_path(entry.path()),
+                });
+            }
+        }
+        instances.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(instances)
+    }
+
+    /// Checks if the instance is the active instance of the server with the given name
+    pub fn is_active(&self, directory: &Path, server_name: Option<&str>) -> bool {
+        let target = std::fs::canonicalize(directory.join(symlink_name(server_name)));
+        match (target, std::fs::canonicalize(&self.path)) {
+            (Ok(target), Ok(path)) => target == path,
+            _ => false,
+        }
+    }
+
+    /// Removes the instance directory and the symlink that points to it
+    pub fn remove(self, directory: &Path, server_name: Option<&str>) -> crate::Result<()> {
+        if self.is_active(directory, server_name) {
+            std::fs::remove_file(directory.join(symlink_name(server_name)))?;
+        }
+        std::fs::remove_dir_all(&self.path)?;
+        Ok(())
+    }
+
     pub fn path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
         self.path.join(path)
     }
@@ -56,24 +125,44 @@ impl ServerDir {
         self.path(ACCESS_FILE)
     }
 
-    pub fn read_access_record(&self) -> crate::Result<AccessRecord> {
-        let record = load_access_file(self.access_filename())?;
-        let version = env!("CARGO_PKG_VERSION");
-        if record.version != version {
-            return error(format!(
-                "Hyperqueue version mismatch detected.\nServer was started with version {}, \
-                but the current version is {}.",
-                record.version, version
-            ));
+    /// Path of the Unix socket for clients running on the same host as the server
+    pub fn local_socket_path(&self) -> PathBuf {
+        self.path(LOCAL_SOCKET_DIR).join(LOCAL_SOCKET)
+    }
+
+    pub fn audit_log_path(&self) -> PathBuf {
+        self.path(AUDIT_FILE)
+    }
+
+    pub fn server_log_path(&self) -> PathBuf {
+        self.path(SERVER_LOG_FILE)
+    }
+
+    pub fn worker_log_path(&self, worker_id: WorkerId) -> PathBuf {
+        self.path(format!("worker-{}.log", worker_id))
+    }
+
+    /// Replaces the access file of a running server.
+    /// The record is written into a temporary file first, so readers never see a partial file.
+    pub fn replace_access_record(&self, record: &AccessRecord) -> crate::Result<()> {
+        let tmp_path = self.path(format!("{}.tmp", ACCESS_FILE));
+        if tmp_path.exists() {
+            std::fs::remove_file(&tmp_path)?;
         }
+        store_access_record(record, &tmp_path)?;
+        std::fs::rename(&tmp_path, self.access_filename())?;
+        Ok(())
+    }
 
-        Ok(record)
+    pub fn read_access_record(&self) -> crate::Result<AccessRecord> {
+        load_access_file(self.access_filename())
     }
 }
 
-/// Returns either `path` if it doesn't contain `SYMLINK_PATH` or the target of `SYMLINK_PATH`.
-fn resolve_active_directory(path: &Path) -> PathBuf {
-    let symlink_path = path.join(SYMLINK_PATH);
+/// Returns either `path` if it doesn't contain the symlink of the server
+/// or the target of the symlink.
+fn resolve_active_directory(path: &Path, server_name: Option<&str>) -> PathBuf {
+    let symlink_path = path.join(symlink_name(server_name));
     std::fs::canonicalize(symlink_path)
         .ok()
         .filter(|p| p.is_dir())
@@ -81,20 +170,25 @@ fn resolve_active_directory(path: &Path) -> PathBuf {
 }
 
 fn serde_serialize_key<S: Serializer>(
-    key: &Arc<SecretKey>,
+    key: &Option<Arc<SecretKey>>,
     serializer: S,
 ) -> Result<S::Ok, S::Error> {
-    let str = serialize_key(&key);
-    serializer.serialize_str(&str)
+    match key {
+        Some(key) => serializer.serialize_some(&serialize_key(&key)),
+        None => serializer.serialize_none(),
+    }
 }
 
 fn serde_deserialize_key<'de, D: Deserializer<'de>>(
     deserializer: D,
-) -> Result<Arc<SecretKey>, D::Error> {
-    let key: String = Deserialize::deserialize(deserializer)?;
-    deserialize_key(&key)
-        .map(Arc::new)
-        .map_err(|e| D::Error::custom(format!("Could not load secret key {}", e)))
+) -> Result<Option<Arc<SecretKey>>, D::Error> {
+    let key: Option<String> = Deserialize::deserialize(deserializer)?;
+    key.map(|key| {
+        deserialize_key(&key)
+            .map(Arc::new)
+            .map_err(|e| D::Error::custom(format!("Could not load secret key {}", e)))
+    })
+    .transpose()
 }
 
 /// This data structure represents information required to connect to a running instance of
@@ -102,7 +196,7 @@ fn serde_deserialize_key<'de, D: Deserializer<'de>>(
 ///
 /// It is stored on disk during `hq start` and loaded by both client operations (stats, submit) and
 /// HyperQueue workers in order to connect to the server instance.
-#[derive(Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct AccessRecord {
     /// Version of HQ
     version: String,
@@ -121,13 +215,37 @@ pub struct AccessRecord {
 
     start_date: DateTime<Utc>,
 
+    /// Name of the server, servers with different names can share a server directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    /// Key of the admin role
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "serde_serialize_key")]
+    #[serde(deserialize_with = "serde_deserialize_key")]
+    hq_secret_key: Option<Arc<SecretKey>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "serde_serialize_key")]
     #[serde(deserialize_with = "serde_deserialize_key")]
-    hq_secret_key: Arc<SecretKey>,
+    submit_secret_key: Option<Arc<SecretKey>>,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     #[serde(serialize_with = "serde_serialize_key")]
     #[serde(deserialize_with = "serde_deserialize_key")]
-    tako_secret_key: Arc<SecretKey>,
+    monitor_secret_key: Option<Arc<SecretKey>>,
+
+    /// Key with which workers send stats and results of tasks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "serde_serialize_key")]
+    #[serde(deserialize_with = "serde_deserialize_key")]
+    worker_secret_key: Option<Arc<SecretKey>>,
+
+    /// Only records with the admin or the worker role contain the key for connecting workers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "serde_serialize_key")]
+    #[serde(deserialize_with = "serde_deserialize_key")]
+    tako_secret_key: Option<Arc<SecretKey>>,
 }
 
 impl AccessRecord {
@@ -135,8 +253,9 @@ impl AccessRecord {
         host: String,
         server_port: u16,
         worker_port: u16,
-        hq_secret_key: Arc<SecretKey>,
+        role_keys: RoleKeys,
         tako_secret_key: Arc<SecretKey>,
+        name: Option<String>,
     ) -> Self {
         Self {
             version: env!("CARGO_PKG_VERSION").to_string(),
@@ -144,17 +263,72 @@ impl AccessRecord {
             server_port,
             worker_port,
             start_date: Utc::now(),
-            hq_secret_key,
-            tako_secret_key,
+            name,
+            hq_secret_key: Some(role_keys.admin),
+            submit_secret_key: Some(role_keys.submit),
+            monitor_secret_key: Some(role_keys.monitor),
+            worker_secret_key: Some(role_keys.worker),
+            tako_secret_key: Some(tako_secret_key),
             pid: std::process::id(),
         }
     }
+
+    /// Returns a copy of the record that contains only keys of the roles allowed by `role`.
+    /// Records of the worker role contain only the keys needed by workers.
+    pub fn restrict(&self, role: AccessRole) -> AccessRecord {
+        let mut record = self.clone();
+        if role < AccessRole::Admin {
+            record.hq_secret_key = None;
+        }
+        if role < AccessRole::Submit {
+            record.submit_secret_key = None;
+        }
+        if role < AccessRole::Monitor {
+            record.monitor_secret_key = None;
+        }
+        if role != AccessRole::Admin && role != AccessRole::Worker {
+            record.worker_secret_key = None;
+            record.tako_secret_key = None;
+        }
+        record
+    }
+
+    /// Replaces keys of all roles, the key for connecting workers is kept
+    pub fn set_role_keys(&mut self, role_keys: RoleKeys) {
+        self.hq_secret_key = Some(role_keys.admin);
+        self.submit_secret_key = Some(role_keys.submit);
+        self.monitor_secret_key = Some(role_keys.monitor);
+        self.worker_secret_key = Some(role_keys.worker);
+    }
+
+    /// Replaces the key with which workers connect to the server
+    pub fn set_tako_secret_key(&mut self, key: Arc<SecretKey>) {
+        self.tako_secret_key = Some(key);
+    }
+
+    /// Clients negotiate the protocol version with the server, but workers communicate
+    /// with the server through tako, which requires the same version of HyperQueue.
+    pub fn check_version(&self) -> crate::Result<()> {
+        let version = env!("CARGO_PKG_VERSION");
+        if self.version != version {
+            return error(format!(
+                "Hyperqueue version mismatch detected.\nServer was started with version {}, \
+                but the current version is {}.",
+                self.version, version
+            ));
+        }
+        Ok(())
+    }
+
     pub fn version(&self) -> &str {
         &self.version
     }
     pub fn host(&self) -> &str {
         &self.host
     }
+    pub fn set_host(&mut self, host: String) {
+        self.host = host;
+    }
     pub fn pid(&self) -> u32 {
         self.pid
     }
@@ -164,14 +338,37 @@ impl AccessRecord {
     pub fn worker_port(&self) -> u16 {
         self.worker_port
     }
+    pub fn set_worker_port(&mut self, port: u16) {
+        self.worker_port = port;
+    }
     pub fn start_date(&self) -> &DateTime<Utc> {
         &self.start_date
     }
-    pub fn hq_secret_key(&self) -> &Arc<SecretKey> {
-        &self.hq_secret_key
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
     }
-    pub fn tako_secret_key(&self) -> &Arc<SecretKey> {
-        &self.tako_secret_key
+    pub fn secret_key(&self, role: AccessRole) -> Option<&Arc<SecretKey>> {
+        match role {
+            AccessRole::Admin => self.hq_secret_key.as_ref(),
+            AccessRole::Submit => self.submit_secret_key.as_ref(),
+            AccessRole::Monitor => self.monitor_secret_key.as_ref(),
+            AccessRole::Worker => self.worker_secret_key.as_ref(),
+        }
+    }
+    /// The highest role for which the record contains a key
+    pub fn role(&self) -> Option<AccessRole> {
+        [
+            AccessRole::Admin,
+            AccessRole::Submit,
+            AccessRole::Monitor,
+            AccessRole::Worker,
+        ]
+        .iter()
+        .copied()
+        .find(|role| self.secret_key(*role).is_some())
+    }
+    pub fn tako_secret_key(&self) -> Option<&Arc<SecretKey>> {
+        self.tako_secret_key.as_ref()
     }
 }
 
@@ -195,14 +392,53 @@ mod tests {
     use tempdir::TempDir;
 
     use crate::common::serverdir::{load_access_file, store_access_record, AccessRecord};
+    use crate::transfer::auth::AccessRole;
 
     #[test]
     fn test_roundtrip() {
-        let record =
-            AccessRecord::new("foo".into(), 42, 43, Default::default(), Default::default());
+        let record = AccessRecord::new(
+            "foo".into(),
+            42,
+            43,
+            Default::default(),
+            Default::default(),
+            None,
+        );
         let path = TempDir::new("foo").unwrap().into_path().join("access.json");
         store_access_record(&record, path.clone()).unwrap();
         let loaded = load_access_file(path).unwrap();
         assert!(record == loaded);
     }
+
+    #[test]
+    fn test_restrict() {
+        let record = AccessRecord::new(
+            "foo".into(),
+            42,
+            43,
+            Default::default(),
+            Default::default(),
+            None,
+        );
+        assert_eq!(record.role(), Some(AccessRole::Admin));
+
+        let monitor = record.restrict(AccessRole::Monitor);
+        assert_eq!(monitor.role(), Some(AccessRole::Monitor));
+        assert!(monitor.secret_key(AccessRole::Submit).is_none());
+        assert!(monitor.tako_secret_key().is_none());
+
+        let path = TempDir::new("foo").unwrap().into_path().join("access.json");
+        store_access_record(&monitor, path.clone()).unwrap();
+        assert!(load_access_file(path).unwrap() == monitor);
+
+        let submit = record.restrict(AccessRole::Submit);
+        assert_eq!(submit.role(), Some(AccessRole::Submit));
+        assert!(submit.secret_key(AccessRole::Monitor).is_some());
+        assert!(submit.secret_key(AccessRole::Worker).is_none());
+
+        let worker = record.restrict(AccessRole::Worker);
+        assert_eq!(worker.role(), Some(AccessRole::Worker));
+        assert!(worker.secret_key(AccessRole::Monitor).is_none());
+        assert!(worker.tako_secret_key().is_some());
+    }
 }
@@ -1,12 +1,78 @@
-use std::sync::Arc;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
+use chrono::Utc;
+use log::{Log, Metadata, Record};
 use tokio::sync::Notify;
 
+use crate::common::logfile::RotatingFile;
+
+/// Maximal size of a log file before it is rotated
+const LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Number of rotated log files that are kept
+const LOG_FILE_BACKUPS: u32 = 5;
+
+/// File into which records are logged besides stderr, it is set by [`log_to_file`]
+static LOG_FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+
+/// Logs into stderr (formatted by `env_logger`) and into the log file, if it is set
+struct HqLogger {
+    stderr: env_logger::Logger,
+}
+
+impl Log for HqLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.stderr.matches(record) {
+            return;
+        }
+        self.stderr.log(record);
+
+        if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+            let line = format!(
+                "[{} {:<5} {}] {}\n",
+                Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            // The whole line is written at once, so a line is never split by a rotation
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
 pub fn setup_logging() {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
-    env_logger::builder().format_timestamp_millis().init();
+    let stderr = env_logger::builder().format_timestamp_millis().build();
+    log::set_max_level(stderr.filter());
+    log::set_logger(Box::leak(Box::new(HqLogger { stderr })))
+        .expect("Logger was already initialized");
+}
+
+/// Logs also into a file at `path`, which is rotated when it grows too large.
+/// It replaces the previous log file, if any. Failures are only reported, logging into stderr continues.
+pub fn log_to_file(path: &Path) {
+    match RotatingFile::open(path, LOG_FILE_MAX_SIZE, LOG_FILE_BACKUPS) {
+        Ok(file) => {
+            *LOG_FILE.lock().unwrap() = Some(file);
+            log::debug!("Logging into {:?}", path);
+        }
+        Err(e) => log::warn!("Cannot open log file {:?}: {}", path, e),
+    }
 }
 
 pub fn setup_interrupt() -> Arc<Notify> {
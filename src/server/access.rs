@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use crate::common::serverdir::{AccessRecord, ServerDir};
+use crate::common::WrappedRcRefCell;
+use crate::server::workergate::WorkerGateRef;
+use crate::transfer::auth::{generate_key, RoleKeys};
+
+/// Access record of the running server together with the directory where it is stored
+pub struct ServerAccess {
+    server_dir: ServerDir,
+    record: AccessRecord,
+    keys: RoleKeys,
+    worker_gate: WorkerGateRef,
+}
+
+pub type ServerAccessRef = WrappedRcRefCell<ServerAccess>;
+
+impl ServerAccess {
+    pub fn keys(&self) -> &RoleKeys {
+        &self.keys
+    }
+
+    /// Generates new keys of clients and stores them into the access file.
+    /// Connections that are already established keep using the old keys.
+    /// When `workers` is set, the key of workers is replaced too and all workers are disconnected.
+    pub fn rotate_keys(&mut self, workers: bool) -> crate::Result<()> {
+        let keys = RoleKeys::generate();
+        let mut record = self.record.clone();
+        record.set_role_keys(keys.clone());
+        let worker_key = workers.then(|| Arc::new(generate_key()));
+        if let Some(key) = &worker_key {
+            record.set_tako_secret_key(key.clone());
+        }
+        self.server_dir.replace_access_record(&record)?;
+        self.record = record;
+        self.keys = keys;
+        if let Some(key) = worker_key {
+            self.worker_gate.get_mut().replace_key(key);
+        }
+        Ok(())
+    }
+}
+
+impl ServerAccessRef {
+    pub fn new(
+        server_dir: ServerDir,
+        record: AccessRecord,
+        keys: RoleKeys,
+        worker_gate: WorkerGateRef,
+    ) -> ServerAccessRef {
+        WrappedRcRefCell::wrap(ServerAccess {
+            server_dir,
+            record,
+            keys,
+            worker_gate,
+        })
+    }
+}
@@ -0,0 +1,277 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::server::client::ClientSession;
+use crate::transfer::auth::AccessRole;
+use crate::{JobId, JobTaskCount, WorkerId};
+
+/// Mutating operation requested by a client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "operation", rename_all = "kebab-case")]
+pub enum AuditOperation {
+    Submit {
+        job_id: Option<JobId>,
+        name: String,
+        tasks: Option<JobTaskCount>,
+        command: String,
+    },
+    Cancel {
+        selector: String,
+        tasks: Option<String>,
+        canceled_jobs: Vec<JobId>,
+        canceled_tasks: usize,
+    },
+    StopWorker {
+        worker_id: WorkerId,
+    },
+    StopServer,
+    RotateKeys {
+        /// The key of workers was replaced as well
+        #[serde(default)]
+        workers: bool,
+    },
+    DrainServer {
+        timeout: Option<String>,
+    },
+}
+
+impl AuditOperation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditOperation::Submit { .. } => "submit",
+            AuditOperation::Cancel { .. } => "cancel",
+            AuditOperation::StopWorker { .. } => "stop-worker",
+            AuditOperation::StopServer => "stop-server",
+            AuditOperation::RotateKeys { .. } => "rotate-keys",
+            AuditOperation::DrainServer { .. } => "drain-server",
+        }
+    }
+
+    pub fn details(&self) -> String {
+        match self {
+            AuditOperation::Submit {
+                job_id,
+                name,
+                tasks,
+                command,
+            } => {
+                let job = match (job_id, tasks) {
+                    (Some(job_id), Some(tasks)) => format!("job {} ({} tasks)", job_id, tasks),
+                    _ => "job".to_string(),
+                };
+                format!("{} `{}`: {}", job, name, command)
+            }
+            AuditOperation::Cancel {
+                selector,
+                tasks,
+                canceled_jobs,
+                canceled_tasks,
+            } => {
+                let tasks = match tasks {
+                    Some(tasks) => format!(" tasks {}", tasks),
+                    None => String::new(),
+                };
+                let jobs: Vec<String> = canceled_jobs.iter().map(|id| id.to_string()).collect();
+                format!(
+                    "jobs {}{}: {} tasks canceled in jobs [{}]",
+                    selector,
+                    tasks,
+                    canceled_tasks,
+                    jobs.join(", ")
+                )
+            }
+            AuditOperation::StopWorker { worker_id } => format!("worker {}", worker_id),
+            AuditOperation::DrainServer {
+                timeout: Some(timeout),
+            } => format!("timeout {}", timeout),
+            AuditOperation::RotateKeys { workers: true } => "including workers".to_string(),
+            AuditOperation::StopServer
+            | AuditOperation::RotateKeys { workers: false }
+            | AuditOperation::DrainServer { timeout: None } => String::new(),
+        }
+    }
+
+    fn involves_job(&self, job: JobId) -> bool {
+        match self {
+            AuditOperation::Submit { job_id, .. } => *job_id == Some(job),
+            AuditOperation::Cancel { canceled_jobs, .. } => canceled_jobs.contains(&job),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub user: String,
+    pub peer: String,
+    pub role: AccessRole,
+    #[serde(flatten)]
+    pub operation: AuditOperation,
+    /// Set if the server has rejected the operation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub operation: Option<String>,
+    pub job: Option<JobId>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.user.as_ref().map_or(true, |user| &entry.user == user)
+            && self
+                .operation
+                .as_ref()
+                .map_or(true, |operation| entry.operation.name() == operation)
+            && self
+                .job
+                .map_or(true, |job| entry.operation.involves_job(job))
+    }
+}
+
+/// Append-only log of mutating operations, one JSON object per line
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> crate::Result<AuditLog> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(AuditLog { file })
+    }
+
+    pub fn record(
+        &self,
+        session: &ClientSession,
+        operation: AuditOperation,
+        error: Option<String>,
+    ) {
+        let entry = AuditEntry {
+            time: Utc::now(),
+            user: session.user.clone(),
+            peer: session.peer.clone(),
+            role: session.role,
+            operation,
+            error,
+        };
+        if let Err(e) = self.write_entry(&entry) {
+            log::error!("Cannot write audit log entry {:?}: {}", entry, e);
+        }
+    }
+
+    fn write_entry(&self, entry: &AuditEntry) -> crate::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        // The whole line is written at once, so lines of entries are not interleaved
+        (&self.file).write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Reads entries of an audit log, invalid lines are skipped
+pub fn read_audit_log(path: &Path) -> crate::Result<Vec<AuditEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log::warn!("Invalid audit log entry `{}`: {}", line, e),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::{read_audit_log, AuditFilter, AuditLog, AuditOperation};
+    use crate::server::client::ClientSession;
+    use crate::transfer::auth::AccessRole;
+
+    #[test]
+    fn test_audit_log() {
+        let tmp_dir = TempDir::new("hq").unwrap();
+        let path = tmp_dir.path().join("audit.log");
+        let session = ClientSession {
+            role: AccessRole::Submit,
+            user: "alice".to_string(),
+            peer: "127.0.0.1:1234".to_string(),
+        };
+
+        let log = AuditLog::open(&path).unwrap();
+        log.record(
+            &session,
+            AuditOperation::Submit {
+                job_id: Some(1),
+                name: "sleep".to_string(),
+                tasks: Some(1),
+                command: "sleep 1".to_string(),
+            },
+            None,
+        );
+        log.record(
+            &session,
+            AuditOperation::Cancel {
+                selector: "1".to_string(),
+                tasks: None,
+                canceled_jobs: vec![1],
+                canceled_tasks: 1,
+            },
+            None,
+        );
+        log.record(
+            &session,
+            AuditOperation::StopServer,
+            Some("Permission denied".to_string()),
+        );
+        drop(log);
+
+        // Entries are appended to an existing log
+        AuditLog::open(&path).unwrap().record(
+            &session,
+            AuditOperation::RotateKeys { workers: false },
+            None,
+        );
+
+        let entries = read_audit_log(&path).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].user, "alice");
+        assert_eq!(
+            entries[0].operation.details(),
+            "job 1 (1 tasks) `sleep`: sleep 1"
+        );
+        assert_eq!(entries[2].error.as_deref(), Some("Permission denied"));
+        assert_eq!(entries[3].operation.name(), "rotate-keys");
+
+        let filter = AuditFilter {
+            job: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(entries.iter().filter(|e| filter.matches(e)).count(), 2);
+        let filter = AuditFilter {
+            user: Some("bob".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(entries.iter().filter(|e| filter.matches(e)).count(), 0);
+        let filter = AuditFilter {
+            operation: Some("cancel".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(entries.iter().filter(|e| filter.matches(e)).count(), 1);
+    }
+}
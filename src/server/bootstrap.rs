@@ -1,21 +1,33 @@
+use std::convert::TryFrom;
 use std::future::Future;
-use std::path::Path;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
 
 use anyhow::Context;
-use cli_table::{print_stdout, Cell, Style, Table};
-use tokio::net::TcpListener;
+use cli_table::{print_stdout, Cell, Color, Style, Table};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
 use tokio::sync::Notify;
 use tokio::task::LocalSet;
 
 use crate::client::globalsettings::GlobalSettings;
-use crate::common::serverdir::{AccessRecord, ServerDir};
-use crate::common::setup::setup_interrupt;
+use crate::client::utils::format_memory;
+use crate::common::idset::IdSet;
+use crate::common::serverdir::{store_access_record, AccessRecord, ServerDir, ACCESS_FILE};
+use crate::common::setup::{log_to_file, setup_interrupt};
+use crate::server::access::ServerAccessRef;
+use crate::server::audit::AuditLog;
+use crate::server::client::{handle_client_connections, ClientContext};
+use crate::server::metrics::serve_metrics;
 use crate::server::rpc::TakoServer;
 use crate::server::state::StateRef;
-use crate::transfer::auth::generate_key;
-use crate::transfer::connection::{ClientConnection, HqConnection};
+use crate::server::workergate::{serve_worker_gate, WorkerGateRef};
+use crate::transfer::auth::{generate_key, AccessRole, RoleKeys};
+use crate::transfer::connection::ClientConnection;
+use crate::transfer::messages::{ServerInfo, ServerStats, StateCounts};
+use humantime::format_duration;
 use std::time::Duration;
 
 enum ServerStatus {
@@ -26,12 +38,76 @@ enum ServerStatus {
 pub struct ServerConfig {
     pub host: String,
     pub idle_timeout: Option<Duration>,
+    /// Address on which the server listens for clients and workers
+    pub bind: IpAddr,
+    /// Ports that may be used for clients, a free port is chosen if it is not set
+    pub client_ports: Option<IdSet>,
+    /// Ports that may be used for workers, a free port is chosen if it is not set
+    pub worker_ports: Option<IdSet>,
+    /// Port of the HTTP endpoint with metrics, the endpoint is disabled if it is not set
+    pub metrics_port: Option<u16>,
+    /// Address on which the HTTP endpoint with metrics listens
+    pub metrics_bind: IpAddr,
+    /// Command executed when any job ends
+    pub on_job_finish: Option<String>,
+}
+
+/// Returns addresses that should be tried for binding a socket in the given order
+fn socket_addresses(bind: IpAddr, ports: Option<&IdSet>) -> anyhow::Result<Vec<SocketAddr>> {
+    match ports {
+        None => Ok(vec![SocketAddr::new(bind, 0)]),
+        Some(ports) => ports
+            .iter()
+            .map(|port| match u16::try_from(port) {
+                Ok(port) if port > 0 => Ok(SocketAddr::new(bind, port)),
+                _ => anyhow::bail!("Invalid port {}", port),
+            })
+            .collect(),
+    }
+}
+
+/// Addresses are tried in the given order until a socket is created
+async fn bind_listener(addresses: &[SocketAddr], name: &str) -> anyhow::Result<TcpListener> {
+    for address in addresses {
+        match TcpListener::bind(address).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => log::debug!("Cannot bind {} to {}: {}", name, address, e),
+        }
+    }
+    anyhow::bail!(
+        "Cannot create {}, none of the requested ports is available",
+        name
+    )
+}
+
+/// Creates a Unix socket that is accessible only by the owner of the server.
+/// The socket is created inside a directory accessible only by the owner, so that nobody else
+/// can connect to it before its permissions are restricted.
+/// The server works without it (e.g. when the path is too long), so errors are only logged.
+fn bind_local_listener(path: &Path) -> Option<UnixListener> {
+    let bind = || -> std::io::Result<UnixListener> {
+        if let Some(parent) = path.parent() {
+            std::fs::DirBuilder::new().mode(0o700).create(parent)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    };
+    match bind() {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            log::warn!("Cannot create local socket {:?}: {}", path, e);
+            let _ = std::fs::remove_file(path);
+            None
+        }
+    }
 }
 
 /// This function initializes the HQ server.
 ///
 /// It takes a path to a directory and tries to find metadata of a running HQ server either directly
-/// inside the directory or in a path linked to by a symlink named [`SYMLINK_PATH`].
+/// inside the directory or in a path linked to by a symlink named [`SYMLINK_PATH`]
+/// (or [`SYMLINK_PATH`]`-<name>` for named servers).
 ///
 /// If no metadata is found or the metadata links to a server that seems to be offline, a new
 /// server will be started.
@@ -41,25 +117,45 @@ pub async fn init_hq_server(
     gsettings: &GlobalSettings,
     server_cfg: ServerConfig,
 ) -> anyhow::Result<()> {
-    match get_server_status(gsettings.server_directory()).await {
+    match get_server_status(gsettings.server_directory(), gsettings.server_name()).await {
         Err(_) | Ok(ServerStatus::Offline(_)) => {
             log::info!("No online server found, starting a new server");
             start_server(gsettings, server_cfg).await
         }
-        Ok(ServerStatus::Online(_)) => anyhow::bail!(
-            "Server at {0} is already online, please stop it first using \
-            `hq stop --server-dir {0}`",
-            gsettings.server_directory().display()
-        ),
+        Ok(ServerStatus::Online(_)) => {
+            let name_arg = match gsettings.server_name() {
+                Some(name) => format!(" --server-name {}", name),
+                None => String::new(),
+            };
+            anyhow::bail!(
+                "Server at {0} is already online, please stop it first using \
+                `hq server stop --server-dir {0}{1}`",
+                gsettings.server_directory().display(),
+                name_arg
+            )
+        }
     }
 }
 
-pub async fn get_client_connection(server_directory: &Path) -> anyhow::Result<ClientConnection> {
-    let sd = ServerDir::open(server_directory).context("No running instance of HQ found")?;
+pub async fn get_client_connection(gsettings: &GlobalSettings) -> anyhow::Result<ClientConnection> {
+    let sd = gsettings
+        .open_server_dir()
+        .context("No running instance of HQ found")?;
     let access_record = sd
         .read_access_record()
         .with_context(|| format!("Cannot read access record from {:?}", sd.access_filename()))?;
-    let connection = HqConnection::connect_to_server(&access_record)
+    let socket_path = sd.local_socket_path();
+    if socket_path.exists() {
+        match ClientConnection::connect_to_local_server(&access_record, &socket_path).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) => log::debug!(
+                "Cannot connect to local socket {:?}, using TCP: {}",
+                socket_path,
+                e
+            ),
+        }
+    }
+    let connection = ClientConnection::connect_to_server(&access_record)
         .await
         .with_context(|| {
             format!(
@@ -71,14 +167,20 @@ pub async fn get_client_connection(server_directory: &Path) -> anyhow::Result<Cl
     Ok(connection)
 }
 
-async fn get_server_status(server_directory: &Path) -> crate::Result<ServerStatus> {
-    let record = ServerDir::open(server_directory).and_then(|sd| sd.read_access_record())?;
+async fn get_server_status(
+    server_directory: &Path,
+    server_name: Option<&str>,
+) -> crate::Result<ServerStatus> {
+    let record =
+        ServerDir::open(server_directory, server_name).and_then(|sd| sd.read_access_record())?;
+    Ok(server_status(record).await)
+}
 
-    if HqConnection::connect_to_server(&record).await.is_err() {
-        return Ok(ServerStatus::Offline(record));
+async fn server_status(record: AccessRecord) -> ServerStatus {
+    if ClientConnection::connect_to_server(&record).await.is_err() {
+        return ServerStatus::Offline(record);
     }
-
-    Ok(ServerStatus::Online(record))
+    ServerStatus::Online(record)
 }
 
 async fn initialize_server(
@@ -87,39 +189,90 @@ async fn initialize_server(
     server_cfg: ServerConfig,
 ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>>> {
     let server_directory = gsettings.server_directory();
-    let client_listener = TcpListener::bind("0.0.0.0:0")
-        .await
-        .with_context(|| "Cannot create HQ server socket".to_string())?;
+    let client_addresses = socket_addresses(server_cfg.bind, server_cfg.client_ports.as_ref())?;
+    let worker_addresses = socket_addresses(server_cfg.bind, server_cfg.worker_ports.as_ref())?;
+    let client_listener = bind_listener(&client_addresses, "HQ server socket").await?;
+    let worker_listener = bind_listener(&worker_addresses, "socket for workers").await?;
     let server_port = client_listener.local_addr()?.port();
+    let metrics_listener = match server_cfg.metrics_port {
+        Some(port) => {
+            let address = SocketAddr::new(server_cfg.metrics_bind, port);
+            let listener = TcpListener::bind(address)
+                .await
+                .with_context(|| format!("Cannot bind metrics endpoint to {}", address))?;
+            log::info!(
+                "Metrics are available at http://{}/metrics",
+                listener.local_addr()?
+            );
+            Some(listener)
+        }
+        None => None,
+    };
 
-    let hq_secret_key = Arc::new(generate_key());
+    let role_keys = RoleKeys::generate();
+    // Workers authenticate with the key from the access record at the worker gate,
+    // the key of tako is internal and it is used only by the gate on the server
+    let worker_key = Arc::new(generate_key());
     let tako_secret_key = Arc::new(generate_key());
 
     let state_ref = StateRef::new();
+    state_ref
+        .get_mut()
+        .set_job_finish_hook(server_cfg.on_job_finish);
+    let tako_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
     let (tako_server, tako_future) = TakoServer::start(
         state_ref.clone(),
         tako_secret_key.clone(),
         server_cfg.idle_timeout,
+        tako_address,
     )
     .await?;
+    let worker_gate = WorkerGateRef::new(
+        worker_key.clone(),
+        tako_secret_key,
+        SocketAddr::new(tako_address.ip(), tako_server.worker_port()),
+    );
 
     let record = AccessRecord::new(
         server_cfg.host,
         server_port,
-        tako_server.worker_port(),
-        hq_secret_key.clone(),
-        tako_secret_key.clone(),
+        worker_listener.local_addr()?.port(),
+        role_keys.clone(),
+        worker_key,
+        gsettings.server_name().map(|name| name.to_string()),
     );
 
-    ServerDir::create(server_directory, &record)?;
-    print_access_record(gsettings, server_directory, &record);
+    let server_dir = ServerDir::create(server_directory, &record)?;
+    log_to_file(&server_dir.server_log_path());
+    let socket_path = server_dir.local_socket_path();
+    let local_listener = bind_local_listener(&socket_path);
+    let audit_path = server_dir.audit_log_path();
+    let audit = AuditLog::open(&audit_path)
+        .with_context(|| format!("Cannot open audit log {:?}", audit_path))?;
+    let server_info = Rc::new(ServerInfo::new(server_directory, &record));
+    print_server_info(gsettings, &server_info, None);
 
     let stop_notify = Rc::new(Notify::new());
-    let stop_cloned = stop_notify.clone();
+    let context = ClientContext {
+        state_ref,
+        tako_ref: tako_server,
+        end_flag: stop_notify.clone(),
+        server_info,
+        access: ServerAccessRef::new(server_dir, record, role_keys, worker_gate.clone()),
+        audit: Rc::new(audit),
+    };
+    let metrics_future = {
+        let context = context.clone();
+        async move {
+            match metrics_listener {
+                Some(listener) => serve_metrics(listener, context).await,
+                None => futures::future::pending().await,
+            }
+        }
+    };
 
-    let key = hq_secret_key;
     let fut = async move {
-        tokio::select! {
+        let result = tokio::select! {
             _ = end_flag.notified() => {
                 log::info!("Received SIGINT");
                 Ok(())
@@ -128,15 +281,13 @@ async fn initialize_server(
                 log::info!("Stopping after Stop command from client");
                 Ok(())
             },
-            () = crate::server::client::handle_client_connections(
-                state_ref,
-                tako_server,
-                client_listener,
-                stop_cloned,
-                key
-            ) => { Ok(()) }
+            () = handle_client_connections(client_listener, local_listener, context) => { Ok(()) }
+            () = metrics_future => { Ok(()) }
+            () = serve_worker_gate(worker_listener, worker_gate) => { Ok(()) }
             r = tako_future => { r.map_err(|e| e.into()) }
-        }
+        };
+        let _ = std::fs::remove_file(&socket_path);
+        result
     };
     Ok(fut)
 }
@@ -151,25 +302,211 @@ async fn start_server(
     local_set.run_until(fut).await
 }
 
-pub fn print_access_record(gsettings: &GlobalSettings, server_dir: &Path, record: &AccessRecord) {
-    let rows = vec![
+/// Stores a copy of the access record of the running server, restricted to `role`,
+/// into `target_dir`. The directory can be then used as a server directory of clients.
+pub fn export_access_record(
+    gsettings: &GlobalSettings,
+    role: AccessRole,
+    target_dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let record = gsettings
+        .open_server_dir()
+        .and_then(|sd| sd.read_access_record())
+        .context("No running instance of HQ found")?;
+    if record.secret_key(role).is_none() {
+        anyhow::bail!(
+            "The access record does not contain a key of the {} role",
+            role
+        );
+    }
+    std::fs::create_dir_all(target_dir)?;
+    let path = target_dir.join(ACCESS_FILE);
+    store_access_record(&record.restrict(role), &path)
+        .with_context(|| format!("Cannot write access file {:?}", path))?;
+    Ok(path)
+}
+
+struct ServerInstance {
+    server_dir: ServerDir,
+    status: ServerStatus,
+    active: bool,
+}
+
+/// Returns all server instances stored in the server directory together with their status
+async fn list_server_instances(server_directory: &Path) -> anyhow::Result<Vec<ServerInstance>> {
+    let mut instances = Vec::new();
+    for server_dir in ServerDir::list_instances(server_directory)
+        .with_context(|| format!("Cannot read server directory {:?}", server_directory))?
+    {
+        let record = match server_dir.read_access_record() {
+            Ok(record) => record,
+            Err(e) => {
+                log::warn!(
+                    "Cannot read access record from {:?}: {}",
+                    server_dir.access_filename(),
+                    e
+                );
+                continue;
+            }
+        };
+        let active = server_dir.is_active(server_directory, record.name());
+        instances.push(ServerInstance {
+            server_dir,
+            status: server_status(record).await,
+            active,
+        });
+    }
+    Ok(instances)
+}
+
+pub async fn print_server_list(gsettings: &GlobalSettings) -> anyhow::Result<()> {
+    let instances = list_server_instances(gsettings.server_directory()).await?;
+    let rows: Vec<_> = instances
+        .iter()
+        .map(|instance| {
+            let (record, status) = match &instance.status {
+                ServerStatus::Online(record) => {
+                    (record, "ONLINE".cell().foreground_color(Some(Color::Green)))
+                }
+                ServerStatus::Offline(record) => {
+                    (record, "OFFLINE".cell().foreground_color(Some(Color::Red)))
+                }
+            };
+            vec![
+                record.name().unwrap_or("(default)").cell(),
+                status,
+                if instance.active { "yes" } else { "no" }.cell(),
+                format!("{}:{}", record.host(), record.server_port()).cell(),
+                record.start_date().format("%F %T %Z").cell(),
+                record.version().cell(),
+                instance.server_dir.directory().display().cell(),
+            ]
+        })
+        .collect();
+    let table = rows
+        .table()
+        .color_choice(gsettings.color_policy())
+        .title(vec![
+            "Name".cell().bold(true),
+            "Status".cell().bold(true),
+            "Active".cell().bold(true),
+            "Address".cell().bold(true),
+            "Start date".cell().bold(true),
+            "Version".cell().bold(true),
+            "Directory".cell().bold(true),
+        ]);
+    assert!(print_stdout(table).is_ok());
+    Ok(())
+}
+
+/// How long pruning waits for a connection to a server instance
+const PRUNE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns true only if the server of the record has certainly stopped, i.e. a connection
+/// to its address was refused. Servers that are just unreachable (e.g. from another host)
+/// are not considered to be stopped.
+async fn is_server_stopped(record: &AccessRecord) -> bool {
+    let address = (record.host(), record.server_port());
+    match tokio::time::timeout(PRUNE_CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+        Ok(Err(e)) => e.kind() == std::io::ErrorKind::ConnectionRefused,
+        _ => false,
+    }
+}
+
+/// Removes directories of all server instances that have stopped.
+/// Active instances (the targets of `hq-current*` symlinks) are removed only when `force` is set.
+pub async fn prune_server_instances(gsettings: &GlobalSettings, force: bool) -> anyhow::Result<()> {
+    let server_directory = gsettings.server_directory();
+    for instance in list_server_instances(server_directory).await? {
+        let record = match instance.status {
+            ServerStatus::Offline(record) => record,
+            ServerStatus::Online(_) => continue,
+        };
+        let path = instance.server_dir.directory().clone();
+        if !is_server_stopped(&record).await {
+            log::warn!(
+                "Skipping {}, server at {}:{} is unreachable, but it may still be running",
+                path.display(),
+                record.host(),
+                record.server_port()
+            );
+            continue;
+        }
+        if instance.active && !force {
+            log::warn!(
+                "Skipping {}, it is the active instance of the server (use --force to remove it)",
+                path.display()
+            );
+            continue;
+        }
+        instance
+            .server_dir
+            .remove(server_directory, record.name())
+            .with_context(|| format!("Cannot remove server directory {:?}", path))?;
+        println!("Removed {}", path.display());
+    }
+    Ok(())
+}
+
+pub fn print_server_info(
+    gsettings: &GlobalSettings,
+    info: &ServerInfo,
+    stats: Option<&ServerStats>,
+) {
+    let mut rows = vec![
         vec![
             "Server directory".cell().bold(true),
-            server_dir.display().cell(),
-        ],
-        vec!["Host".cell().bold(true), record.host().cell()],
-        vec!["Pid".cell().bold(true), record.pid().cell()],
-        vec!["HQ port".cell().bold(true), record.server_port().cell()],
-        vec![
-            "Workers port".cell().bold(true),
-            record.worker_port().cell(),
+            info.server_dir.display().cell(),
         ],
+        vec!["Host".cell().bold(true), info.host.as_str().cell()],
+        vec!["Pid".cell().bold(true), info.pid.cell()],
+        vec!["HQ port".cell().bold(true), info.server_port.cell()],
+        vec!["Workers port".cell().bold(true), info.worker_port.cell()],
         vec![
             "Start date".cell().bold(true),
-            record.start_date().format("%F %T %Z").cell(),
+            info.start_date.format("%F %T %Z").cell(),
         ],
-        vec!["Version".cell().bold(true), record.version().cell()],
+        vec!["Version".cell().bold(true), info.version.as_str().cell()],
     ];
+    if let Some(stats) = stats {
+        let format_counts = |counts: &StateCounts| {
+            format!(
+                "{} waiting, {} running, {} finished, {} failed, {} canceled",
+                counts.waiting, counts.running, counts.finished, counts.failed, counts.canceled
+            )
+        };
+        rows.extend(vec![
+            vec![
+                "Uptime".cell().bold(true),
+                format_duration(Duration::from_secs(stats.uptime.as_secs())).cell(),
+            ],
+            vec!["Jobs".cell().bold(true), format_counts(&stats.jobs).cell()],
+            vec![
+                "Tasks".cell().bold(true),
+                format_counts(&stats.tasks).cell(),
+            ],
+            vec![
+                "Workers".cell().bold(true),
+                format!(
+                    "{} online, {} offline",
+                    stats.online_workers, stats.offline_workers
+                )
+                .cell(),
+            ],
+            vec![
+                "CPUs".cell().bold(true),
+                format!("{} used, {} total", stats.used_cpus, stats.total_cpus).cell(),
+            ],
+            vec![
+                "Memory usage".cell().bold(true),
+                stats
+                    .memory_usage
+                    .map(format_memory)
+                    .unwrap_or_else(|| "N/A".to_string())
+                    .cell(),
+            ],
+        ]);
+    }
     let table = rows.table().color_choice(gsettings.color_policy());
     assert!(print_stdout(table).is_ok());
 }
@@ -185,22 +522,29 @@ mod tests {
     use crate::common::fsutils::test_utils::run_concurrent;
     use crate::common::serverdir::{store_access_record, AccessRecord, ServerDir, SYMLINK_PATH};
     use crate::server::bootstrap::{
-        get_client_connection, get_server_status, initialize_server, ServerConfig,
+        get_client_connection, get_server_status, initialize_server, socket_addresses, ServerConfig,
     };
 
     use super::ServerStatus;
     use crate::client::globalsettings::GlobalSettings;
     use cli_table::ColorChoice;
     use std::future::Future;
+    use std::net::{IpAddr, Ipv4Addr};
     use std::path::Path;
 
     pub async fn init_test_server(
         tmp_dir: &Path,
     ) -> (impl Future<Output = anyhow::Result<()>>, Arc<Notify>) {
-        let gsettings = GlobalSettings::new(tmp_dir.to_path_buf(), ColorChoice::Never);
+        let gsettings = GlobalSettings::new(tmp_dir.to_path_buf(), None, ColorChoice::Never);
         let server_cfg = ServerConfig {
             host: "localhost".to_string(),
             idle_timeout: None,
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            client_ports: None,
+            worker_ports: None,
+            metrics_port: None,
+            metrics_bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            on_job_finish: None,
         };
         let notify = Arc::new(Notify::new());
         (
@@ -214,19 +558,25 @@ mod tests {
     #[tokio::test]
     async fn test_status_empty_directory() {
         let tmp_dir = TempDir::new("foo").unwrap();
-        assert!(get_server_status(&tmp_dir.into_path()).await.is_err());
+        assert!(get_server_status(&tmp_dir.into_path(), None).await.is_err());
     }
 
     #[tokio::test]
     async fn test_status_directory_with_access_file() {
         let tmp_dir = TempDir::new("foo").unwrap();
         let tmp_path = tmp_dir.into_path();
-        let server_dir = ServerDir::open(&tmp_path).unwrap();
-        let record =
-            AccessRecord::new("foo".into(), 42, 43, Default::default(), Default::default());
+        let server_dir = ServerDir::open(&tmp_path, None).unwrap();
+        let record = AccessRecord::new(
+            "foo".into(),
+            42,
+            43,
+            Default::default(),
+            Default::default(),
+            None,
+        );
         store_access_record(&record, server_dir.access_filename()).unwrap();
 
-        let res = get_server_status(&tmp_path).await.unwrap();
+        let res = get_server_status(&tmp_path, None).await.unwrap();
         assert!(matches!(res, ServerStatus::Offline(_)));
     }
 
@@ -237,13 +587,19 @@ mod tests {
         std::fs::create_dir(&actual_dir).unwrap();
         std::os::unix::fs::symlink(&actual_dir, tmp_dir.join(SYMLINK_PATH)).unwrap();
 
-        let server_dir = ServerDir::open(&actual_dir).unwrap();
-        let record =
-            AccessRecord::new("foo".into(), 42, 43, Default::default(), Default::default());
+        let server_dir = ServerDir::open(&actual_dir, None).unwrap();
+        let record = AccessRecord::new(
+            "foo".into(),
+            42,
+            43,
+            Default::default(),
+            Default::default(),
+            None,
+        );
         store_access_record(&record, server_dir.access_filename()).unwrap();
 
         //let server_dir = ServerDir::open(&tmp_dir).unwrap();
-        let res = get_server_status(&tmp_dir).await.unwrap();
+        let res = get_server_status(&tmp_dir, None).await.unwrap();
         assert!(matches!(res, ServerStatus::Offline(_)));
     }
 
@@ -252,7 +608,8 @@ mod tests {
         let tmp_dir = TempDir::new("foo").unwrap().into_path();
         let (fut, _) = init_test_server(&tmp_dir).await;
         let (set, handle) = run_concurrent(fut, async {
-            let mut connection = get_client_connection(&tmp_dir).await.unwrap();
+            let gsettings = GlobalSettings::new(tmp_dir.clone(), None, ColorChoice::Never);
+            let mut connection = get_client_connection(&gsettings).await.unwrap();
             stop_server(&mut connection).await.unwrap();
         })
         .await;
@@ -266,4 +623,23 @@ mod tests {
         notify.notify_one();
         fut.await.unwrap();
     }
+
+    #[test]
+    fn test_socket_addresses() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(
+            socket_addresses(ip, None).unwrap(),
+            vec!["127.0.0.1:0".parse().unwrap()]
+        );
+        assert_eq!(
+            socket_addresses(ip, Some(&"6000-6001,7000".parse().unwrap())).unwrap(),
+            vec![
+                "127.0.0.1:6000".parse().unwrap(),
+                "127.0.0.1:6001".parse().unwrap(),
+                "127.0.0.1:7000".parse().unwrap()
+            ]
+        );
+        assert!(socket_addresses(ip, Some(&"0".parse().unwrap())).is_err());
+        assert!(socket_addresses(ip, Some(&"65536".parse().unwrap())).is_err());
+    }
 }
@@ -1,61 +1,142 @@
+use std::net::SocketAddr;
 use std::rc::Rc;
-use std::sync::Arc;
 
+use chrono::Utc;
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use orion::kdf::SecretKey;
-use tako::messages::common::{LauncherDefinition, ProgramDefinition};
+use tako::common::resources::CpuRequest;
 use tako::messages::gateway::{
-    CancelTasks, FromGatewayMessage, NewTasksMessage, StopWorkerRequest, TaskDef, ToGatewayMessage,
+    CancelTasks, FromGatewayMessage, NewTasksMessage, StopWorkerRequest, ToGatewayMessage,
 };
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
 use tokio::sync::Notify;
 
 use crate::client::job::{job_status, Status};
-use crate::common::env::{HQ_ENTRY, HQ_JOB_ID, HQ_SUBMIT_DIR, HQ_TASK_ID};
+use crate::common::idset::IdSet;
+use crate::server::access::ServerAccessRef;
+use crate::server::audit::{AuditLog, AuditOperation};
 use crate::server::job::Job;
 use crate::server::rpc::TakoServer;
-use crate::server::state::StateRef;
-use crate::transfer::connection::ServerConnection;
+use crate::server::state::{submit_job_tasks, State, StateRef};
+use crate::transfer::auth::AccessRole;
+use crate::transfer::connection::{ConnectionStream, ServerConnection};
 use crate::transfer::messages::{
-    CancelJobResponse, FromClientMessage, JobInfoResponse, JobSelector, JobType, SubmitRequest,
-    SubmitResponse, ToClientMessage, WorkerListResponse,
+    CancelJobResponse, DrainProgress, DrainResponse, FromClientMessage, JobInfoResponse,
+    JobSelector, JobType, ServerInfo, ServerInfoResponse, ServerStats, StateCounts, SubmitRequest,
+    SubmitResponse, TaskFinishMessage, ToClientMessage, WorkerListResponse, WorkerStatsMessage,
 };
-use crate::{JobId, JobTaskCount, JobTaskId, WorkerId};
+use crate::worker::stats::parse_proc_kb_value;
+use crate::{JobId, Set, TakoTaskId, WorkerId};
 use bstr::BString;
-use std::path::Path;
+use humantime::format_duration;
+use std::time::{Duration, Instant};
+
+/// How often the progress of draining is sent to the client
+const DRAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long draining waits until stopped workers disconnect
+const WORKER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause after a failed accept of a client connection, so that a persistent error does not
+/// make the server spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Maximum memory occupied by entries that a client uploads for a single job
+const MAX_UPLOADED_ENTRIES_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Parts of the server that are shared by all client connections
+#[derive(Clone)]
+pub struct ClientContext {
+    pub state_ref: StateRef,
+    pub tako_ref: TakoServer,
+    pub end_flag: Rc<Notify>,
+    pub server_info: Rc<ServerInfo>,
+    pub access: ServerAccessRef,
+    pub audit: Rc<AuditLog>,
+}
+
+/// Identity of a connected client
+pub struct ClientSession {
+    pub role: AccessRole,
+    pub user: String,
+    /// Address of the client
+    pub peer: String,
+}
 
 pub async fn handle_client_connections(
-    state_ref: StateRef,
-    tako_ref: TakoServer,
     listener: TcpListener,
-    end_flag: Rc<Notify>,
-    key: Arc<SecretKey>,
+    mut local_listener: Option<UnixListener>,
+    context: ClientContext,
 ) {
-    while let Ok((connection, _)) = listener.accept().await {
-        let state_ref = state_ref.clone();
-        let tako_ref = tako_ref.clone();
-        let end_flag = end_flag.clone();
-        let key = key.clone();
+    loop {
+        let (connection, peer): (Box<dyn ConnectionStream>, Option<SocketAddr>) = tokio::select! {
+            r = listener.accept() => match r {
+                Ok((connection, address)) => (Box::new(connection), Some(address)),
+                Err(e) => {
+                    // The error may be temporary (e.g. too many open files)
+                    log::error!("Cannot accept a client connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            r = accept_local(&local_listener) => match r {
+                Ok(connection) => (Box::new(connection), None),
+                Err(e) => {
+                    log::error!(
+                        "Cannot accept a client connection on the local socket, \
+                        clients have to connect through TCP: {}",
+                        e
+                    );
+                    local_listener = None;
+                    continue;
+                }
+            },
+        };
+        let context = context.clone();
         tokio::task::spawn_local(async move {
-            if let Err(e) = handle_client(connection, state_ref, tako_ref, end_flag, key).await {
+            if let Err(e) = handle_client(connection, peer, context).await {
                 log::error!("Client error: {}", e);
             }
         });
     }
 }
 
+async fn accept_local(listener: &Option<UnixListener>) -> std::io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(connection, _)| connection),
+        None => futures::future::pending().await,
+    }
+}
+
+/// `peer` is `None` for clients connected through the local socket
 async fn handle_client(
-    socket: TcpStream,
-    state_ref: StateRef,
-    tako_ref: TakoServer,
-    end_flag: Rc<Notify>,
-    key: Arc<SecretKey>,
+    socket: Box<dyn ConnectionStream>,
+    peer: Option<SocketAddr>,
+    context: ClientContext,
 ) -> crate::Result<()> {
     log::debug!("New client connection");
-    let socket = ServerConnection::accept_client(socket, key).await?;
+    let (socket, hello) = match peer {
+        None => ServerConnection::accept_local_client(socket).await?,
+        Some(_) => {
+            let keys = context.access.get().keys().clone();
+            ServerConnection::accept_client(socket, &keys).await?
+        }
+    };
+    log::debug!(
+        "Client of user {} authenticated with role {}",
+        hello.user,
+        hello.role
+    );
+    let session = ClientSession {
+        role: hello.role,
+        user: hello.user,
+        peer: match peer {
+            Some(address) => address.to_string(),
+            None => "local".to_string(),
+        },
+    };
     let (tx, rx) = socket.split();
 
-    client_rpc_loop(tx, rx, state_ref, tako_ref, end_flag).await;
+    client_rpc_loop(tx, rx, &context, &session).await;
     log::debug!("Client connection ended");
     Ok(())
 }
@@ -66,34 +147,166 @@ pub async fn client_rpc_loop<
 >(
     mut tx: Tx,
     mut rx: Rx,
-    state_ref: StateRef,
-    tako_ref: TakoServer,
-    end_flag: Rc<Notify>,
+    context: &ClientContext,
+    session: &ClientSession,
 ) {
+    let ClientContext {
+        state_ref,
+        tako_ref,
+        end_flag,
+        server_info,
+        access,
+        audit,
+    } = context;
+    let role = session.role;
+    let mut uploaded_entries = EntryUpload::default();
     while let Some(message_result) = rx.next().await {
         match message_result {
             Ok(message) => {
+                // Uploaded entries belong only to the message that directly follows them
+                let upload = match &message {
+                    FromClientMessage::UploadEntries(_) => None,
+                    _ => Some(uploaded_entries.take()),
+                };
+                state_ref
+                    .get_mut()
+                    .metrics_mut()
+                    .record_request(message.name());
+                let required_role = message.required_role();
+                if !role.allows(required_role) {
+                    log::warn!(
+                        "Client with role {} sent a message that requires the {} role",
+                        role,
+                        required_role
+                    );
+                    let error = format!(
+                        "Permission denied: this operation requires the {} role, \
+                        but the client has the {} role",
+                        required_role, role
+                    );
+                    if let Some(operation) = denied_operation(&message) {
+                        audit.record(session, operation, Some(error.clone()));
+                    }
+                    // The error is sent even for messages without a response,
+                    // so that the denial is not ignored silently
+                    assert!(tx.send(ToClientMessage::Error(error)).await.is_ok());
+                    continue;
+                }
                 let response = match message {
-                    FromClientMessage::Submit(msg) => {
-                        handle_submit(&state_ref, &tako_ref, msg).await
+                    FromClientMessage::Submit(mut msg) => {
+                        let name = msg.name.clone();
+                        let command = format_command(&msg.spec.args);
+                        let response = match upload.filter(|_| msg.uploaded_entries) {
+                            Some(Err(error)) => ToClientMessage::Error(error),
+                            Some(Ok(entries)) => {
+                                msg.entries = Some(entries);
+                                handle_submit(state_ref, tako_ref, msg).await
+                            }
+                            None => handle_submit(state_ref, tako_ref, msg).await,
+                        };
+                        let job = match &response {
+                            ToClientMessage::SubmitResponse(r) => {
+                                Some((r.job.info.id, r.job.info.n_tasks))
+                            }
+                            _ => None,
+                        };
+                        let operation = AuditOperation::Submit {
+                            job_id: job.map(|(id, _)| id),
+                            name,
+                            tasks: job.map(|(_, tasks)| tasks),
+                            command,
+                        };
+                        audit.record(session, operation, response_error(&response));
+                        response
                     }
-                    FromClientMessage::JobInfo(msg) => compute_job_info(&state_ref, msg.selector),
+                    FromClientMessage::JobInfo(msg) => compute_job_info(state_ref, msg.selector),
                     FromClientMessage::Stop => {
+                        audit.record(session, AuditOperation::StopServer, None);
+                        // The client may have already disconnected, the server stops anyway
+                        let _ = tx.send(ToClientMessage::StopResponse).await;
                         end_flag.notify_one();
                         break;
                     }
-                    FromClientMessage::WorkerList => handle_worker_list(&state_ref).await,
+                    FromClientMessage::WorkerList => handle_worker_list(state_ref).await,
                     FromClientMessage::WorkerInfo(msg) => {
-                        handle_worker_info(&state_ref, msg.worker_id).await
+                        handle_worker_info(state_ref, msg.worker_id).await
                     }
                     FromClientMessage::StopWorker(msg) => {
-                        handle_worker_stop(&tako_ref, msg.worker_id).await.unwrap()
+                        let worker_id = msg.worker_id;
+                        let response = handle_worker_stop(tako_ref, worker_id).await.unwrap();
+                        audit.record(
+                            session,
+                            AuditOperation::StopWorker { worker_id },
+                            response_error(&response),
+                        );
+                        response
                     }
                     FromClientMessage::Cancel(msg) => {
-                        handle_job_cancel(&state_ref, &tako_ref, msg.selector).await
+                        let selector = msg.selector.to_string();
+                        let tasks = msg.tasks.as_ref().map(|tasks| tasks.to_string());
+                        let response =
+                            handle_job_cancel(state_ref, tako_ref, msg.selector, msg.tasks).await;
+                        audit.record(
+                            session,
+                            cancel_operation(selector, tasks, &response),
+                            response_error(&response),
+                        );
+                        response
                     }
                     FromClientMessage::JobDetail(msg) => {
-                        compute_job_detail(&state_ref, msg.job_id, msg.include_tasks)
+                        compute_job_detail(state_ref, msg.selector, msg.include_tasks)
+                    }
+                    FromClientMessage::RecentFailures => ToClientMessage::RecentFailuresResponse(
+                        state_ref.get().recent_failures().cloned().collect(),
+                    ),
+                    FromClientMessage::ServerInfo => compute_server_info(state_ref, server_info),
+                    FromClientMessage::RotateKeys(msg) => {
+                        let response = match access.get_mut().rotate_keys(msg.workers) {
+                            Ok(()) if msg.workers => {
+                                log::info!("Keys of clients and workers were rotated");
+                                ToClientMessage::RotateKeysResponse
+                            }
+                            Ok(()) => {
+                                log::info!("Keys of clients were rotated");
+                                ToClientMessage::RotateKeysResponse
+                            }
+                            Err(e) => ToClientMessage::Error(format!("Cannot rotate keys: {}", e)),
+                        };
+                        audit.record(
+                            session,
+                            AuditOperation::RotateKeys {
+                                workers: msg.workers,
+                            },
+                            response_error(&response),
+                        );
+                        response
+                    }
+                    FromClientMessage::WorkerStats(msg) => {
+                        handle_worker_stats(state_ref, msg);
+                        continue;
+                    }
+                    FromClientMessage::TaskFinish(msg) => handle_task_finish(state_ref, msg),
+                    FromClientMessage::UploadEntries(entries) => {
+                        uploaded_entries.add(entries);
+                        continue;
+                    }
+                    FromClientMessage::Drain(msg) => {
+                        let timeout = msg
+                            .timeout
+                            .map(|timeout| format_duration(timeout).to_string());
+                        audit.record(session, AuditOperation::DrainServer { timeout }, None);
+                        let response = drain_server(
+                            &mut tx,
+                            state_ref,
+                            tako_ref,
+                            msg.timeout,
+                            msg.cancel_waiting,
+                        )
+                        .await;
+                        // The client may have already disconnected, the server stops anyway
+                        let _ = tx.send(ToClientMessage::DrainResponse(response)).await;
+                        end_flag.notify_one();
+                        break;
                     }
                 };
                 assert!(tx.send(response).await.is_ok());
@@ -113,6 +326,246 @@ pub async fn client_rpc_loop<
     }
 }
 
+/// Entries uploaded by a client for its next submitted job
+#[derive(Default)]
+struct EntryUpload {
+    entries: Vec<BString>,
+    /// Memory occupied by the entries
+    size: usize,
+    /// Set when the entries exceeded the limit, the following submit fails with it
+    error: Option<String>,
+}
+
+impl EntryUpload {
+    fn add(&mut self, entries: Vec<BString>) {
+        if self.error.is_some() {
+            return;
+        }
+        self.size += entries
+            .iter()
+            .map(|entry| entry.len() + std::mem::size_of::<BString>())
+            .sum::<usize>();
+        if self.size > MAX_UPLOADED_ENTRIES_SIZE {
+            self.entries = Vec::new();
+            self.error = Some(format!(
+                "Uploaded entries exceed the limit of {} bytes",
+                MAX_UPLOADED_ENTRIES_SIZE
+            ));
+        } else {
+            self.entries.extend(entries);
+        }
+    }
+
+    /// Returns the uploaded entries (or the error of the upload) and starts a new upload
+    fn take(&mut self) -> Result<Vec<BString>, String> {
+        let upload = std::mem::take(self);
+        match upload.error {
+            Some(error) => Err(error),
+            None => Ok(upload.entries),
+        }
+    }
+}
+
+fn response_error(response: &ToClientMessage) -> Option<String> {
+    match response {
+        ToClientMessage::Error(error) => Some(error.clone()),
+        _ => None,
+    }
+}
+
+fn format_command(args: &[BString]) -> String {
+    args.iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn cancel_operation(
+    selector: String,
+    tasks: Option<String>,
+    response: &ToClientMessage,
+) -> AuditOperation {
+    let mut canceled_jobs = Vec::new();
+    let mut canceled_tasks = 0;
+    if let ToClientMessage::CancelJobResponse(responses) = response {
+        for (job_id, response) in responses {
+            if let CancelJobResponse::Canceled(canceled, _, _) = response {
+                if !canceled.is_empty() {
+                    canceled_jobs.push(*job_id);
+                    canceled_tasks += canceled.len();
+                }
+            }
+        }
+    }
+    AuditOperation::Cancel {
+        selector,
+        tasks,
+        canceled_jobs,
+        canceled_tasks,
+    }
+}
+
+/// Audit operation of a mutating message that was rejected because of the role of the client
+fn denied_operation(message: &FromClientMessage) -> Option<AuditOperation> {
+    let operation = match message {
+        FromClientMessage::Submit(msg) => AuditOperation::Submit {
+            job_id: None,
+            name: msg.name.clone(),
+            tasks: None,
+            command: format_command(&msg.spec.args),
+        },
+        FromClientMessage::Cancel(msg) => AuditOperation::Cancel {
+            selector: msg.selector.to_string(),
+            tasks: msg.tasks.as_ref().map(|tasks| tasks.to_string()),
+            canceled_jobs: Vec::new(),
+            canceled_tasks: 0,
+        },
+        FromClientMessage::StopWorker(msg) => AuditOperation::StopWorker {
+            worker_id: msg.worker_id,
+        },
+        FromClientMessage::Stop => AuditOperation::StopServer,
+        FromClientMessage::RotateKeys(msg) => AuditOperation::RotateKeys {
+            workers: msg.workers,
+        },
+        FromClientMessage::Drain(msg) => AuditOperation::DrainServer {
+            timeout: msg
+                .timeout
+                .map(|timeout| format_duration(timeout).to_string()),
+        },
+        _ => return None,
+    };
+    Some(operation)
+}
+
+/// Refuses new jobs, cancels tasks that have not started yet and waits until the running tasks
+/// end (at most for `timeout`). Then all workers are stopped.
+/// The progress is sent to the client, but a disconnected client does not interrupt the draining.
+async fn drain_server<Tx: Sink<ToClientMessage> + Unpin>(
+    tx: &mut Tx,
+    state_ref: &StateRef,
+    tako_ref: &TakoServer,
+    timeout: Option<Duration>,
+    cancel_waiting: bool,
+) -> DrainResponse {
+    log::info!("Draining the server");
+    let start = Instant::now();
+    let mut canceled_tasks = 0;
+    let mut waiting_tasks = 0;
+    // Waiting tasks are withdrawn from tako, so that they do not start while the server drains.
+    // Unless `cancel_waiting` is set, they stay waiting in the state of their jobs.
+    let withdrawn_tasks: Vec<(JobId, Vec<TakoTaskId>)> = {
+        let mut state = state_ref.get_mut();
+        state.set_draining();
+        let job_ids: Vec<JobId> = state.jobs().map(|job| job.job_id).collect();
+        job_ids
+            .into_iter()
+            .map(|job_id| {
+                let job = state.get_job_mut(job_id).unwrap();
+                let waiting = job.waiting_task_ids();
+                if cancel_waiting {
+                    let (tasks, canceled) = job.cancel_unsubmitted_tasks(waiting);
+                    canceled_tasks += canceled.len() as u64;
+                    (job_id, tasks)
+                } else {
+                    waiting_tasks += waiting.len() as u64;
+                    let submitted = waiting
+                        .into_iter()
+                        .filter(|tako_id| job.is_submitted(*tako_id))
+                        .collect();
+                    (job_id, submitted)
+                }
+            })
+            .filter(|(_, tasks)| !tasks.is_empty())
+            .collect()
+    };
+
+    for (job_id, tasks) in withdrawn_tasks {
+        let n_tasks = tasks.len() as u64;
+        match tako_ref
+            .send_message(FromGatewayMessage::CancelTasks(CancelTasks { tasks }))
+            .await
+            .unwrap()
+        {
+            ToGatewayMessage::CancelTasksResponse(msg) => {
+                // Tasks that were not withdrawn have started in the meantime
+                let n_started = n_tasks - msg.cancelled_tasks.len() as u64;
+                if cancel_waiting {
+                    let mut state = state_ref.get_mut();
+                    let job = state.get_job_mut(job_id).unwrap();
+                    for tako_id in msg.cancelled_tasks {
+                        job.set_cancel_state(tako_id);
+                        canceled_tasks += 1;
+                    }
+                } else {
+                    waiting_tasks -= n_started;
+                }
+            }
+            ToGatewayMessage::Error(msg) => log::warn!(
+                "Canceling waiting tasks of job {} failed: {}",
+                job_id,
+                msg.message
+            ),
+            _ => panic!("Invalid message"),
+        }
+    }
+
+    let unfinished_tasks = loop {
+        let running_tasks: u64 = state_ref
+            .get()
+            .jobs()
+            .map(|job| job.counters.n_running_tasks as u64)
+            .sum();
+        if running_tasks == 0 || timeout.map_or(false, |timeout| start.elapsed() >= timeout) {
+            break running_tasks;
+        }
+        let _ = tx
+            .send(ToClientMessage::DrainProgress(DrainProgress {
+                running_tasks,
+            }))
+            .await;
+        tokio::time::sleep(DRAIN_PROGRESS_INTERVAL).await;
+    };
+    if unfinished_tasks > 0 {
+        log::warn!(
+            "Draining timed out, {} task(s) are still running",
+            unfinished_tasks
+        );
+    }
+
+    let workers: Vec<WorkerId> = state_ref
+        .get()
+        .get_workers()
+        .values()
+        .filter(|w| w.is_online())
+        .map(|w| w.worker_id())
+        .collect();
+    for worker_id in &workers {
+        match handle_worker_stop(tako_ref, *worker_id).await {
+            Ok(ToClientMessage::Error(e)) => log::warn!("Cannot stop worker {}: {}", worker_id, e),
+            Err(e) => log::warn!("Cannot stop worker {}: {}", worker_id, e),
+            Ok(_) => {}
+        }
+    }
+    // Workers are given a moment to disconnect, so that they do not consider the server lost
+    let stop_start = Instant::now();
+    while stop_start.elapsed() < WORKER_STOP_TIMEOUT
+        && workers.iter().any(|worker_id| {
+            state_ref
+                .get()
+                .get_worker(*worker_id)
+                .map_or(false, |w| w.is_online())
+        })
+    {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    DrainResponse {
+        canceled_tasks,
+        unfinished_tasks,
+        waiting_tasks,
+    }
+}
+
 async fn handle_worker_stop(
     tako_ref: &TakoServer,
     worker_id: WorkerId,
@@ -129,31 +582,106 @@ async fn handle_worker_stop(
     }
 }
 
-fn compute_job_detail(state_ref: &StateRef, job_id: JobId, include_tasks: bool) -> ToClientMessage {
+fn compute_job_detail(
+    state_ref: &StateRef,
+    selector: JobSelector,
+    include_tasks: bool,
+) -> ToClientMessage {
     let state = state_ref.get();
     ToClientMessage::JobDetailResponse(
         state
-            .get_job(job_id)
-            .map(|j| j.make_job_detail(include_tasks)),
+            .select_job_ids(&selector)
+            .into_iter()
+            .map(|id| {
+                (
+                    id,
+                    state.get_job(id).map(|j| j.make_job_detail(include_tasks)),
+                )
+            })
+            .collect(),
     )
 }
 
+fn compute_server_info(state_ref: &StateRef, server_info: &ServerInfo) -> ToClientMessage {
+    ToClientMessage::ServerInfoResponse(ServerInfoResponse {
+        info: server_info.clone(),
+        stats: compute_server_stats(&state_ref.get(), server_info),
+    })
+}
+
+/// Computes aggregated statistics of jobs, tasks and workers, shared by `hq server info`
+/// and the metrics endpoint
+pub fn compute_server_stats(state: &State, server_info: &ServerInfo) -> ServerStats {
+    let mut jobs = StateCounts::default();
+    let mut tasks = StateCounts::default();
+    for job in state.jobs() {
+        let info = job.make_job_info();
+        let counter = match job_status(&info) {
+            Status::Waiting => &mut jobs.waiting,
+            Status::Running => &mut jobs.running,
+            Status::Finished => &mut jobs.finished,
+            Status::Failed => &mut jobs.failed,
+            Status::Canceled => &mut jobs.canceled,
+        };
+        *counter += 1;
+
+        let counters = &info.counters;
+        tasks.waiting += counters.n_waiting_tasks(info.n_tasks) as u64;
+        tasks.running += counters.n_running_tasks as u64;
+        tasks.finished += counters.n_finished_tasks as u64;
+        tasks.failed += counters.n_failed_tasks as u64;
+        tasks.canceled += counters.n_canceled_tasks as u64;
+    }
+
+    let worker_cpus: Vec<u64> = state
+        .get_workers()
+        .values()
+        .filter(|w| w.is_online())
+        .map(|w| w.n_cpus())
+        .collect();
+    let used_cpus = state
+        .jobs()
+        .map(|job| {
+            let cpus = match job.resources.cpus() {
+                CpuRequest::Compact(n) | CpuRequest::ForceCompact(n) | CpuRequest::Scatter(n) => {
+                    *n as u64
+                }
+                CpuRequest::All => worker_cpus.iter().copied().max().unwrap_or(0),
+            };
+            cpus * job.counters.n_running_tasks as u64
+        })
+        .sum();
+
+    let online_workers = worker_cpus.len() as u64;
+    ServerStats {
+        uptime: (Utc::now() - server_info.start_date)
+            .to_std()
+            .unwrap_or_default(),
+        jobs,
+        tasks,
+        online_workers,
+        offline_workers: state.get_workers().len() as u64 - online_workers,
+        total_cpus: worker_cpus.iter().sum(),
+        used_cpus,
+        memory_usage: read_memory_usage(),
+    }
+}
+
+/// Returns resident memory of the current process in bytes
+fn read_memory_usage() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_proc_kb_value(&status, "VmRSS:").ok()
+}
+
 fn compute_job_info(state_ref: &StateRef, selector: JobSelector) -> ToClientMessage {
     let state = state_ref.get();
 
-    let jobs: Vec<_> = match selector {
-        JobSelector::All => state.jobs().map(|j| j.make_job_info()).collect(),
-        JobSelector::LastN(n) => state
-            .last_n_ids(n)
-            .filter_map(|id| state.get_job(id))
-            .map(|j| j.make_job_info())
-            .collect(),
-        JobSelector::Specific(ids) => ids
-            .into_iter()
-            .filter_map(|id| state.get_job(id))
-            .map(|j| j.make_job_info())
-            .collect(),
-    };
+    let jobs: Vec<_> = state
+        .select_job_ids(&selector)
+        .into_iter()
+        .filter_map(|id| state.get_job(id))
+        .map(|j| j.make_job_info())
+        .collect();
     ToClientMessage::JobInfoResponse(JobInfoResponse { jobs })
 }
 
@@ -161,89 +689,105 @@ async fn handle_job_cancel(
     state_ref: &StateRef,
     tako_ref: &TakoServer,
     selector: JobSelector,
+    tasks: Option<IdSet>,
 ) -> ToClientMessage {
-    let mut state = state_ref.get_mut();
     let mut responses: Vec<(JobId, CancelJobResponse)> = Vec::new();
+    // Indices of responses that are completed after tasks are canceled in tako
+    let mut submitted: Vec<(usize, Vec<TakoTaskId>)> = Vec::new();
 
-    let job_ids: Vec<JobId> = match selector {
-        JobSelector::All => state
-            .jobs()
-            .map(|job| job.make_job_info())
-            .filter(|job_info| {
-                vec![Status::Running, Status::Waiting]
-                    .iter()
-                    .any(|status| status == &job_status(&job_info))
-            })
-            .map(|job_info| job_info.id)
-            .collect(),
-        JobSelector::LastN(n) => state.last_n_ids(n).collect(),
-        JobSelector::Specific(ids) => ids,
-    };
+    {
+        let mut state = state_ref.get_mut();
+        let job_ids: Vec<JobId> = match selector {
+            JobSelector::All => state
+                .jobs()
+                .map(|job| job.make_job_info())
+                .filter(|job_info| {
+                    vec![Status::Running, Status::Waiting]
+                        .iter()
+                        .any(|status| status == &job_status(&job_info))
+                })
+                .map(|job_info| job_info.id)
+                .collect(),
+            selector => state.select_job_ids(&selector),
+        };
 
-    for job_id in job_ids {
-        let tako_task_ids;
-        {
-            let n_tasks = match state.get_job(job_id) {
+        for job_id in job_ids {
+            let (tako_task_ids, already_finished, already_canceled) = match state.get_job(job_id) {
                 None => {
                     responses.push((job_id, CancelJobResponse::InvalidJob));
                     continue;
                 }
-                Some(job) => {
-                    tako_task_ids = job.non_finished_task_ids();
-                    job.n_tasks()
-                }
+                Some(job) => job.tasks_to_cancel(tasks.as_ref()),
             };
-            if tako_task_ids.is_empty() {
-                responses.push((job_id, CancelJobResponse::Canceled(Vec::new(), n_tasks)));
-                continue;
+            let (tako_task_ids, mut canceled_ids) = state
+                .get_job_mut(job_id)
+                .unwrap()
+                .cancel_unsubmitted_tasks(tako_task_ids);
+            canceled_ids.sort_unstable();
+            if !tako_task_ids.is_empty() {
+                submitted.push((responses.len(), tako_task_ids));
             }
+            responses.push((
+                job_id,
+                CancelJobResponse::Canceled(canceled_ids, already_finished, already_canceled),
+            ));
         }
+    }
 
+    // The state is not borrowed while waiting for tako, because tako updates it meanwhile
+    for (index, tako_task_ids) in submitted {
+        let (job_id, response) = &mut responses[index];
         let canceled_tasks = match tako_ref
             .send_message(FromGatewayMessage::CancelTasks(CancelTasks {
-                tasks: tako_task_ids,
+                tasks: tako_task_ids.clone(),
             }))
             .await
             .unwrap()
         {
             ToGatewayMessage::CancelTasksResponse(msg) => msg.cancelled_tasks,
             ToGatewayMessage::Error(msg) => {
-                responses.push((job_id, CancelJobResponse::Failed(msg.message)));
+                *response = CancelJobResponse::Failed(msg.message);
                 continue;
             }
             _ => panic!("Invalid message"),
         };
+        let (canceled_ids, already_finished) = match response {
+            CancelJobResponse::Canceled(canceled_ids, already_finished, _) => {
+                (canceled_ids, already_finished)
+            }
+            _ => unreachable!(),
+        };
 
-        let job = state.get_job_mut(job_id).unwrap();
-        let canceled_ids: Vec<_> = canceled_tasks
-            .iter()
-            .map(|tako_id| job.set_cancel_state(*tako_id))
-            .collect();
-        let already_finished = job.n_tasks() - canceled_ids.len() as JobTaskCount;
-        responses.push((
-            job_id,
-            CancelJobResponse::Canceled(canceled_ids, already_finished),
-        ));
+        let mut state = state_ref.get_mut();
+        let job = state.get_job_mut(*job_id).unwrap();
+        canceled_ids.extend(
+            canceled_tasks
+                .iter()
+                .map(|tako_id| job.set_cancel_state(*tako_id)),
+        );
+        canceled_ids.sort_unstable();
+
+        // Tasks that were not canceled by tako have finished in the meantime
+        let canceled_tasks: Set<TakoTaskId> = canceled_tasks.into_iter().collect();
+        already_finished.extend(
+            tako_task_ids
+                .into_iter()
+                .filter(|tako_id| !canceled_tasks.contains(tako_id))
+                .map(|tako_id| job.get_task_id(tako_id)),
+        );
+        already_finished.sort_unstable();
     }
 
-    return ToClientMessage::CancelJobResponse(responses);
-}
+    // Canceled tasks free room for tasks of partially canceled jobs that were not submitted yet
+    for (job_id, response) in &responses {
+        if let CancelJobResponse::Canceled(canceled, _, _) = response {
+            if !canceled.is_empty() {
+                submit_job_tasks(state_ref, tako_ref, *job_id);
+            }
+        }
+    }
 
-fn make_program_def_for_task(
-    program_def: &ProgramDefinition,
-    job_id: JobId,
-    task_id: JobTaskId,
-    submit_dir: &Path,
-) -> ProgramDefinition {
-    let mut def = program_def.clone();
-    def.env.insert(HQ_JOB_ID.into(), job_id.to_string().into());
-    def.env
-        .insert(HQ_TASK_ID.into(), task_id.to_string().into());
-    def.env.insert(
-        HQ_SUBMIT_DIR.into(),
-        BString::from(submit_dir.to_string_lossy().as_bytes()),
-    );
-    def
+    ToClientMessage::CancelJobResponse(responses)
 }
 
 async fn handle_submit(
@@ -251,64 +795,51 @@ async fn handle_submit(
     tako_ref: &TakoServer,
     message: SubmitRequest,
 ) -> ToClientMessage {
+    if state_ref.get().is_draining() {
+        return ToClientMessage::Error(
+            "The server is being drained, new jobs are not accepted".to_string(),
+        );
+    }
     if message.resources.validate().is_err() {
         return ToClientMessage::Error("Invalid resource request".to_string());
     }
-    let resources = message.resources;
-    let spec = message.spec;
-    let pin = message.pin;
-    let submit_dir = message.submit_dir;
-
-    let make_task = |job_id, task_id, tako_id, entry: Option<BString>| {
-        let mut program = make_program_def_for_task(&spec, job_id, task_id, &submit_dir);
-        if let Some(e) = entry {
-            program.env.insert(HQ_ENTRY.into(), e);
-        }
-        let launcher_def = LauncherDefinition { program, pin };
-        let body = rmp_serde::to_vec_named(&launcher_def).unwrap();
-        TaskDef {
-            id: tako_id,
-            type_id: 0,
-            body,
-            keep: false,
-            observe: true,
-            n_outputs: 0,
-            priority: 0,
-            resources: resources.clone(),
-        }
+    let task_count = match &message.job_type {
+        JobType::Simple => 1,
+        JobType::Array(a) => a.task_count(),
     };
+    if let Some(entries) = &message.entries {
+        if entries.len() != task_count as usize {
+            return ToClientMessage::Error(format!(
+                "Number of entries ({}) does not match the number of tasks ({})",
+                entries.len(),
+                task_count
+            ));
+        }
+    }
+
     let (task_defs, job_detail) = {
         let mut state = state_ref.get_mut();
         let job_id = state.new_job_id();
-        let task_count = match &message.job_type {
-            JobType::Simple => 1,
-            JobType::Array(a) => a.task_count(),
-        };
         let tako_base_id = state.new_task_id(task_count);
-        let task_defs = match (&message.job_type, message.entries) {
-            (JobType::Simple, _) => vec![make_task(job_id, 0, tako_base_id, None)],
-            (JobType::Array(a), None) => a
-                .iter()
-                .zip(tako_base_id..)
-                .map(|(task_id, tako_id)| make_task(job_id, task_id, tako_id, None))
-                .collect(),
-            (JobType::Array(a), Some(entries)) => a
-                .iter()
-                .zip(tako_base_id..)
-                .zip(entries.into_iter())
-                .map(|((task_id, tako_id), entry)| make_task(job_id, task_id, tako_id, Some(entry)))
-                .collect(),
-        };
-        let job = Job::new(
+        let mut job = Job::new(
             message.job_type,
             job_id,
             tako_base_id,
-            message.name.clone(),
-            spec,
-            resources,
-            pin,
+            message.name,
+            message.spec,
+            message.resources,
+            message.pin,
             message.max_fails,
         );
+        job.submit_dir = message.submit_dir;
+        job.entries = message.entries;
+        job.finish_hooks = state
+            .job_finish_hook()
+            .cloned()
+            .into_iter()
+            .chain(message.on_finish)
+            .collect();
+        let task_defs = job.take_tasks_to_submit();
         let job_detail = job.make_job_detail(false);
         state.add_job(job);
 
@@ -347,3 +878,48 @@ async fn handle_worker_info(state_ref: &StateRef, worker_id: WorkerId) -> ToClie
 
     ToClientMessage::WorkerInfoResponse(state.get_worker(worker_id).map(|w| w.make_info()))
 }
+
+fn handle_worker_stats(state_ref: &StateRef, msg: WorkerStatsMessage) {
+    let mut state = state_ref.get_mut();
+    match state.get_worker_mut(msg.worker_id) {
+        Some(worker) if worker.is_authenticated_by(&msg.token) => worker.update_stats(msg.stats),
+        _ => log::warn!(
+            "Ignoring stats that were not sent by worker {}",
+            msg.worker_id
+        ),
+    }
+}
+
+fn handle_task_finish(state_ref: &StateRef, msg: TaskFinishMessage) -> ToClientMessage {
+    let mut state = state_ref.get_mut();
+    let authenticated = state
+        .get_worker(msg.worker_id)
+        .map(|worker| worker.is_authenticated_by(&msg.token))
+        .unwrap_or(false);
+    if !authenticated {
+        log::warn!(
+            "Ignoring tasks that were not finished by worker {}",
+            msg.worker_id
+        );
+        return ToClientMessage::Error(format!(
+            "Tasks can be reported only by worker {}",
+            msg.worker_id
+        ));
+    }
+    for record in msg.records {
+        let job = match state.get_job_mut(record.job_id) {
+            Some(job) => job,
+            None => {
+                log::debug!("Received a task of an unknown job {}", record.job_id);
+                continue;
+            }
+        };
+        if let Some(usage) = record.usage {
+            job.set_task_usage(record.task_id, usage);
+        }
+        if let Some(error) = record.error {
+            job.set_task_start_error(record.task_id, error);
+        }
+    }
+    ToClientMessage::TaskFinishResponse
+}
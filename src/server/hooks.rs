@@ -0,0 +1,124 @@
+use std::process::Stdio;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+
+use crate::client::job::{job_status, Status};
+use crate::common::env::{
+    HQ_CANCELED_TASKS, HQ_FAILED_TASKS, HQ_FINISHED_TASKS, HQ_JOB_ID, HQ_JOB_NAME, HQ_JOB_STATUS,
+    HQ_TASK_COUNT,
+};
+use crate::server::job::Job;
+
+/// Environment variables that describe a finished job
+fn hook_env(job: &Job) -> Vec<(&'static str, String)> {
+    let info = job.make_job_info();
+    let status = match job_status(&info) {
+        Status::Finished => "FINISHED",
+        Status::Failed => "FAILED",
+        Status::Canceled => "CANCELED",
+        Status::Waiting | Status::Running => unreachable!(),
+    };
+    vec![
+        (HQ_JOB_ID, job.job_id.to_string()),
+        (HQ_JOB_NAME, job.name.clone()),
+        (HQ_JOB_STATUS, status.to_string()),
+        (HQ_TASK_COUNT, info.n_tasks.to_string()),
+        (
+            HQ_FINISHED_TASKS,
+            info.counters.n_finished_tasks.to_string(),
+        ),
+        (HQ_FAILED_TASKS, info.counters.n_failed_tasks.to_string()),
+        (
+            HQ_CANCELED_TASKS,
+            info.counters.n_canceled_tasks.to_string(),
+        ),
+    ]
+}
+
+/// At most this many bytes of each output stream of a hook are written into the server log
+const HOOK_OUTPUT_LIMIT: usize = 4096;
+
+/// Reads the whole stream, but keeps only its first [`HOOK_OUTPUT_LIMIT`] bytes
+async fn read_limited<R: AsyncRead + Unpin>(reader: Option<R>) -> String {
+    let mut reader = match reader {
+        Some(reader) => reader,
+        None => return String::new(),
+    };
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let mut truncated = false;
+    while let Ok(count) = reader.read(&mut buffer).await {
+        if count == 0 {
+            break;
+        }
+        let available = HOOK_OUTPUT_LIMIT - output.len();
+        truncated |= count > available;
+        output.extend_from_slice(&buffer[..count.min(available)]);
+    }
+    let mut output = String::from_utf8_lossy(&output).trim_end().to_string();
+    if truncated {
+        output.push_str(" [truncated]");
+    }
+    output
+}
+
+/// Starts the finish hooks of a job that has just ended. The hooks are executed by `sh -c`
+/// on the server host and the server does not wait for them; failures are only logged.
+/// The (truncated) output of the hooks is written into the server log.
+pub fn run_finish_hooks(job: &Job) {
+    if job.finish_hooks.is_empty() {
+        return;
+    }
+    let env = hook_env(job);
+    for hook in &job.finish_hooks {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(hook)
+            .envs(env.iter().cloned())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let job_id = job.job_id;
+        let hook = hook.clone();
+        tokio::task::spawn_local(async move {
+            let mut child = match command.spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    log::warn!("Cannot start hook `{}` of job {}: {}", hook, job_id, e);
+                    return;
+                }
+            };
+            let (stdout, stderr, status) = tokio::join!(
+                read_limited(child.stdout.take()),
+                read_limited(child.stderr.take()),
+                child.wait()
+            );
+            if !stdout.is_empty() {
+                log::info!("Output of hook `{}` of job {}: {}", hook, job_id, stdout);
+            }
+            match status {
+                Ok(status) if status.success() => {
+                    if !stderr.is_empty() {
+                        log::info!(
+                            "Error output of hook `{}` of job {}: {}",
+                            hook,
+                            job_id,
+                            stderr
+                        );
+                    }
+                    log::debug!("Hook `{}` of job {} finished", hook, job_id)
+                }
+                Ok(status) => log::warn!(
+                    "Hook `{}` of job {} failed with {}: {}",
+                    hook,
+                    job_id,
+                    status,
+                    stderr
+                ),
+                Err(e) => log::warn!("Hook `{}` of job {} failed: {}", hook, job_id, e),
+            }
+        });
+    }
+}
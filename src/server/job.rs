@@ -1,28 +1,161 @@
+use std::path::PathBuf;
+
+use bstr::BString;
 use serde::{Deserialize, Serialize};
-use tako::messages::common::ProgramDefinition;
+use tako::messages::common::{LauncherDefinition, ProgramDefinition};
+use tako::messages::gateway::TaskDef;
 
-use crate::transfer::messages::{JobDetail, JobInfo, JobType};
+use crate::common::bitset::BitSet;
+use crate::common::env::{HQ_ENTRY, HQ_JOB_ID, HQ_JOB_NAME, HQ_SUBMIT_DIR, HQ_TASK_ID};
+use crate::common::idset::IdSet;
+use crate::server::hooks::run_finish_hooks;
+use crate::transfer::messages::{JobDetail, JobInfo, JobType, JobUsageSummary, TaskResourceUsage};
 use crate::{JobId, JobTaskCount, JobTaskId, Map, TakoTaskId};
 use tako::common::resources::ResourceRequest;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum JobTaskState {
     Waiting,
     Running,
     Finished,
-    Failed(String),
+    Failed,
     Canceled,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Information about a single task, expanded from [`JobTaskStates`]
+#[derive(Debug, Clone)]
 pub struct JobTaskInfo {
     pub state: JobTaskState,
     pub task_id: JobTaskId,
+    pub error: Option<String>,
+    pub usage: Option<TaskResourceUsage>,
+}
+
+/// Ids of tasks of a job grouped by their states, as compressed ranges
+/// (e.g. finished `0-9999` and failed `10000,10042`)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobTaskStates {
+    pub waiting: IdSet,
+    pub running: IdSet,
+    pub finished: IdSet,
+    pub failed: IdSet,
+    pub canceled: IdSet,
+    /// Error messages of failed tasks, sorted by task ids
+    pub errors: Vec<(JobTaskId, String)>,
+    /// Resource usage reported by tasks, sorted by task ids
+    pub usage: Vec<(JobTaskId, TaskResourceUsage)>,
+}
+
+impl JobTaskStates {
+    fn ranges(&self) -> [(&IdSet, JobTaskState); 5] {
+        [
+            (&self.waiting, JobTaskState::Waiting),
+            (&self.running, JobTaskState::Running),
+            (&self.finished, JobTaskState::Finished),
+            (&self.failed, JobTaskState::Failed),
+            (&self.canceled, JobTaskState::Canceled),
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges().iter().all(|(ids, _)| ids.ranges().is_empty())
+    }
+
+    /// Expands the ranges into information about individual tasks, sorted by task ids
+    pub fn tasks(&self) -> Vec<JobTaskInfo> {
+        let errors: Map<JobTaskId, &String> = self.errors.iter().map(|(id, e)| (*id, e)).collect();
+        let usage: Map<JobTaskId, &TaskResourceUsage> =
+            self.usage.iter().map(|(id, u)| (*id, u)).collect();
+        let mut tasks: Vec<JobTaskInfo> = self
+            .ranges()
+            .iter()
+            .copied()
+            .flat_map(|(ids, state)| ids.iter().map(move |id| (id as JobTaskId, state)))
+            .map(|(task_id, state)| JobTaskInfo {
+                state,
+                task_id,
+                error: errors.get(&task_id).map(|e| (*e).clone()),
+                usage: usage.get(&task_id).map(|u| (*u).clone()),
+            })
+            .collect();
+        tasks.sort_unstable_by_key(|t| t.task_id);
+        tasks
+    }
+}
+
+/// Jobs with more tasks keep only the aggregated resource usage of their tasks
+pub const MAX_TASK_USAGE_RECORDS: JobTaskCount = 10_000;
+
+/// States of tasks of a job, indexed by the position of a task in the job
+/// (i.e. the offset of its tako id from the base tako id of the job).
+/// Tasks that are not contained in any of the sets are waiting.
+pub struct TaskStates {
+    running: BitSet,
+    finished: BitSet,
+    failed: BitSet,
+    canceled: BitSet,
+    /// Error messages of failed tasks
+    errors: Map<JobTaskCount, String>,
+    /// Errors reported by workers for tasks whose programs could not be started,
+    /// used instead of the generic error when the task fails
+    start_errors: Map<JobTaskCount, String>,
+    /// Tasks that have reported their resource usage
+    usage_reported: BitSet,
+    /// Resource usage of individual tasks, kept only for jobs with at most
+    /// `MAX_TASK_USAGE_RECORDS` tasks
+    usage: Map<JobTaskCount, TaskResourceUsage>,
+    usage_summary: JobUsageSummary,
 }
 
-pub enum JobState {
-    SingleTask(JobTaskState),
-    ManyTasks(Map<TakoTaskId, JobTaskInfo>),
+impl TaskStates {
+    fn new(n_tasks: JobTaskCount) -> Self {
+        let n_tasks = n_tasks as usize;
+        TaskStates {
+            running: BitSet::new(n_tasks),
+            finished: BitSet::new(n_tasks),
+            failed: BitSet::new(n_tasks),
+            canceled: BitSet::new(n_tasks),
+            errors: Default::default(),
+            start_errors: Default::default(),
+            usage_reported: BitSet::new(n_tasks),
+            usage: Default::default(),
+            usage_summary: Default::default(),
+        }
+    }
+
+    fn set_of(&mut self, state: JobTaskState) -> Option<&mut BitSet> {
+        match state {
+            JobTaskState::Waiting => None,
+            JobTaskState::Running => Some(&mut self.running),
+            JobTaskState::Finished => Some(&mut self.finished),
+            JobTaskState::Failed => Some(&mut self.failed),
+            JobTaskState::Canceled => Some(&mut self.canceled),
+        }
+    }
+
+    fn get(&self, index: JobTaskCount) -> JobTaskState {
+        let index = index as usize;
+        if self.running.contains(index) {
+            JobTaskState::Running
+        } else if self.finished.contains(index) {
+            JobTaskState::Finished
+        } else if self.failed.contains(index) {
+            JobTaskState::Failed
+        } else if self.canceled.contains(index) {
+            JobTaskState::Canceled
+        } else {
+            JobTaskState::Waiting
+        }
+    }
+
+    fn set(&mut self, index: JobTaskCount, state: JobTaskState) {
+        if let Some(set) = self.set_of(self.get(index)) {
+            set.remove(index as usize);
+        }
+        if let Some(set) = self.set_of(state) {
+            set.insert(index as usize);
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, Default)]
@@ -43,13 +176,16 @@ impl JobTaskCounters {
     }
 }
 
+/// Maximal number of tasks of a single job that are submitted to tako at once
+const MAX_SUBMITTED_TASKS: JobTaskCount = 100_000;
+
 pub struct Job {
     pub job_id: JobId,
     pub base_task_id: TakoTaskId,
     pub max_fails: Option<JobTaskCount>,
     pub counters: JobTaskCounters,
 
-    pub state: JobState,
+    state: TaskStates,
 
     pub job_type: JobType,
     pub name: String,
@@ -57,6 +193,20 @@ pub struct Job {
     pub program_def: ProgramDefinition,
     pub resources: ResourceRequest,
     pub pin: bool,
+
+    /// Commands that are executed on the server when the job ends
+    pub finish_hooks: Vec<String>,
+
+    pub submit_dir: PathBuf,
+    /// Entries of tasks (`--each-line`), indexed by the position of a task in the job.
+    /// They are stored only here and copied into a task when it is submitted to tako.
+    pub entries: Option<Vec<BString>>,
+
+    /// Tasks at positions lower than this were already submitted to tako
+    /// (or canceled before their submission)
+    n_submitted_tasks: JobTaskCount,
+    /// Tasks that were submitted to tako and have not ended yet
+    n_active_submitted_tasks: JobTaskCount,
 }
 
 impl Job {
@@ -73,24 +223,10 @@ impl Job {
         pin: bool,
         max_fails: Option<JobTaskCount>,
     ) -> Self {
-        let state = match &job_type {
-            JobType::Simple => JobState::SingleTask(JobTaskState::Waiting),
-            JobType::Array(m) if m.task_count() == 1 => JobState::SingleTask(JobTaskState::Waiting),
-            JobType::Array(m) => JobState::ManyTasks(
-                m.iter()
-                    .enumerate()
-                    .map(|(i, task_id)| {
-                        (
-                            base_task_id + i as TakoTaskId,
-                            JobTaskInfo {
-                                state: JobTaskState::Waiting,
-                                task_id,
-                            },
-                        )
-                    })
-                    .collect(),
-            ),
-        };
+        let state = TaskStates::new(match &job_type {
+            JobType::Simple => 1,
+            JobType::Array(a) => a.task_count(),
+        });
 
         Job {
             job_type,
@@ -103,6 +239,11 @@ impl Job {
             resources,
             pin,
             max_fails,
+            finish_hooks: Vec::new(),
+            submit_dir: PathBuf::new(),
+            entries: None,
+            n_submitted_tasks: 0,
+            n_active_submitted_tasks: 0,
         }
     }
 
@@ -113,19 +254,15 @@ impl Job {
             program_def: self.program_def.clone(),
             resources: self.resources.clone(),
             tasks: if include_tasks {
-                match &self.state {
-                    JobState::SingleTask(s) => {
-                        vec![JobTaskInfo {
-                            task_id: 0,
-                            state: s.clone(),
-                        }]
-                    }
-                    JobState::ManyTasks(m) => m.values().cloned().collect(),
-                }
+                self.make_task_states()
             } else {
-                Vec::new()
+                JobTaskStates {
+                    errors: self.make_task_errors(),
+                    ..Default::default()
+                }
             },
             pin: self.pin,
+            usage_summary: self.state.usage_summary.clone(),
         }
     }
 
@@ -147,111 +284,349 @@ impl Job {
         }
     }
 
+    /// Converts ascending positions of tasks into ranges of their ids
+    fn task_id_ranges(&self, indices: impl Iterator<Item = JobTaskCount>) -> IdSet {
+        IdSet::from_sorted_ids(indices.map(|index| self.task_id_at(index) as u64))
+    }
+
+    fn make_task_states(&self) -> JobTaskStates {
+        let from_set =
+            |set: &BitSet| self.task_id_ranges(set.iter().map(|index| index as JobTaskCount));
+
+        let mut usage: Vec<_> = self
+            .state
+            .usage
+            .iter()
+            .map(|(index, usage)| (self.task_id_at(*index), usage.clone()))
+            .collect();
+        usage.sort_unstable_by_key(|(task_id, _)| *task_id);
+
+        JobTaskStates {
+            waiting: self.task_id_ranges(
+                (0..self.n_tasks()).filter(|index| self.state.get(*index) == JobTaskState::Waiting),
+            ),
+            running: from_set(&self.state.running),
+            finished: from_set(&self.state.finished),
+            failed: from_set(&self.state.failed),
+            canceled: from_set(&self.state.canceled),
+            errors: self.make_task_errors(),
+            usage,
+        }
+    }
+
+    /// Returns errors of failed tasks sorted by task ids
+    fn make_task_errors(&self) -> Vec<(JobTaskId, String)> {
+        let mut errors: Vec<_> = self
+            .state
+            .errors
+            .iter()
+            .map(|(index, error)| (self.task_id_at(*index), error.clone()))
+            .collect();
+        errors.sort_unstable_by_key(|(task_id, _)| *task_id);
+        errors
+    }
+
     #[inline]
     pub fn n_tasks(&self) -> JobTaskCount {
-        match &self.state {
-            JobState::SingleTask(_) => 1,
-            JobState::ManyTasks(s) => s.len() as JobTaskCount,
+        match &self.job_type {
+            JobType::Simple => 1,
+            JobType::Array(a) => a.task_count(),
         }
     }
 
-    pub fn get_task_state_mut(
-        &mut self,
-        tako_task_id: TakoTaskId,
-    ) -> (JobTaskId, &mut JobTaskState) {
-        match &mut self.state {
-            JobState::SingleTask(ref mut s) => {
-                debug_assert_eq!(tako_task_id, self.base_task_id);
-                (0, s)
+    /// Returns the position of a task in the job
+    #[inline]
+    fn task_index(&self, tako_task_id: TakoTaskId) -> JobTaskCount {
+        debug_assert!(
+            tako_task_id >= self.base_task_id
+                && tako_task_id < self.base_task_id + self.n_tasks() as TakoTaskId
+        );
+        (tako_task_id - self.base_task_id) as JobTaskCount
+    }
+
+    fn task_id_at(&self, index: JobTaskCount) -> JobTaskId {
+        match &self.job_type {
+            JobType::Simple => 0,
+            JobType::Array(a) => a.task_id(index),
+        }
+    }
+
+    pub fn get_task_id(&self, tako_task_id: TakoTaskId) -> JobTaskId {
+        self.task_id_at(self.task_index(tako_task_id))
+    }
+
+    pub fn get_task_state(&self, tako_task_id: TakoTaskId) -> JobTaskState {
+        self.state.get(self.task_index(tako_task_id))
+    }
+
+    /// Returns the tako id of a task with the given id inside the job
+    pub fn get_tako_task_id(&self, task_id: JobTaskId) -> Option<TakoTaskId> {
+        match &self.job_type {
+            JobType::Simple => (task_id == 0).then(|| self.base_task_id),
+            JobType::Array(a) => a
+                .task_index(task_id)
+                .map(|index| self.base_task_id + index as TakoTaskId),
+        }
+    }
+
+    pub fn is_submitted(&self, tako_task_id: TakoTaskId) -> bool {
+        tako_task_id < self.base_task_id + self.n_submitted_tasks as TakoTaskId
+    }
+
+    fn make_task_def(&self, tako_task_id: TakoTaskId, task_id: JobTaskId) -> TaskDef {
+        let mut program = self.program_def.clone();
+        program
+            .env
+            .insert(HQ_JOB_NAME.into(), self.name.as_str().into());
+        program
+            .env
+            .insert(HQ_JOB_ID.into(), self.job_id.to_string().into());
+        program
+            .env
+            .insert(HQ_TASK_ID.into(), task_id.to_string().into());
+        program.env.insert(
+            HQ_SUBMIT_DIR.into(),
+            BString::from(self.submit_dir.to_string_lossy().as_bytes()),
+        );
+        if let Some(entries) = &self.entries {
+            let index = self.task_index(tako_task_id) as usize;
+            program.env.insert(HQ_ENTRY.into(), entries[index].clone());
+        }
+        let launcher_def = LauncherDefinition {
+            program,
+            pin: self.pin,
+        };
+        TaskDef {
+            id: tako_task_id,
+            type_id: 0,
+            body: rmp_serde::to_vec_named(&launcher_def).unwrap(),
+            keep: false,
+            observe: true,
+            n_outputs: 0,
+            priority: 0,
+            resources: self.resources.clone(),
+        }
+    }
+
+    /// Creates definitions of the next tasks that should be submitted to tako.
+    /// At most [`MAX_SUBMITTED_TASKS`] tasks of a job are in tako at once, the rest is created
+    /// in batches once at least half of them has ended.
+    pub fn take_tasks_to_submit(&mut self) -> Vec<TaskDef> {
+        let n_tasks = self.n_tasks();
+        if self.n_submitted_tasks == n_tasks
+            || self.n_active_submitted_tasks > MAX_SUBMITTED_TASKS / 2
+        {
+            return Vec::new();
+        }
+        let mut task_defs = Vec::new();
+        while self.n_submitted_tasks < n_tasks
+            && self.n_active_submitted_tasks < MAX_SUBMITTED_TASKS
+        {
+            let tako_task_id = self.base_task_id + self.n_submitted_tasks as TakoTaskId;
+            self.n_submitted_tasks += 1;
+            // Tasks canceled before their submission are skipped
+            if self.get_task_state(tako_task_id) == JobTaskState::Waiting {
+                let task_id = self.get_task_id(tako_task_id);
+                task_defs.push(self.make_task_def(tako_task_id, task_id));
+                self.n_active_submitted_tasks += 1;
             }
-            JobState::ManyTasks(m) => {
-                let state = m.get_mut(&tako_task_id).unwrap();
-                (state.task_id, &mut state.state)
+        }
+        task_defs
+    }
+
+    /// Cancels the given tasks that were not submitted to tako yet.
+    /// Returns the remaining tasks, which have to be canceled in tako,
+    /// and ids of the tasks that were canceled.
+    pub fn cancel_unsubmitted_tasks(
+        &mut self,
+        tako_task_ids: Vec<TakoTaskId>,
+    ) -> (Vec<TakoTaskId>, Vec<JobTaskId>) {
+        let (submitted, unsubmitted): (Vec<_>, Vec<_>) = tako_task_ids
+            .into_iter()
+            .partition(|tako_id| self.is_submitted(*tako_id));
+        let canceled = unsubmitted
+            .into_iter()
+            .map(|tako_id| self.set_cancel_state(tako_id))
+            .collect();
+        (submitted, canceled)
+    }
+
+    pub fn set_task_usage(&mut self, task_id: JobTaskId, usage: TaskResourceUsage) {
+        match self.get_tako_task_id(task_id) {
+            Some(tako_task_id) => {
+                let index = self.task_index(tako_task_id);
+                if self.state.usage_reported.contains(index as usize) {
+                    log::debug!(
+                        "Ignoring repeated usage of task {} of job {}",
+                        task_id,
+                        self.job_id
+                    );
+                    return;
+                }
+                self.state.usage_reported.insert(index as usize);
+                self.state.usage_summary.add(&usage);
+                if self.n_tasks() <= MAX_TASK_USAGE_RECORDS {
+                    self.state.usage.insert(index, usage);
+                }
             }
+            None => log::debug!(
+                "Received usage of an invalid task {} of job {}",
+                task_id,
+                self.job_id
+            ),
         }
     }
 
-    pub fn iter_task_states<'a>(
-        &'a self,
-    ) -> Box<dyn Iterator<Item = (TakoTaskId, JobTaskId, &'a JobTaskState)> + 'a> {
-        match self.state {
-            JobState::SingleTask(ref s) => Box::new(Some((self.base_task_id, 0, s)).into_iter()),
-            JobState::ManyTasks(ref m) => {
-                Box::new(m.iter().map(|(k, v)| (*k, v.task_id, &v.state)))
+    /// Records why the program of a task could not be started.
+    /// The error is usually received before the task fails, otherwise it replaces the generic error.
+    pub fn set_task_start_error(&mut self, task_id: JobTaskId, error: String) {
+        match self.get_tako_task_id(task_id) {
+            Some(tako_task_id) => {
+                let index = self.task_index(tako_task_id);
+                match self.state.get(index) {
+                    JobTaskState::Failed => {
+                        self.state.errors.insert(index, error);
+                    }
+                    JobTaskState::Waiting | JobTaskState::Running => {
+                        self.state.start_errors.insert(index, error);
+                    }
+                    JobTaskState::Finished | JobTaskState::Canceled => {}
+                }
             }
+            None => log::debug!(
+                "Received an error of an invalid task {} of job {}",
+                task_id,
+                self.job_id
+            ),
         }
     }
 
+    pub fn iter_task_states(
+        &self,
+    ) -> impl Iterator<Item = (TakoTaskId, JobTaskId, JobTaskState)> + '_ {
+        (0..self.n_tasks()).map(move |index| {
+            (
+                self.base_task_id + index as TakoTaskId,
+                self.task_id_at(index),
+                self.state.get(index),
+            )
+        })
+    }
+
     pub fn non_finished_task_ids(&self) -> Vec<TakoTaskId> {
         let mut result = Vec::new();
         for (tako_id, _task_id, state) in self.iter_task_states() {
             match state {
                 JobTaskState::Waiting | JobTaskState::Running => result.push(tako_id),
-                JobTaskState::Finished | JobTaskState::Failed(_) | JobTaskState::Canceled => { /* Do nothing */
+                JobTaskState::Finished | JobTaskState::Failed | JobTaskState::Canceled => { /* Do nothing */
                 }
             }
         }
         result
     }
 
-    /*pub fn iter_task_states_mut(&mut self) -> Box<dyn Iterator<Item=(TakoTaskId, &mut JobTaskState)>>
-    {
-        match &mut self.state {
-            JobState::SingleTask(ref mut s) => {
-                Box::new(Some((0, s)))
+    pub fn waiting_task_ids(&self) -> Vec<TakoTaskId> {
+        self.iter_task_states()
+            .filter(|(_, _, state)| *state == JobTaskState::Waiting)
+            .map(|(tako_id, _, _)| tako_id)
+            .collect()
+    }
+
+    /// Returns tako ids of tasks that can still be canceled, (sorted) ids of tasks that have
+    /// already finished or failed and (sorted) ids of tasks that have already been canceled.
+    /// If `selection` is given, only the selected tasks are considered.
+    pub fn tasks_to_cancel(
+        &self,
+        selection: Option<&IdSet>,
+    ) -> (Vec<TakoTaskId>, Vec<JobTaskId>, Vec<JobTaskId>) {
+        let mut to_cancel = Vec::new();
+        let mut finished = Vec::new();
+        let mut canceled = Vec::new();
+        for (tako_id, task_id, state) in self.iter_task_states() {
+            if let Some(selection) = selection {
+                if !selection.contains(task_id as u64) {
+                    continue;
+                }
             }
-            JobState::ManyTasks(ref mut m) => {
-                Box::new(m.iter_mut().map(|(k, v)| (k, &mut v.state)))
+            match state {
+                JobTaskState::Waiting | JobTaskState::Running => to_cancel.push(tako_id),
+                JobTaskState::Finished | JobTaskState::Failed => finished.push(task_id),
+                JobTaskState::Canceled => canceled.push(task_id),
             }
         }
-    }*/
+        finished.sort_unstable();
+        canceled.sort_unstable();
+        (to_cancel, finished, canceled)
+    }
+
+    /// Runs the finish hooks if the last unfinished task of the job has just ended
+    fn check_job_end(&self) {
+        if self.counters.n_running_tasks == 0 && self.counters.n_waiting_tasks(self.n_tasks()) == 0
+        {
+            run_finish_hooks(self);
+        }
+    }
 
     pub fn set_running_state(&mut self, tako_task_id: TakoTaskId) {
-        let (_, state) = self.get_task_state_mut(tako_task_id);
-        if matches!(state, JobTaskState::Waiting) {
-            *state = JobTaskState::Running;
+        let index = self.task_index(tako_task_id);
+        if self.state.get(index) == JobTaskState::Waiting {
+            self.state.set(index, JobTaskState::Running);
             self.counters.n_running_tasks += 1;
         }
     }
 
     pub fn set_finished_state(&mut self, tako_task_id: TakoTaskId) {
-        let (_, state) = self.get_task_state_mut(tako_task_id);
-        assert!(matches!(state, JobTaskState::Running));
-        *state = JobTaskState::Finished;
+        let index = self.task_index(tako_task_id);
+        assert_eq!(self.state.get(index), JobTaskState::Running);
+        self.state.set(index, JobTaskState::Finished);
         self.counters.n_running_tasks -= 1;
         self.counters.n_finished_tasks += 1;
+        self.n_active_submitted_tasks -= 1;
+        self.check_job_end();
     }
 
     pub fn set_waiting_state(&mut self, tako_task_id: TakoTaskId) {
-        let (_, state) = self.get_task_state_mut(tako_task_id);
-        assert!(matches!(state, JobTaskState::Running));
-        *state = JobTaskState::Waiting;
+        let index = self.task_index(tako_task_id);
+        assert_eq!(self.state.get(index), JobTaskState::Running);
+        self.state.set(index, JobTaskState::Waiting);
         self.counters.n_running_tasks -= 1;
     }
 
-    pub fn set_failed_state(&mut self, tako_task_id: TakoTaskId, error: String) {
-        let (_, state) = self.get_task_state_mut(tako_task_id);
-        assert!(matches!(state, JobTaskState::Running));
-        *state = JobTaskState::Failed(error);
+    /// Returns the id of the task and its error, which is the error of its start if it was reported
+    pub fn set_failed_state(
+        &mut self,
+        tako_task_id: TakoTaskId,
+        error: String,
+    ) -> (JobTaskId, String) {
+        let index = self.task_index(tako_task_id);
+        assert_eq!(self.state.get(index), JobTaskState::Running);
+        self.state.set(index, JobTaskState::Failed);
+        let error = self.state.start_errors.remove(&index).unwrap_or(error);
+        self.state.errors.insert(index, error.clone());
         self.counters.n_running_tasks -= 1;
         self.counters.n_failed_tasks += 1;
+        self.n_active_submitted_tasks -= 1;
+        self.check_job_end();
+        (self.task_id_at(index), error)
     }
 
     pub fn set_cancel_state(&mut self, tako_task_id: TakoTaskId) -> JobTaskId {
-        let (task_id, state) = self.get_task_state_mut(tako_task_id);
-        let old_state = std::mem::replace(state, JobTaskState::Canceled);
+        let index = self.task_index(tako_task_id);
+        let old_state = self.state.get(index);
         assert!(matches!(
             old_state,
             JobTaskState::Running | JobTaskState::Waiting
         ));
+        self.state.set(index, JobTaskState::Canceled);
+        self.state.start_errors.remove(&index);
         if let JobTaskState::Running = old_state {
             self.counters.n_running_tasks -= 1;
         }
         self.counters.n_canceled_tasks += 1;
-        task_id
-        //assert!(matches!(
-        //    old_state,
-        //    JobTaskState::Running | JobTaskState::Waiting
-        //));
+        if self.is_submitted(tako_task_id) {
+            self.n_active_submitted_tasks -= 1;
+        }
+        self.check_job_end();
+        self.task_id_at(index)
     }
 }
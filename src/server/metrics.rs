@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::server::client::{compute_server_stats, ClientContext};
+use crate::transfer::messages::StateCounts;
+
+/// Upper bounds (in seconds) of buckets of the latency histogram
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Maximum size of a HTTP request head accepted by the metrics endpoint
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Maximum time of receiving a HTTP request head, so that idle connections are not kept open
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Counters of events that happened since the start of the server
+#[derive(Default)]
+pub struct ServerMetrics {
+    pub submitted_tasks: u64,
+    pub finished_tasks: u64,
+    pub failed_tasks: u64,
+    /// Number of received client messages by their type
+    client_requests: BTreeMap<&'static str, u64>,
+}
+
+impl ServerMetrics {
+    pub fn record_request(&mut self, name: &'static str) {
+        *self.client_requests.entry(name).or_insert(0) += 1;
+    }
+}
+
+/// Cumulative histogram of durations in the format of Prometheus
+#[derive(Default, Clone)]
+pub struct LatencyHistogram {
+    /// Number of observations that fell into each bucket of [`LATENCY_BUCKETS`]
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Escapes a value of a label according to the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn write_state_counts(out: &mut String, name: &str, help: &str, counts: &StateCounts) {
+    write_header(out, name, "gauge", help);
+    for (state, count) in &[
+        ("waiting", counts.waiting),
+        ("running", counts.running),
+        ("finished", counts.finished),
+        ("failed", counts.failed),
+        ("canceled", counts.canceled),
+    ] {
+        writeln!(out, "{}{{state=\"{}\"}} {}", name, state, count).unwrap();
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &LatencyHistogram) {
+    write_header(out, name, "histogram", help);
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+        cumulative += count;
+        writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+    }
+    writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count).unwrap();
+    writeln!(out, "{}_sum {}", name, histogram.sum).unwrap();
+    writeln!(out, "{}_count {}", name, histogram.count).unwrap();
+}
+
+/// Renders the current metrics of the server in the Prometheus text format
+pub fn render_metrics(context: &ClientContext) -> String {
+    let state = context.state_ref.get();
+    let stats = compute_server_stats(&state, &context.server_info);
+    let metrics = state.metrics();
+    let mut out = String::new();
+
+    write_header(
+        &mut out,
+        "hq_uptime_seconds",
+        "gauge",
+        "Time since the start of the server",
+    );
+    writeln!(out, "hq_uptime_seconds {}", stats.uptime.as_secs_f64()).unwrap();
+
+    write_state_counts(&mut out, "hq_jobs", "Number of jobs by state", &stats.jobs);
+    write_state_counts(
+        &mut out,
+        "hq_tasks",
+        "Number of tasks by state",
+        &stats.tasks,
+    );
+
+    for (name, help, value) in &[
+        (
+            "hq_submitted_tasks_total",
+            "Number of submitted tasks",
+            metrics.submitted_tasks,
+        ),
+        (
+            "hq_finished_tasks_total",
+            "Number of successfully finished tasks",
+            metrics.finished_tasks,
+        ),
+        (
+            "hq_failed_tasks_total",
+            "Number of failed tasks",
+            metrics.failed_tasks,
+        ),
+    ] {
+        write_header(&mut out, name, "counter", help);
+        writeln!(out, "{} {}", name, value).unwrap();
+    }
+
+    write_header(
+        &mut out,
+        "hq_workers",
+        "gauge",
+        "Number of workers by state",
+    );
+    writeln!(
+        out,
+        "hq_workers{{state=\"online\"}} {}",
+        stats.online_workers
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "hq_workers{{state=\"offline\"}} {}",
+        stats.offline_workers
+    )
+    .unwrap();
+
+    let mut workers: Vec<_> = state
+        .get_workers()
+        .values()
+        .filter(|w| w.is_online())
+        .collect();
+    workers.sort_by_key(|w| w.worker_id());
+    write_header(
+        &mut out,
+        "hq_worker_cpus",
+        "gauge",
+        "Number of CPUs of an online worker",
+    );
+    for worker in &workers {
+        writeln!(
+            out,
+            "hq_worker_cpus{{worker=\"{}\",hostname=\"{}\"}} {}",
+            worker.worker_id(),
+            escape_label(&worker.configuration().hostname),
+            worker.n_cpus()
+        )
+        .unwrap();
+    }
+    write_header(
+        &mut out,
+        "hq_worker_cpu_usage_percent",
+        "gauge",
+        "Average utilization of CPUs of an online worker, as last reported by the worker",
+    );
+    for worker in &workers {
+        if let Some(worker_stats) = worker.stats() {
+            writeln!(
+                out,
+                "hq_worker_cpu_usage_percent{{worker=\"{}\",hostname=\"{}\"}} {}",
+                worker.worker_id(),
+                escape_label(&worker.configuration().hostname),
+                worker_stats.average_cpu_usage()
+            )
+            .unwrap();
+        }
+    }
+    write_header(
+        &mut out,
+        "hq_worker_allocated_cpus",
+        "gauge",
+        "CPUs allocated to running tasks of an online worker, as last reported by the worker",
+    );
+    for worker in &workers {
+        if let Some(worker_stats) = worker.stats() {
+            writeln!(
+                out,
+                "hq_worker_allocated_cpus{{worker=\"{}\",hostname=\"{}\"}} {}",
+                worker.worker_id(),
+                escape_label(&worker.configuration().hostname),
+                worker_stats.allocated_cpus
+            )
+            .unwrap();
+        }
+    }
+
+    write_header(
+        &mut out,
+        "hq_cpus",
+        "gauge",
+        "Number of CPUs of all online workers",
+    );
+    writeln!(out, "hq_cpus {}", stats.total_cpus).unwrap();
+    write_header(
+        &mut out,
+        "hq_allocated_cpus",
+        "gauge",
+        "Number of CPUs allocated to running tasks",
+    );
+    writeln!(out, "hq_allocated_cpus {}", stats.used_cpus).unwrap();
+
+    write_header(
+        &mut out,
+        "hq_client_requests_total",
+        "counter",
+        "Number of client requests by type",
+    );
+    for (name, count) in &metrics.client_requests {
+        writeln!(
+            out,
+            "hq_client_requests_total{{request=\"{}\"}} {}",
+            name, count
+        )
+        .unwrap();
+    }
+
+    write_histogram(
+        &mut out,
+        "hq_tako_request_duration_seconds",
+        "Duration of requests sent to the task scheduler",
+        &context.tako_ref.latency(),
+    );
+    out
+}
+
+/// Returns the path of a `GET` request from its request line
+fn parse_request_path(head: &str) -> Option<&str> {
+    let mut parts = head.lines().next()?.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("GET"), Some(path), Some(version)) if version.starts_with("HTTP/") => Some(path),
+        _ => None,
+    }
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buffer.windows(4).any(|w| w == b"\r\n\r\n") && buffer.len() < MAX_REQUEST_SIZE {
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    }
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+async fn handle_metrics_request(
+    mut stream: TcpStream,
+    context: &ClientContext,
+) -> std::io::Result<()> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Timeout while reading the request",
+            )
+        })??;
+    let (status, content_type, body) = match parse_request_path(&head) {
+        Some("/metrics") => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render_metrics(context),
+        ),
+        Some(_) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        None => ("400 Bad Request", "text/plain", "Bad request\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serves the `/metrics` endpoint over plain HTTP
+pub async fn serve_metrics(listener: TcpListener, context: ClientContext) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let context = context.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = handle_metrics_request(stream, &context).await {
+                log::debug!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_request_path, write_histogram, LatencyHistogram};
+
+    #[test]
+    fn test_parse_request_path() {
+        assert_eq!(
+            parse_request_path("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            Some("/metrics")
+        );
+        assert_eq!(parse_request_path("GET / HTTP/1.0\r\n\r\n"), Some("/"));
+        assert_eq!(parse_request_path("POST /metrics HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse_request_path("GET /metrics\r\n\r\n"), None);
+        assert_eq!(parse_request_path(""), None);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut out = String::new();
+        write_histogram(&mut out, "latency", "Latency", &histogram);
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.contains(&"latency_bucket{le=\"0.0001\"} 1"));
+        assert!(lines.contains(&"latency_bucket{le=\"0.001\"} 1"));
+        assert!(lines.contains(&"latency_bucket{le=\"0.005\"} 2"));
+        assert!(lines.contains(&"latency_bucket{le=\"5\"} 2"));
+        assert!(lines.contains(&"latency_bucket{le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"latency_count 3"));
+    }
+}
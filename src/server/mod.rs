@@ -1,7 +1,12 @@
+pub mod access;
+pub mod audit;
 pub mod bootstrap;
 pub mod client;
+pub mod hooks;
 pub mod job;
+pub mod metrics;
 pub mod reactor;
 pub mod rpc;
 pub mod state;
 pub mod worker;
+pub mod workergate;
@@ -1,5 +1,6 @@
 use std::collections::VecDeque;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use orion::kdf::SecretKey;
@@ -7,16 +8,19 @@ use tako::messages::gateway::{ErrorResponse, FromGatewayMessage, ToGatewayMessag
 use tako::server::client::process_client_message;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::sync::oneshot;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 
 use crate::common::error::error;
 use crate::common::WrappedRcRefCell;
-use crate::server::state::StateRef;
+use crate::server::metrics::LatencyHistogram;
+use crate::server::state::{submit_job_tasks, StateRef};
 
 struct Inner {
     sender: UnboundedSender<FromGatewayMessage>,
     responses: VecDeque<oneshot::Sender<ToGatewayMessage>>,
     worker_port: u16,
+    /// Durations of round-trips of `send_message`
+    latency: LatencyHistogram,
 }
 
 #[derive(Clone)]
@@ -29,30 +33,45 @@ impl TakoServer {
         self.inner.get().worker_port
     }
 
-    pub async fn send_message(
+    pub fn latency(&self) -> LatencyHistogram {
+        self.inner.get().latency.clone()
+    }
+
+    /// Passes the message to tako and returns a future that resolves to the response.
+    /// The message is passed already when this function is called (not when the returned future
+    /// is polled), so messages are processed by tako in the order of the calls.
+    pub fn send_message(
         &self,
         message: FromGatewayMessage,
-    ) -> crate::Result<ToGatewayMessage> {
+    ) -> impl Future<Output = crate::Result<ToGatewayMessage>> {
+        let start = Instant::now();
         let (sx, rx) = oneshot::channel::<ToGatewayMessage>();
         {
             let mut inner = self.inner.get_mut();
             inner.responses.push_back(sx);
             inner.sender.send(message).unwrap();
         }
-        Ok(rx.await.unwrap())
+        let inner = self.inner.clone();
+        async move {
+            let response = rx.await.unwrap();
+            inner.get_mut().latency.observe(start.elapsed());
+            Ok(response)
+        }
     }
 
     pub async fn start(
         state_ref: StateRef,
         key: Arc<SecretKey>,
         idle_timeout: Option<Duration>,
+        address: SocketAddr,
     ) -> crate::Result<(TakoServer, impl Future<Output = crate::Result<()>>)> {
         let msd = Duration::from_millis(20);
 
         let (from_tako_sender, mut from_tako_receiver) = unbounded_channel::<ToGatewayMessage>();
         let (to_tako_sender, mut to_tako_receiver) = unbounded_channel::<FromGatewayMessage>();
+
         let (core_ref, comm_ref, server_future) = tako::server::server_start(
-            "0.0.0.0:0".parse().unwrap(),
+            address,
             Some(key),
             msd,
             from_tako_sender.clone(),
@@ -66,6 +85,7 @@ impl TakoServer {
                 sender: to_tako_sender,
                 responses: Default::default(),
                 worker_port: core_ref.get().get_worker_listen_port(),
+                latency: Default::default(),
             }),
         };
         let server2 = server.clone();
@@ -75,12 +95,14 @@ impl TakoServer {
                 while let Some(message) = from_tako_receiver.recv().await {
                     match message {
                         ToGatewayMessage::TaskUpdate(msg) => {
-                            state_ref.get_mut().process_task_update(msg)
+                            let job_id = state_ref.get_mut().process_task_update(msg);
+                            submit_job_tasks(&state_ref, &server2, job_id);
                         }
                         ToGatewayMessage::TaskFailed(msg) => {
-                            state_ref
+                            let job_id = state_ref
                                 .get_mut()
                                 .process_task_failed(&state_ref, &server2, msg);
+                            submit_job_tasks(&state_ref, &server2, job_id);
                         }
                         ToGatewayMessage::NewWorker(msg) => {
                             state_ref.get_mut().process_worker_new(msg)
@@ -134,9 +156,14 @@ mod tests {
     #[tokio::test]
     async fn test_server_connect_worker() {
         let state = StateRef::new();
-        let (server, _fut) = TakoServer::start(state, Default::default(), None)
-            .await
-            .unwrap();
+        let (server, _fut) = TakoServer::start(
+            state,
+            Default::default(),
+            None,
+            "0.0.0.0:0".parse().unwrap(),
+        )
+        .await
+        .unwrap();
         TcpStream::connect(format!("127.0.0.1:{}", server.worker_port()))
             .await
             .unwrap();
@@ -145,9 +172,14 @@ mod tests {
     #[tokio::test]
     async fn test_server_server_info() {
         let state = StateRef::new();
-        let (server, fut) = TakoServer::start(state, Default::default(), None)
-            .await
-            .unwrap();
+        let (server, fut) = TakoServer::start(
+            state,
+            Default::default(),
+            None,
+            "0.0.0.0:0".parse().unwrap(),
+        )
+        .await
+        .unwrap();
         run_concurrent(fut, async move {
             assert!(
                 matches!(server.send_message(FromGatewayMessage::ServerInfo).await.unwrap(),
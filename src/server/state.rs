@@ -1,17 +1,20 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
+
+use chrono::Utc;
 
 use tako::messages::gateway::{
-    CancelTasks, FromGatewayMessage, LostWorkerMessage, LostWorkerReason, NewWorkerMessage,
-    TaskFailedMessage, TaskState, TaskUpdate, ToGatewayMessage,
+    CancelTasks, FromGatewayMessage, LostWorkerMessage, LostWorkerReason, NewTasksMessage,
+    NewWorkerMessage, TaskFailedMessage, TaskState, TaskUpdate, ToGatewayMessage,
 };
 
 use crate::common::WrappedRcRefCell;
 use crate::server::job::Job;
+use crate::server::metrics::ServerMetrics;
 use crate::server::rpc::TakoServer;
 use crate::server::worker::Worker;
-use crate::transfer::messages::LostWorkerReasonInfo;
+use crate::transfer::messages::{JobSelector, LostWorkerReasonInfo, TaskFailureInfo};
 use crate::{JobId, JobTaskCount, Map, TakoTaskId, WorkerId};
-use std::cmp::min;
+use std::cmp::{max, min};
 
 pub struct State {
     jobs: crate::Map<JobId, Job>,
@@ -33,8 +36,22 @@ pub struct State {
     base_task_id_to_job_id: BTreeMap<TakoTaskId, WorkerId>,
     job_id_counter: JobId,
     task_id_counter: TakoTaskId,
+
+    // The most recent task failures, the oldest one is at the front
+    recent_failures: VecDeque<TaskFailureInfo>,
+
+    metrics: ServerMetrics,
+
+    /// Set when the server is being drained, new jobs are refused
+    draining: bool,
+
+    /// Command executed when any job ends
+    job_finish_hook: Option<String>,
 }
 
+/// How many task failures are remembered for `recent_failures`
+const MAX_RECENT_FAILURES: usize = 100;
+
 pub type StateRef = WrappedRcRefCell<State>;
 
 /*pub fn new_state_ref() -> StateRef {
@@ -76,6 +93,62 @@ fn cancel_tasks_from_callback(
     });
 }
 
+/// Submits further tasks of the job to tako if enough of its submitted tasks have ended.
+/// No tasks are submitted while the server is being drained.
+pub fn submit_job_tasks(state_ref: &StateRef, tako_ref: &TakoServer, job_id: JobId) {
+    let tasks = {
+        let mut state = state_ref.get_mut();
+        if state.is_draining() {
+            return;
+        }
+        match state.get_job_mut(job_id) {
+            Some(job) => job.take_tasks_to_submit(),
+            None => return,
+        }
+    };
+    if tasks.is_empty() {
+        return;
+    }
+    log::debug!("Submitting {} more task(s) of job {}", tasks.len(), job_id);
+    // The message is passed to tako right now, before any later message (e.g. cancelation
+    // of the tasks), only the response is awaited asynchronously
+    let response = tako_ref.send_message(FromGatewayMessage::NewTasks(NewTasksMessage { tasks }));
+    tokio::task::spawn_local(async move {
+        match response.await.unwrap() {
+            ToGatewayMessage::NewTasksResponse(_) => { /* Ok */ }
+            _ => {
+                panic!("Invalid response");
+            }
+        };
+    });
+}
+
+/// Matches `name` against `pattern`, where `*` matches any sequence of characters
+/// and `?` matches exactly one character
+fn name_matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last star in the pattern and the position in name where it started to match
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 impl State {
     pub fn get_job(&self, job_id: JobId) -> Option<&Job> {
         self.jobs.get(&job_id)
@@ -100,6 +173,7 @@ impl State {
             .base_task_id_to_job_id
             .insert(job.base_task_id, job_id)
             .is_none());
+        self.metrics.submitted_tasks += job.n_tasks() as u64;
         assert!(self.jobs.insert(job_id, job).is_none());
     }
 
@@ -135,6 +209,32 @@ impl State {
         (self.job_id_counter - n)..self.job_id_counter
     }
 
+    /// Returns ids of jobs selected by `selector` in ascending order.
+    /// Explicitly given ids are returned even if there are no such jobs,
+    /// only ranges are clipped to ids that were already assigned.
+    pub fn select_job_ids(&self, selector: &JobSelector) -> Vec<JobId> {
+        let mut ids: Vec<JobId> = match selector {
+            JobSelector::All => self.jobs.keys().copied().collect(),
+            JobSelector::LastN(n) => self.last_n_ids(*n).collect(),
+            JobSelector::Specific(ids) => {
+                let last_id = self.job_id_counter - 1;
+                ids.ranges()
+                    .iter()
+                    .flat_map(|r| r.start..=max(r.start, min(r.end, last_id)))
+                    .collect()
+            }
+            JobSelector::Name(pattern) => self
+                .jobs
+                .values()
+                .filter(|job| name_matches_pattern(pattern, &job.name))
+                .map(|job| job.job_id)
+                .collect(),
+        };
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
     pub fn new_task_id(&mut self, task_count: JobTaskCount) -> TakoTaskId {
         let id = self.task_id_counter;
         self.task_id_counter += task_count as u64;
@@ -158,31 +258,78 @@ impl State {
         state_ref: &StateRef,
         tako_ref: &TakoServer,
         msg: TaskFailedMessage,
-    ) {
+    ) -> JobId {
         log::debug!("Task id={} failed", msg.id);
 
+        self.metrics.failed_tasks += 1;
         let job = self.get_job_mut_by_tako_task_id(msg.id).unwrap();
-        job.set_failed_state(msg.id, msg.info.message);
+        let (task_id, error) = job.set_failed_state(msg.id, msg.info.message);
+        let job_id = job.job_id;
 
         if let Some(max_fails) = job.max_fails {
             if job.counters.n_failed_tasks > max_fails {
-                let task_ids = job.non_finished_task_ids();
+                let (task_ids, _) = job.cancel_unsubmitted_tasks(job.non_finished_task_ids());
                 cancel_tasks_from_callback(state_ref, tako_ref, job.job_id, task_ids);
             }
         }
+
+        if self.recent_failures.len() == MAX_RECENT_FAILURES {
+            self.recent_failures.pop_front();
+        }
+        self.recent_failures.push_back(TaskFailureInfo {
+            job_id,
+            task_id,
+            time: Utc::now(),
+            error,
+        });
+        job_id
+    }
+
+    pub fn job_finish_hook(&self) -> Option<&String> {
+        self.job_finish_hook.as_ref()
+    }
+
+    pub fn set_job_finish_hook(&mut self, hook: Option<String>) {
+        self.job_finish_hook = hook;
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
     }
 
-    pub fn process_task_update(&mut self, msg: TaskUpdate) {
+    pub fn set_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    pub fn metrics_mut(&mut self) -> &mut ServerMetrics {
+        &mut self.metrics
+    }
+
+    /// Returns the most recent task failures, the newest one first
+    pub fn recent_failures(&self) -> impl Iterator<Item = &TaskFailureInfo> {
+        self.recent_failures.iter().rev()
+    }
+
+    pub fn process_task_update(&mut self, msg: TaskUpdate) -> JobId {
         log::debug!("Task id={} updated {:?}", msg.id, msg.state);
         let job = self.get_job_mut_by_tako_task_id(msg.id).unwrap();
+        let job_id = job.job_id;
         match msg.state {
             TaskState::Running => job.set_running_state(msg.id),
-            TaskState::Finished => job.set_finished_state(msg.id),
+            TaskState::Finished => {
+                job.set_finished_state(msg.id);
+                self.metrics.finished_tasks += 1;
+            }
             TaskState::Waiting => job.set_waiting_state(msg.id),
             TaskState::Invalid => {
                 unreachable!()
             }
         };
+        job_id
     }
 
     pub fn process_worker_new(&mut self, msg: NewWorkerMessage) {
@@ -214,6 +361,10 @@ impl StateRef {
             base_task_id_to_job_id: Default::default(),
             job_id_counter: 1,
             task_id_counter: 1,
+            recent_failures: Default::default(),
+            metrics: Default::default(),
+            draining: false,
+            job_finish_hook: None,
         })
     }
 }
@@ -223,9 +374,11 @@ mod tests {
     use tako::messages::common::ProgramDefinition;
 
     use crate::common::arraydef::ArrayDef;
-    use crate::server::job::Job;
-    use crate::server::state::StateRef;
-    use crate::transfer::messages::JobType;
+    use std::time::Duration;
+
+    use crate::server::job::{Job, JobTaskState, MAX_TASK_USAGE_RECORDS};
+    use crate::server::state::{name_matches_pattern, StateRef};
+    use crate::transfer::messages::{JobType, TaskResourceUsage};
     use tako::common::resources::ResourceRequest;
 
     fn dummy_program_definition() -> ProgramDefinition {
@@ -306,4 +459,199 @@ mod tests {
         assert_eq!(state.get_job_mut_by_tako_task_id(130).unwrap().job_id, 227);
         assert!(state.get_job_mut_by_tako_task_id(131).is_none());
     }
+
+    #[test]
+    fn test_submit_tasks_lazily() {
+        let mut job = Job::new(
+            JobType::Array(ArrayDef::simple_range(0, 150_000)),
+            1,
+            10,
+            "".to_string(),
+            dummy_program_definition(),
+            ResourceRequest::default(),
+            false,
+            None,
+        );
+        let tasks = job.take_tasks_to_submit();
+        assert_eq!(tasks.len(), 100_000);
+        assert_eq!(tasks[0].id, 10);
+        assert_eq!(tasks.last().unwrap().id, 100_009);
+        assert!(job.take_tasks_to_submit().is_empty());
+
+        let (remaining, canceled) = job.cancel_unsubmitted_tasks(vec![15, 120_010]);
+        assert_eq!(remaining, vec![15]);
+        assert_eq!(canceled, vec![120_000]);
+
+        for tako_id in 10..40_010 {
+            job.set_running_state(tako_id);
+            job.set_finished_state(tako_id);
+        }
+        assert!(job.take_tasks_to_submit().is_empty());
+        for tako_id in 40_010..50_010 {
+            job.set_running_state(tako_id);
+            job.set_finished_state(tako_id);
+        }
+        // The task canceled before its submission is skipped
+        let tasks = job.take_tasks_to_submit();
+        assert_eq!(tasks.len(), 49_999);
+        assert_eq!(tasks[0].id, 100_010);
+        assert_eq!(tasks.last().unwrap().id, 150_009);
+        assert!(job.take_tasks_to_submit().is_empty());
+    }
+
+    #[test]
+    fn test_task_state_ranges() {
+        let mut job = Job::new(
+            JobType::Array(ArrayDef::simple_range(5, 10)),
+            1,
+            100,
+            "".to_string(),
+            dummy_program_definition(),
+            ResourceRequest::default(),
+            false,
+            None,
+        );
+        assert_eq!(job.take_tasks_to_submit().len(), 10);
+        for tako_id in 100..103 {
+            job.set_running_state(tako_id);
+        }
+        job.set_finished_state(100);
+        job.set_finished_state(101);
+        assert_eq!(job.set_failed_state(102, "error".to_string()).0, 7);
+        assert_eq!(job.set_cancel_state(104), 9);
+        assert_eq!(job.get_task_state(103), JobTaskState::Waiting);
+
+        let tasks = job.make_job_detail(true).tasks;
+        assert_eq!(tasks.waiting.to_string(), "8,10-14");
+        assert!(tasks.running.ranges().is_empty());
+        assert_eq!(tasks.finished.to_string(), "5-6");
+        assert_eq!(tasks.failed.to_string(), "7");
+        assert_eq!(tasks.canceled.to_string(), "9");
+        assert_eq!(tasks.errors, vec![(7, "error".to_string())]);
+
+        let infos = tasks.tasks();
+        assert_eq!(infos.len(), 10);
+        assert_eq!(infos[2].task_id, 7);
+        assert_eq!(infos[2].state, JobTaskState::Failed);
+        assert_eq!(infos[2].error.as_deref(), Some("error"));
+        let tasks = job.make_job_detail(false).tasks;
+        assert!(tasks.is_empty());
+        assert_eq!(tasks.errors, vec![(7, "error".to_string())]);
+    }
+
+    fn dummy_usage(max_rss: u64) -> TaskResourceUsage {
+        TaskResourceUsage {
+            user_time: Duration::from_secs(2),
+            system_time: Duration::from_secs(1),
+            max_rss,
+            input_blocks: 1,
+            output_blocks: 2,
+        }
+    }
+
+    #[test]
+    fn test_task_usage_summary() {
+        let mut job = Job::new(
+            JobType::Array(ArrayDef::simple_range(0, 4)),
+            1,
+            10,
+            "".to_string(),
+            dummy_program_definition(),
+            ResourceRequest::default(),
+            false,
+            None,
+        );
+        job.set_task_usage(0, dummy_usage(100));
+        job.set_task_usage(1, dummy_usage(300));
+        // Repeated and invalid reports are ignored
+        job.set_task_usage(1, dummy_usage(1000));
+        job.set_task_usage(7, dummy_usage(1000));
+
+        let detail = job.make_job_detail(false);
+        assert!(detail.tasks.usage.is_empty());
+        let summary = detail.usage_summary;
+        assert_eq!(summary.n_tasks, 2);
+        assert_eq!(summary.cpu_time, Duration::from_secs(6));
+        assert_eq!(summary.mean_max_rss(), 200);
+        assert_eq!(summary.max_max_rss, 300);
+        assert_eq!(summary.input_blocks, 2);
+        assert_eq!(summary.output_blocks, 4);
+
+        let usage = job.make_job_detail(true).tasks.usage;
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage[1].0, 1);
+        assert_eq!(usage[1].1.max_rss, 300);
+    }
+
+    #[test]
+    fn test_task_usage_of_large_jobs_is_aggregated() {
+        let mut job = Job::new(
+            JobType::Array(ArrayDef::simple_range(0, MAX_TASK_USAGE_RECORDS + 1)),
+            1,
+            10,
+            "".to_string(),
+            dummy_program_definition(),
+            ResourceRequest::default(),
+            false,
+            None,
+        );
+        job.set_task_usage(0, dummy_usage(100));
+
+        let detail = job.make_job_detail(true);
+        assert!(detail.tasks.usage.is_empty());
+        assert_eq!(detail.usage_summary.n_tasks, 1);
+    }
+
+    #[test]
+    fn test_task_start_errors() {
+        let mut job = Job::new(
+            JobType::Array(ArrayDef::simple_range(0, 2)),
+            1,
+            10,
+            "".to_string(),
+            dummy_program_definition(),
+            ResourceRequest::default(),
+            false,
+            None,
+        );
+        assert_eq!(job.take_tasks_to_submit().len(), 2);
+        job.set_running_state(10);
+        job.set_running_state(11);
+
+        // The error of the start is received before the task fails
+        job.set_task_start_error(0, "Cannot start program".to_string());
+        assert_eq!(
+            job.set_failed_state(10, "exit code 1".to_string()),
+            (0, "Cannot start program".to_string())
+        );
+
+        // The error of the start is received after the task has failed
+        job.set_failed_state(11, "exit code 1".to_string());
+        job.set_task_start_error(1, "Cannot open stdin".to_string());
+
+        let errors = job.make_job_detail(false).tasks.errors;
+        assert_eq!(
+            errors,
+            vec![
+                (0, "Cannot start program".to_string()),
+                (1, "Cannot open stdin".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_name_matches_pattern() {
+        assert!(name_matches_pattern("abc", "abc"));
+        assert!(!name_matches_pattern("abc", "abcd"));
+        assert!(name_matches_pattern("pre*", "preprocess"));
+        assert!(name_matches_pattern("pre*", "pre"));
+        assert!(!name_matches_pattern("pre*", "xpre"));
+        assert!(name_matches_pattern("*process", "preprocess"));
+        assert!(name_matches_pattern("a*b*c", "aXXbYYbc"));
+        assert!(!name_matches_pattern("a*b*c", "aXXbYYb"));
+        assert!(name_matches_pattern("job-?", "job-1"));
+        assert!(!name_matches_pattern("job-?", "job-10"));
+        assert!(name_matches_pattern("*", ""));
+        assert!(!name_matches_pattern("?", ""));
+    }
 }
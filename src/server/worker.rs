@@ -1,8 +1,11 @@
 use chrono::Utc;
+use orion::util::secure_cmp;
 use tako::messages::common::WorkerConfiguration;
 
 use crate::server::worker::WorkerState::Offline;
-use crate::transfer::messages::{LostWorkerReasonInfo, WorkerExitInfo, WorkerInfo};
+use crate::transfer::messages::{
+    LostWorkerReasonInfo, WorkerExitInfo, WorkerInfo, WorkerStats, WORKER_TOKEN_KEY,
+};
 use crate::WorkerId;
 
 pub enum WorkerState {
@@ -14,14 +17,20 @@ pub struct Worker {
     worker_id: WorkerId,
     state: WorkerState,
     configuration: WorkerConfiguration,
+    stats: Option<WorkerStats>,
+    /// Token registered by the worker, it is not a part of the public configuration
+    token: Option<String>,
 }
 
 impl Worker {
-    pub fn new(worker_id: WorkerId, configuration: WorkerConfiguration) -> Self {
+    pub fn new(worker_id: WorkerId, mut configuration: WorkerConfiguration) -> Self {
+        let token = configuration.extra.remove(WORKER_TOKEN_KEY);
         Worker {
             worker_id,
             configuration,
             state: WorkerState::Online,
+            stats: None,
+            token,
         }
     }
 
@@ -33,6 +42,34 @@ impl Worker {
         &self.configuration
     }
 
+    /// Number of CPUs of the worker over all sockets
+    pub fn n_cpus(&self) -> u64 {
+        self.configuration
+            .resources
+            .cpus
+            .iter()
+            .map(|socket| socket.len() as u64)
+            .sum()
+    }
+
+    pub fn stats(&self) -> Option<&WorkerStats> {
+        self.stats.as_ref()
+    }
+
+    /// Checks that a message was sent by this worker and not by another client
+    pub fn is_authenticated_by(&self, token: &str) -> bool {
+        self.is_online()
+            && self
+                .token
+                .as_ref()
+                .map(|expected| secure_cmp(expected.as_bytes(), token.as_bytes()).is_ok())
+                .unwrap_or(false)
+    }
+
+    pub fn is_online(&self) -> bool {
+        matches!(self.state, WorkerState::Online)
+    }
+
     pub fn set_offline_state(&mut self, reason: LostWorkerReasonInfo) {
         self.state = Offline(WorkerExitInfo {
             ended_at: Utc::now(),
@@ -40,6 +77,10 @@ impl Worker {
         });
     }
 
+    pub fn update_stats(&mut self, stats: WorkerStats) {
+        self.stats = Some(stats);
+    }
+
     pub fn make_info(&self) -> WorkerInfo {
         WorkerInfo {
             id: self.worker_id,
@@ -48,6 +89,7 @@ impl Worker {
                 WorkerState::Online => None,
                 Offline(d) => Some(d.clone()),
             },
+            stats: self.stats.clone(),
         }
     }
 }
@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{AbortHandle, Abortable};
+use orion::kdf::SecretKey;
+use tokio::net::TcpListener;
+
+use crate::common::WrappedRcRefCell;
+use crate::transfer::workergate::pass_worker_to_tako;
+use crate::Map;
+
+/// Pause after a failed accept of a worker connection
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Authenticates connecting workers and passes their connections to tako
+pub struct WorkerGate {
+    /// Key of workers from the access record
+    key: Arc<SecretKey>,
+    /// Internal key of the tako server
+    tako_key: Arc<SecretKey>,
+    /// Address of the tako server on the loopback interface
+    tako_address: SocketAddr,
+    /// Connections of workers that are passed to tako
+    connections: Map<u64, AbortHandle>,
+    next_connection_id: u64,
+}
+
+pub type WorkerGateRef = WrappedRcRefCell<WorkerGate>;
+
+impl WorkerGate {
+    /// Replaces the key of workers and closes connections of all workers, so that they have to
+    /// connect again with the new key. Tako considers them lost and reschedules their tasks.
+    pub fn replace_key(&mut self, key: Arc<SecretKey>) {
+        self.key = key;
+        for (_, connection) in self.connections.drain() {
+            connection.abort();
+        }
+    }
+}
+
+impl WorkerGateRef {
+    pub fn new(
+        key: Arc<SecretKey>,
+        tako_key: Arc<SecretKey>,
+        tako_address: SocketAddr,
+    ) -> WorkerGateRef {
+        WrappedRcRefCell::wrap(WorkerGate {
+            key,
+            tako_key,
+            tako_address,
+            connections: Default::default(),
+            next_connection_id: 0,
+        })
+    }
+}
+
+pub async fn serve_worker_gate(listener: TcpListener, gate: WorkerGateRef) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("Cannot accept a worker connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let (id, key, tako_key, tako_address) = {
+            let mut gate = gate.get_mut();
+            let id = gate.next_connection_id;
+            gate.next_connection_id += 1;
+            gate.connections.insert(id, abort_handle);
+            (
+                id,
+                gate.key.clone(),
+                gate.tako_key.clone(),
+                gate.tako_address,
+            )
+        };
+        let gate = gate.clone();
+        tokio::task::spawn_local(async move {
+            let connection = pass_worker_to_tako(stream, key, tako_key, tako_address);
+            match Abortable::new(connection, abort_registration).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::debug!("Connection of a worker from {} failed: {}", address, e),
+                Err(_) => log::info!("Connection of a worker from {} was closed", address),
+            }
+            gate.get_mut().connections.remove(&id);
+        });
+    }
+}
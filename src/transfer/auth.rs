@@ -1,8 +1,99 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
 use anyhow::Context;
 use orion::kdf::SecretKey;
+use serde::{Deserialize, Serialize};
 
 const KEY_SIZE: usize = 32;
 
+/// Level of access of a client, each role includes the permissions of the previous ones,
+/// except the worker role, which is separate from all other roles
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessRole {
+    /// Sending stats and results of tasks by workers
+    Worker,
+    /// Reading information about jobs, workers and the server
+    /// Submitting and canceling jobs
+    Submit,
+    /// Stopping the server and workers, connections of workers
+    Admin,
+}
+
+impl AccessRole {
+    /// Whether a client with this role may perform an operation that requires `required`
+    pub fn allows(self, required: AccessRole) -> bool {
+        match (self, required) {
+            (AccessRole::Worker, AccessRole::Worker) => true,
+            (AccessRole::Worker, _) | (_, AccessRole::Worker) => false,
+            _ => self >= required,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessRole::Worker => "worker",
+            AccessRole::Monitor => "monitor",
+            AccessRole::Submit => "submit",
+            AccessRole::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for AccessRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "worker" => Ok(AccessRole::Worker),
+            "monitor" => Ok(AccessRole::Monitor),
+            "submit" => Ok(AccessRole::Submit),
+            "admin" => Ok(AccessRole::Admin),
+            _ => anyhow::bail!(
+                "Invalid role {:?}, expected monitor, submit, admin or worker",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for AccessRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Secret keys of individual access roles
+#[derive(Clone, Default)]
+pub struct RoleKeys {
+    pub admin: Arc<SecretKey>,
+    pub submit: Arc<SecretKey>,
+    pub monitor: Arc<SecretKey>,
+    pub worker: Arc<SecretKey>,
+}
+
+impl RoleKeys {
+    pub fn generate() -> Self {
+        RoleKeys {
+            admin: Arc::new(generate_key()),
+            submit: Arc::new(generate_key()),
+            monitor: Arc::new(generate_key()),
+            worker: Arc::new(generate_key()),
+        }
+    }
+
+    pub fn get(&self, role: AccessRole) -> &Arc<SecretKey> {
+        match role {
+            AccessRole::Worker => &self.worker,
+            AccessRole::Monitor => &self.monitor,
+            AccessRole::Submit => &self.submit,
+            AccessRole::Admin => &self.admin,
+        }
+    }
+}
+
 pub fn generate_key() -> SecretKey {
     SecretKey::generate(KEY_SIZE).unwrap()
 }
@@ -20,7 +111,7 @@ pub fn deserialize_key(key: &str) -> anyhow::Result<SecretKey> {
 mod tests {
     use orion::kdf::SecretKey;
 
-    use crate::transfer::auth::{deserialize_key, serialize_key};
+    use crate::transfer::auth::{deserialize_key, serialize_key, AccessRole};
 
     #[test]
     fn test_roundtrip() {
@@ -29,4 +120,18 @@ mod tests {
         let deserialized = deserialize_key(&str).unwrap();
         assert_eq!(key, deserialized);
     }
+
+    #[test]
+    fn test_role_order() {
+        assert!(AccessRole::Monitor < AccessRole::Submit);
+        assert!(AccessRole::Submit < AccessRole::Admin);
+        assert!(AccessRole::Admin.allows(AccessRole::Monitor));
+        assert!(!AccessRole::Submit.allows(AccessRole::Admin));
+        assert!(AccessRole::Worker.allows(AccessRole::Worker));
+        assert!(!AccessRole::Worker.allows(AccessRole::Monitor));
+        assert!(!AccessRole::Admin.allows(AccessRole::Worker));
+        assert_eq!("worker".parse::<AccessRole>().unwrap(), AccessRole::Worker);
+        assert_eq!("submit".parse::<AccessRole>().unwrap(), AccessRole::Submit);
+        assert!("root".parse::<AccessRole>().is_err());
+    }
 }
@@ -1,4 +1,6 @@
+use std::ffi::CStr;
 use std::marker::PhantomData;
+use std::path::Path;
 use std::sync::Arc;
 
 use bytes::{Bytes, BytesMut};
@@ -8,30 +10,37 @@ use futures::{Sink, SinkExt, Stream, StreamExt};
 use orion::aead::streaming::{StreamOpener, StreamSealer};
 use orion::kdf::SecretKey;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tako::transfer::auth::{do_authentication, open_message, seal_message};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 use crate::common::error::error;
 use crate::common::serverdir::AccessRecord;
+use crate::transfer::auth::{AccessRole, RoleKeys};
 use crate::transfer::messages::{FromClientMessage, ToClientMessage};
-use crate::transfer::protocol::make_protocol_builder;
+use crate::transfer::protocol::{check_compatibility, make_protocol_builder, ProtocolVersion};
 
-type Codec = Framed<TcpStream, LengthDelimitedCodec>;
+type Codec<S> = Framed<S, LengthDelimitedCodec>;
 
 const COMM_PROTOCOL: u32 = 0;
 
-pub struct HqConnection<ReceiveMsg, SendMsg> {
-    writer: SplitSink<Codec, Bytes>,
-    reader: SplitStream<Codec>,
+/// Byte stream over which a connection between a client and the server is established
+pub trait ConnectionStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> ConnectionStream for S {}
+
+pub struct HqConnection<ReceiveMsg, SendMsg, S> {
+    writer: SplitSink<Codec<S>, Bytes>,
+    reader: SplitStream<Codec<S>>,
     sealer: Option<StreamSealer>,
     opener: Option<StreamOpener>,
     _r: PhantomData<ReceiveMsg>,
     _s: PhantomData<SendMsg>,
 }
 
-impl<R: DeserializeOwned, S: Serialize> HqConnection<R, S> {
+impl<R: DeserializeOwned, S: Serialize, T: ConnectionStream> HqConnection<R, S, T> {
     pub async fn send(&mut self, item: S) -> crate::Result<()> {
         let data = serialize_message(item, &mut self.sealer)?;
         self.writer.send(data).await?;
@@ -73,10 +82,12 @@ impl<R: DeserializeOwned, S: Serialize> HqConnection<R, S> {
         (sink, stream)
     }
 
-    async fn init(socket: TcpStream, server: bool, key: Arc<SecretKey>) -> crate::Result<Self> {
-        let connection = make_protocol_builder().new_framed(socket);
-        let (mut tx, mut rx) = connection.split();
-
+    async fn init(
+        mut tx: SplitSink<Codec<T>, Bytes>,
+        mut rx: SplitStream<Codec<T>>,
+        server: bool,
+        key: Arc<SecretKey>,
+    ) -> crate::Result<Self> {
         let mut my_role = "hq-server".to_string();
         let mut peer_role = "hq-client".to_string();
         if !server {
@@ -102,29 +113,197 @@ impl<R: DeserializeOwned, S: Serialize> HqConnection<R, S> {
             _s: Default::default(),
         })
     }
+
+    /// Creates a connection without authentication and encryption.
+    /// It is only used for local sockets, where the permissions of the socket file
+    /// restrict who can connect.
+    fn init_local(tx: SplitSink<Codec<T>, Bytes>, rx: SplitStream<Codec<T>>) -> Self {
+        Self {
+            writer: tx,
+            reader: rx,
+            sealer: None,
+            opener: None,
+            _r: Default::default(),
+            _s: Default::default(),
+        }
+    }
+}
+
+pub type ClientConnection =
+    HqConnection<ToClientMessage, FromClientMessage, Box<dyn ConnectionStream>>;
+pub type ServerConnection<S> = HqConnection<FromClientMessage, ToClientMessage, S>;
+
+/// The first message of a client, it is sent before the authentication
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientHello {
+    pub protocol: ProtocolVersion,
+    /// The role selects the key that is used for the authentication
+    pub role: AccessRole,
+    /// Unix user running the client
+    pub user: String,
+}
+
+impl ClientHello {
+    fn new(role: AccessRole) -> ClientHello {
+        ClientHello {
+            protocol: ProtocolVersion::current(),
+            role,
+            user: current_user(),
+        }
+    }
+}
+
+/// The highest role for which the record contains a key
+fn record_role(record: &AccessRecord) -> crate::Result<AccessRole> {
+    match record.role() {
+        Some(role) => Ok(role),
+        None => error("Access record does not contain any access key".into()),
+    }
+}
+
+/// Response of the server to [`ClientHello`]
+#[derive(Serialize, Deserialize, Debug)]
+struct ServerHello {
+    protocol: ProtocolVersion,
+    /// Set when the server refuses the client, the connection is closed afterwards
+    error: Option<String>,
+}
+
+/// Hello messages are serialized as JSON, so that they can be read by all versions of HQ
+async fn send_hello<S: ConnectionStream, T: Serialize>(
+    tx: &mut SplitSink<Codec<S>, Bytes>,
+    hello: &T,
+) -> crate::Result<()> {
+    let data: Bytes = serde_json::to_vec(hello)?.into();
+    tx.send(data).await?;
+    Ok(())
+}
+
+async fn receive_hello<S: ConnectionStream, T: DeserializeOwned>(
+    rx: &mut SplitStream<Codec<S>>,
+) -> crate::Result<T> {
+    match rx.next().await {
+        Some(message) => match serde_json::from_slice(&message?) {
+            Ok(hello) => Ok(hello),
+            Err(e) => error(format!("Received an invalid hello message: {}", e)),
+        },
+        None => error("Connection was closed during the handshake".into()),
+    }
+}
+
+async fn client_handshake<S: ConnectionStream>(
+    tx: &mut SplitSink<Codec<S>, Bytes>,
+    rx: &mut SplitStream<Codec<S>>,
+    hello: &ClientHello,
+) -> crate::Result<()> {
+    send_hello(tx, hello).await?;
+    let response: ServerHello = receive_hello(rx).await?;
+    if let Some(message) = response.error {
+        return error(message);
+    }
+    check_compatibility(&hello.protocol, &response.protocol).or_else(error)
 }
 
-pub type ClientConnection = HqConnection<ToClientMessage, FromClientMessage>;
-pub type ServerConnection = HqConnection<FromClientMessage, ToClientMessage>;
+/// Receives the hello message of a client and refuses clients with an incompatible protocol
+async fn server_handshake<S: ConnectionStream>(
+    tx: &mut SplitSink<Codec<S>, Bytes>,
+    rx: &mut SplitStream<Codec<S>>,
+) -> crate::Result<ClientHello> {
+    let hello: ClientHello = receive_hello(rx).await?;
+    let protocol = ProtocolVersion::current();
+    let result = check_compatibility(&hello.protocol, &protocol);
+    send_hello(
+        tx,
+        &ServerHello {
+            protocol,
+            error: result.clone().err(),
+        },
+    )
+    .await?;
+    result.or_else(error)?;
+    Ok(hello)
+}
+
+/// Name of the user running this process, the uid is used if the name cannot be found
+fn current_user() -> String {
+    let uid = unsafe { libc::getuid() };
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if !passwd.is_null() {
+        let name = unsafe { CStr::from_ptr((*passwd).pw_name) };
+        if let Ok(name) = name.to_str() {
+            return name.to_string();
+        }
+    }
+    uid.to_string()
+}
 
 /// Client -> server connection
 impl ClientConnection {
     pub async fn connect_to_server(record: &AccessRecord) -> crate::Result<ClientConnection> {
+        Self::connect_to_server_as(record, record_role(record)?).await
+    }
+
+    /// Connects with the key of the given role instead of the highest role of the record
+    pub async fn connect_to_server_as(
+        record: &AccessRecord,
+        role: AccessRole,
+    ) -> crate::Result<ClientConnection> {
+        let key = match record.secret_key(role) {
+            Some(key) => key.clone(),
+            None => {
+                return error(format!(
+                    "Access record does not contain a key of the {} role",
+                    role
+                ))
+            }
+        };
+        let hello = ClientHello::new(role);
+
         let address = format!("{}:{}", record.host(), record.server_port());
-        let connection = TcpStream::connect(address).await?;
+        let connection: Box<dyn ConnectionStream> = Box::new(TcpStream::connect(address).await?);
+        let (mut tx, mut rx) = make_protocol_builder().new_framed(connection).split();
 
-        let key = record.hq_secret_key().clone();
-        HqConnection::init(connection, false, key).await
+        client_handshake(&mut tx, &mut rx, &hello).await?;
+        HqConnection::init(tx, rx, false, key).await
+    }
+
+    /// Connects to the local socket of the server, no authentication is performed
+    pub async fn connect_to_local_server(
+        record: &AccessRecord,
+        socket_path: &Path,
+    ) -> crate::Result<ClientConnection> {
+        let hello = ClientHello::new(record_role(record)?);
+
+        let connection: Box<dyn ConnectionStream> =
+            Box::new(UnixStream::connect(socket_path).await?);
+        let (mut tx, mut rx) = make_protocol_builder().new_framed(connection).split();
+
+        client_handshake(&mut tx, &mut rx, &hello).await?;
+        Ok(HqConnection::init_local(tx, rx))
     }
 }
 
 /// Server -> client connection
-impl ServerConnection {
+impl<S: ConnectionStream> ServerConnection<S> {
+    /// Returns the connection together with the hello message of the authenticated client
     pub async fn accept_client(
-        socket: TcpStream,
-        key: Arc<SecretKey>,
-    ) -> crate::Result<ServerConnection> {
-        HqConnection::init(socket, true, key).await
+        socket: S,
+        keys: &RoleKeys,
+    ) -> crate::Result<(ServerConnection<S>, ClientHello)> {
+        let (mut tx, mut rx) = make_protocol_builder().new_framed(socket).split();
+        let hello = server_handshake(&mut tx, &mut rx).await?;
+        let connection = HqConnection::init(tx, rx, true, keys.get(hello.role).clone()).await?;
+        Ok((connection, hello))
+    }
+
+    /// Accepts a client connected through the local socket of the server.
+    /// The client is trusted to use the role that it has sent.
+    pub async fn accept_local_client(
+        socket: S,
+    ) -> crate::Result<(ServerConnection<S>, ClientHello)> {
+        let (mut tx, mut rx) = make_protocol_builder().new_framed(socket).split();
+        let hello = server_handshake(&mut tx, &mut rx).await?;
+        Ok((HqConnection::init_local(tx, rx), hello))
     }
 }
 
@@ -4,10 +4,17 @@ use serde::Serialize;
 use tako::messages::common::{ProgramDefinition, WorkerConfiguration};
 
 use crate::common::arraydef::ArrayDef;
-use crate::server::job::{JobTaskCounters, JobTaskInfo};
+use crate::common::idset::IdSet;
+use crate::common::selectorparser::parse_job_selector;
+use crate::common::serverdir::AccessRecord;
+use crate::server::job::{JobTaskCounters, JobTaskStates};
+use crate::transfer::auth::AccessRole;
 use crate::{JobId, JobTaskCount, JobTaskId, WorkerId};
 use bstr::BString;
-use std::path::PathBuf;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use tako::common::resources::ResourceRequest;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -26,18 +33,47 @@ pub struct SubmitRequest {
     pub pin: bool,
     pub entries: Option<Vec<BString>>,
     pub submit_dir: PathBuf,
+    /// Command executed on the server when the job ends
+    #[serde(default)]
+    pub on_finish: Option<String>,
+    /// Entries were uploaded by [`FromClientMessage::UploadEntries`] before this request
+    #[serde(default)]
+    pub uploaded_entries: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CancelRequest {
     pub selector: JobSelector,
+    /// Cancel only the selected tasks of the jobs, all non-finished tasks are canceled if not set
+    pub tasks: Option<IdSet>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum JobSelector {
     All,
     LastN(JobId),
-    Specific(Vec<JobId>),
+    Specific(IdSet),
+    /// Jobs whose name matches a pattern with `*` and `?` wildcards
+    Name(String),
+}
+
+impl FromStr for JobSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_job_selector(s)
+    }
+}
+
+impl fmt::Display for JobSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobSelector::All => write!(f, "all"),
+            JobSelector::LastN(n) => write!(f, "last{}", n),
+            JobSelector::Specific(ids) => write!(f, "{}", ids),
+            JobSelector::Name(pattern) => write!(f, "name:{}", pattern),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -47,15 +83,128 @@ pub struct JobInfoRequest {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JobDetailRequest {
-    pub job_id: JobId,
+    pub selector: JobSelector,
+    /// States and resource usage of individual tasks are included only when it is set,
+    /// errors of failed tasks are always included
     pub include_tasks: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RotateKeysRequest {
+    /// Replace also the key of workers and disconnect connected workers
+    pub workers: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StopWorkerMessage {
     pub(crate) worker_id: WorkerId,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrainRequest {
+    /// Maximal time of waiting for running tasks, the server stops after it even if some tasks still run
+    pub timeout: Option<Duration>,
+    /// Cancel tasks that have not started, otherwise they are kept waiting (and not executed)
+    pub cancel_waiting: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrainProgress {
+    pub running_tasks: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrainResponse {
+    /// Tasks that had not started before the draining began and were canceled
+    pub canceled_tasks: u64,
+    /// Tasks that were still running when the timeout expired
+    pub unfinished_tasks: u64,
+    /// Tasks that had not started before the draining began and were kept waiting
+    pub waiting_tasks: u64,
+}
+
+/// Key of `WorkerConfiguration::extra` under which a worker registers a random token
+/// over its tako connection. Messages sent by the worker over client connections carry
+/// the token, so that the server accepts them only from the worker itself.
+pub const WORKER_TOKEN_KEY: &str = "HQ_WORKER_TOKEN";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkerStatsMessage {
+    pub worker_id: WorkerId,
+    pub token: String,
+    pub stats: WorkerStats,
+}
+
+/// Resource usage of a finished task program
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Maximum resident set size in bytes
+    pub max_rss: u64,
+    /// Number of block input operations
+    pub input_blocks: u64,
+    /// Number of block output operations
+    pub output_blocks: u64,
+}
+
+/// Resource usage aggregated over all tasks of a job that have reported it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobUsageSummary {
+    pub n_tasks: u64,
+    /// Sum of user and system CPU time
+    pub cpu_time: Duration,
+    pub total_max_rss: u64,
+    pub max_max_rss: u64,
+    pub input_blocks: u64,
+    pub output_blocks: u64,
+}
+
+impl JobUsageSummary {
+    pub fn add(&mut self, usage: &TaskResourceUsage) {
+        self.n_tasks += 1;
+        self.cpu_time += usage.user_time + usage.system_time;
+        self.total_max_rss += usage.max_rss;
+        self.max_max_rss = self.max_max_rss.max(usage.max_rss);
+        self.input_blocks += usage.input_blocks;
+        self.output_blocks += usage.output_blocks;
+    }
+
+    pub fn mean_max_rss(&self) -> u64 {
+        if self.n_tasks == 0 {
+            0
+        } else {
+            self.total_max_rss / self.n_tasks
+        }
+    }
+}
+
+/// Sent by a worker when the program of a task has ended
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskFinishRecord {
+    pub job_id: JobId,
+    pub task_id: JobTaskId,
+    /// Resource usage of the program, if it could be measured
+    pub usage: Option<TaskResourceUsage>,
+    /// Error that prevented the program from being started
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TaskFinishMessage {
+    pub worker_id: WorkerId,
+    pub token: String,
+    pub records: Vec<TaskFinishRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskFailureInfo {
+    pub job_id: JobId,
+    pub task_id: JobTaskId,
+    pub time: DateTime<Utc>,
+    pub error: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum FromClientMessage {
     Submit(SubmitRequest),
@@ -66,11 +215,71 @@ pub enum FromClientMessage {
     WorkerInfo(WorkerInfoRequest),
     StopWorker(StopWorkerMessage),
     Stop,
+    RecentFailures,
+    ServerInfo,
+    RotateKeys(RotateKeysRequest),
+    // Sent periodically by workers, the server does not respond to them
+    WorkerStats(WorkerStatsMessage),
+    /// Sent by workers when programs of tasks end
+    TaskFinish(TaskFinishMessage),
+    Drain(DrainRequest),
+    /// A chunk of entries of the next submitted job, the server does not respond to it
+    UploadEntries(Vec<BString>),
+}
+
+impl FromClientMessage {
+    /// The role that is allowed to send the message, see [`AccessRole::allows`]
+    pub fn required_role(&self) -> AccessRole {
+        match self {
+            // Hooks are executed on the server host
+            FromClientMessage::Submit(msg) if msg.on_finish.is_some() => AccessRole::Admin,
+            FromClientMessage::JobDetail(_)
+            | FromClientMessage::JobInfo(_)
+            | FromClientMessage::WorkerList
+            | FromClientMessage::WorkerInfo(_)
+            | FromClientMessage::RecentFailures
+            | FromClientMessage::ServerInfo => AccessRole::Monitor,
+            FromClientMessage::Submit(_)
+            | FromClientMessage::Cancel(_)
+            | FromClientMessage::UploadEntries(_) => AccessRole::Submit,
+            FromClientMessage::StopWorker(_)
+            | FromClientMessage::Stop
+            | FromClientMessage::RotateKeys(_)
+            | FromClientMessage::Drain(_) => AccessRole::Admin,
+            // Workers hold only the key of the worker role, other roles cannot send them
+            FromClientMessage::WorkerStats(_) | FromClientMessage::TaskFinish(_) => {
+                AccessRole::Worker
+            }
+        }
+    }
+
+    /// Name of the message type, used e.g. in metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            FromClientMessage::Submit(_) => "submit",
+            FromClientMessage::Cancel(_) => "cancel",
+            FromClientMessage::JobDetail(_) => "job-detail",
+            FromClientMessage::JobInfo(_) => "job-info",
+            FromClientMessage::WorkerList => "worker-list",
+            FromClientMessage::WorkerInfo(_) => "worker-info",
+            FromClientMessage::StopWorker(_) => "stop-worker",
+            FromClientMessage::Stop => "stop",
+            FromClientMessage::RecentFailures => "recent-failures",
+            FromClientMessage::ServerInfo => "server-info",
+            FromClientMessage::RotateKeys(_) => "rotate-keys",
+            FromClientMessage::WorkerStats(_) => "worker-stats",
+            FromClientMessage::TaskFinish(_) => "task-finish",
+            FromClientMessage::Drain(_) => "drain",
+            FromClientMessage::UploadEntries(_) => "upload-entries",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CancelJobResponse {
-    Canceled(Vec<JobTaskId>, JobTaskCount),
+    /// Ids of canceled tasks, ids of tasks that have already finished (or failed)
+    /// and ids of tasks that have been canceled before
+    Canceled(Vec<JobTaskId>, Vec<JobTaskId>, Vec<JobTaskId>),
     InvalidJob,
     Failed(String),
 }
@@ -78,13 +287,22 @@ pub enum CancelJobResponse {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ToClientMessage {
     JobInfoResponse(JobInfoResponse),
-    JobDetailResponse(Option<JobDetail>),
+    JobDetailResponse(Vec<(JobId, Option<JobDetail>)>),
     SubmitResponse(SubmitResponse),
     WorkerListResponse(WorkerListResponse),
     WorkerInfoResponse(Option<WorkerInfo>),
     StopWorkerResponse,
     CancelJobResponse(Vec<(JobId, CancelJobResponse)>),
+    RecentFailuresResponse(Vec<TaskFailureInfo>),
+    ServerInfoResponse(ServerInfoResponse),
+    RotateKeysResponse,
+    /// Sent before the server stops after [`FromClientMessage::Stop`]
+    StopResponse,
+    TaskFinishResponse,
     Error(String),
+    /// Sent periodically while the server is draining, before [`ToClientMessage::DrainResponse`]
+    DrainProgress(DrainProgress),
+    DrainResponse(DrainResponse),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -122,11 +340,43 @@ pub struct WorkerExitInfo {
     pub reason: LostWorkerReasonInfo,
 }
 
+/// Utilization of a node of a worker, sampled by the worker itself
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerStats {
+    pub timestamp: DateTime<Utc>,
+    /// Usage of individual cores in percents
+    pub cpu_usage: Vec<f32>,
+    /// Total memory in bytes
+    pub memory_total: u64,
+    /// Used memory in bytes
+    pub memory_used: u64,
+    pub load_average: [f32; 3],
+    /// Number of CPUs allocated to tasks running on the worker
+    pub allocated_cpus: u32,
+}
+
+impl WorkerStats {
+    pub fn average_cpu_usage(&self) -> f32 {
+        if self.cpu_usage.is_empty() {
+            return 0.0;
+        }
+        self.cpu_usage.iter().sum::<f32>() / self.cpu_usage.len() as f32
+    }
+
+    pub fn memory_usage(&self) -> f32 {
+        if self.memory_total == 0 {
+            return 0.0;
+        }
+        (self.memory_used as f32 / self.memory_total as f32) * 100.0
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WorkerInfo {
     pub id: WorkerId,
     pub configuration: WorkerConfiguration,
     pub ended: Option<WorkerExitInfo>,
+    pub stats: Option<WorkerStats>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -139,9 +389,10 @@ pub struct JobDetail {
     pub info: JobInfo,
     pub job_type: JobType,
     pub program_def: ProgramDefinition,
-    pub tasks: Vec<JobTaskInfo>,
+    pub tasks: JobTaskStates,
     pub resources: ResourceRequest,
     pub pin: bool,
+    pub usage_summary: JobUsageSummary,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -163,3 +414,59 @@ pub struct WorkerInfoRequest {
 pub struct WorkerInfoResponse {
     pub worker: WorkerInfo,
 }
+
+/// Static information about a running server, taken from its access record
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub server_dir: PathBuf,
+    pub host: String,
+    pub pid: u32,
+    pub server_port: u16,
+    pub worker_port: u16,
+    pub start_date: DateTime<Utc>,
+    pub version: String,
+}
+
+impl ServerInfo {
+    pub fn new(server_dir: &Path, record: &AccessRecord) -> Self {
+        ServerInfo {
+            server_dir: server_dir.to_path_buf(),
+            host: record.host().to_string(),
+            pid: record.pid(),
+            server_port: record.server_port(),
+            worker_port: record.worker_port(),
+            start_date: *record.start_date(),
+            version: record.version().to_string(),
+        }
+    }
+}
+
+/// Number of jobs or tasks in individual states
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StateCounts {
+    pub waiting: u64,
+    pub running: u64,
+    pub finished: u64,
+    pub failed: u64,
+    pub canceled: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerStats {
+    pub uptime: Duration,
+    pub jobs: StateCounts,
+    pub tasks: StateCounts,
+    pub online_workers: u64,
+    pub offline_workers: u64,
+    pub total_cpus: u64,
+    /// Estimated from resource requests of running tasks
+    pub used_cpus: u64,
+    /// Resident memory of the server process in bytes
+    pub memory_usage: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerInfoResponse {
+    pub info: ServerInfo,
+    pub stats: ServerStats,
+}
@@ -2,3 +2,4 @@ pub mod auth;
 pub mod connection;
 pub mod messages;
 pub mod protocol;
+pub mod workergate;
@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use tokio_util::codec::length_delimited::Builder;
 use tokio_util::codec::LengthDelimitedCodec;
 
@@ -6,3 +7,79 @@ pub fn make_protocol_builder() -> Builder {
         .little_endian()
         .max_frame_length(128 * 1024 * 1024)
 }
+
+/// Version of the protocol between clients and the server.
+/// It has to be increased whenever the messages change.
+pub const PROTOCOL_VERSION: u32 = 15;
+
+/// The oldest protocol version of the other side that can still be understood.
+/// It has to be increased when a change of the messages is not backward compatible.
+pub const MIN_COMPATIBLE_PROTOCOL_VERSION: u32 = 15;
+
+/// Protocol version exchanged by clients and the server when a connection is established
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProtocolVersion {
+    pub version: u32,
+    pub min_compatible: u32,
+    /// Version of HyperQueue, it is only used in error messages
+    pub hq_version: String,
+}
+
+impl ProtocolVersion {
+    pub fn current() -> Self {
+        ProtocolVersion {
+            version: PROTOCOL_VERSION,
+            min_compatible: MIN_COMPATIBLE_PROTOCOL_VERSION,
+            hq_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    pub fn is_compatible_with(&self, other: &ProtocolVersion) -> bool {
+        other.version >= self.min_compatible && self.version >= other.min_compatible
+    }
+}
+
+/// Checks that a client and a server can communicate together
+pub fn check_compatibility(
+    client: &ProtocolVersion,
+    server: &ProtocolVersion,
+) -> Result<(), String> {
+    if client.is_compatible_with(server) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Incompatible HyperQueue versions: the server has version {} (protocol {}), \
+            but the client has version {} (protocol {}). \
+            Use a client with a version compatible with the server.",
+            server.hq_version, server.version, client.hq_version, client.version
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_compatibility, ProtocolVersion};
+
+    fn version(version: u32, min_compatible: u32) -> ProtocolVersion {
+        ProtocolVersion {
+            version,
+            min_compatible,
+            hq_version: format!("0.{}.0", version),
+        }
+    }
+
+    #[test]
+    fn test_compatibility() {
+        assert!(check_compatibility(&version(1, 1), &version(1, 1)).is_ok());
+        // Newer client with a compatible server
+        assert!(check_compatibility(&version(3, 2), &version(2, 1)).is_ok());
+        // Older client with a compatible server
+        assert!(check_compatibility(&version(2, 1), &version(3, 2)).is_ok());
+        // Server does not understand the old client
+        assert!(check_compatibility(&version(1, 1), &version(3, 2)).is_err());
+        // Client does not understand the old server
+        let error = check_compatibility(&version(3, 3), &version(2, 1)).unwrap_err();
+        assert!(error.contains("server has version 0.2.0 (protocol 2)"));
+        assert!(error.contains("client has version 0.3.0 (protocol 3)"));
+    }
+}
@@ -0,0 +1,357 @@
+//! Workers connect to the server through a gate that authenticates them with the key of workers
+//! from the access record. Thanks to that, the key of workers can be replaced while the server
+//! is running, even though the key of tako is fixed for the whole lifetime of the tako server.
+//!
+//! The tako server listens only on the loopback interface and its key never leaves the server.
+//! Each worker runs a local end of the gate, to which its tako connects with a key generated
+//! by the worker for the single connection. Both ends of the gate terminate the encryption of
+//! tako: a message is opened with the key of one connection and sealed again with the key of
+//! the other one, so that messages of tako keep their framing and their order.
+//!
+//! The price is that each message of tako is decrypted and encrypted twice more (once at each
+//! end of the gate) and copied through two more sockets. Messages between tako workers and the
+//! server are small (task assignments and state updates), so the cost is dominated by latency
+//! of the network rather than by the gate.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use orion::aead::streaming::{StreamOpener, StreamSealer};
+use orion::kdf::SecretKey;
+use tako::transfer::auth::{do_authentication, seal_message};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::common::error::error;
+use crate::transfer::auth::generate_key;
+use crate::transfer::protocol::make_protocol_builder;
+
+type Codec = Framed<TcpStream, LengthDelimitedCodec>;
+
+/// Protocol between the two ends of the gate
+const GATE_PROTOCOL: u32 = 0;
+const GATE_ROLE: &str = "hq-worker-gate";
+const GATE_WORKER_ROLE: &str = "hq-worker";
+
+/// Handshake used by tako between its workers and its server
+const TAKO_PROTOCOL: u32 = 0;
+const TAKO_SERVER_ROLE: &str = "server";
+const TAKO_WORKER_ROLE: &str = "worker";
+
+/// One authenticated side of a connection that passes through the gate
+struct Link {
+    tx: SplitSink<Codec, Bytes>,
+    rx: SplitStream<Codec>,
+    sealer: Option<StreamSealer>,
+    opener: Option<StreamOpener>,
+}
+
+impl Link {
+    async fn authenticate(
+        stream: TcpStream,
+        protocol: u32,
+        my_role: &str,
+        peer_role: &str,
+        key: Arc<SecretKey>,
+    ) -> crate::Result<Link> {
+        let (mut tx, mut rx) = make_protocol_builder().new_framed(stream).split();
+        let (sealer, opener) = do_authentication(
+            protocol,
+            my_role.to_string(),
+            peer_role.to_string(),
+            Some(key),
+            &mut tx,
+            &mut rx,
+        )
+        .await?;
+        Ok(Link {
+            tx,
+            rx,
+            sealer,
+            opener,
+        })
+    }
+}
+
+fn open_frame(opener: &mut Option<StreamOpener>, frame: &BytesMut) -> crate::Result<Bytes> {
+    match opener {
+        Some(opener) => match opener.open_chunk(frame) {
+            Ok((data, _)) => Ok(data.into()),
+            Err(_) => error("Cannot decrypt a message passing through the worker gate".into()),
+        },
+        None => Ok(frame.clone().freeze()),
+    }
+}
+
+/// Passes messages from `rx` to `tx` until `rx` is closed
+async fn forward(
+    rx: &mut SplitStream<Codec>,
+    opener: &mut Option<StreamOpener>,
+    tx: &mut SplitSink<Codec, Bytes>,
+    sealer: &mut Option<StreamSealer>,
+) -> crate::Result<()> {
+    while let Some(frame) = rx.next().await {
+        let data = open_frame(opener, &frame?)?;
+        tx.send(seal_message(sealer, data)).await?;
+    }
+    Ok(())
+}
+
+/// Side of a connection passed through the gate that has closed it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClosedBy {
+    /// The other end of the gate, e.g. the server has closed the connection of the worker
+    Remote,
+    /// The local tako, i.e. tako has ended the session on its own
+    Tako,
+}
+
+/// Passes messages between the two links until one of them is closed,
+/// then both links are closed
+async fn relay(remote: Link, tako: Link) -> crate::Result<ClosedBy> {
+    let Link {
+        tx: mut remote_tx,
+        rx: mut remote_rx,
+        sealer: mut remote_sealer,
+        opener: mut remote_opener,
+    } = remote;
+    let Link {
+        tx: mut tako_tx,
+        rx: mut tako_rx,
+        sealer: mut tako_sealer,
+        opener: mut tako_opener,
+    } = tako;
+    tokio::select! {
+        r = forward(&mut remote_rx, &mut remote_opener, &mut tako_tx, &mut tako_sealer) => {
+            r.map(|()| ClosedBy::Remote)
+        }
+        r = forward(&mut tako_rx, &mut tako_opener, &mut remote_tx, &mut remote_sealer) => {
+            r.map(|()| ClosedBy::Tako)
+        }
+    }
+}
+
+/// Authenticates a worker and passes its messages to the tako server at `tako_address`,
+/// which is authenticated with `tako_key`
+pub async fn pass_worker_to_tako(
+    stream: TcpStream,
+    key: Arc<SecretKey>,
+    tako_key: Arc<SecretKey>,
+    tako_address: SocketAddr,
+) -> crate::Result<()> {
+    let worker =
+        Link::authenticate(stream, GATE_PROTOCOL, GATE_ROLE, GATE_WORKER_ROLE, key).await?;
+    let tako = TcpStream::connect(tako_address).await?;
+    let tako = Link::authenticate(
+        tako,
+        TAKO_PROTOCOL,
+        TAKO_WORKER_ROLE,
+        TAKO_SERVER_ROLE,
+        tako_key,
+    )
+    .await?;
+    relay(worker, tako).await?;
+    Ok(())
+}
+
+/// Connection of a worker that has been authenticated by the gate of the server
+pub struct GateConnection {
+    link: Link,
+    /// Key that the local tako has to use on its connection to the gate,
+    /// it is generated for each connection and it is not known to the server
+    pub tako_key: Arc<SecretKey>,
+}
+
+impl GateConnection {
+    pub async fn connect(address: &str, key: Arc<SecretKey>) -> crate::Result<GateConnection> {
+        let stream = TcpStream::connect(address).await?;
+        let link =
+            Link::authenticate(stream, GATE_PROTOCOL, GATE_WORKER_ROLE, GATE_ROLE, key).await?;
+        Ok(GateConnection {
+            link,
+            tako_key: Arc::new(generate_key()),
+        })
+    }
+
+    /// Accepts a single connection of tako at `listener` and passes it through the gate.
+    /// Returns the side that has closed the connection.
+    pub async fn pass_from(self, listener: TcpListener) -> crate::Result<ClosedBy> {
+        let (tako, _) = listener.accept().await?;
+        drop(listener);
+        let tako = Link::authenticate(
+            tako,
+            TAKO_PROTOCOL,
+            TAKO_SERVER_ROLE,
+            TAKO_WORKER_ROLE,
+            self.tako_key,
+        )
+        .await?;
+        relay(self.link, tako).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use futures::{SinkExt, StreamExt};
+    use orion::kdf::SecretKey;
+    use tako::transfer::auth::{open_message, seal_message, serialize};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::transfer::auth::generate_key;
+    use crate::transfer::workergate::{
+        pass_worker_to_tako, ClosedBy, GateConnection, Link, GATE_PROTOCOL, GATE_ROLE,
+        GATE_WORKER_ROLE, TAKO_PROTOCOL, TAKO_SERVER_ROLE, TAKO_WORKER_ROLE,
+    };
+
+    async fn local_listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        (listener, address)
+    }
+
+    /// Tako server that sends back every received message
+    async fn echo_tako_server(listener: TcpListener, key: Arc<SecretKey>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut link = Link::authenticate(
+            stream,
+            TAKO_PROTOCOL,
+            TAKO_SERVER_ROLE,
+            TAKO_WORKER_ROLE,
+            key,
+        )
+        .await
+        .unwrap();
+        while let Some(frame) = link.rx.next().await {
+            let message: Vec<u8> = open_message(&mut link.opener, &frame.unwrap()).unwrap();
+            let data = serialize(&message).unwrap();
+            let data = seal_message(&mut link.sealer, data.into());
+            if link.tx.send(data).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Starts an echo tako server behind a server gate, returns the address of the gate
+    async fn start_gate(gate_key: Arc<SecretKey>) -> SocketAddr {
+        let tako_key = Arc::new(generate_key());
+        let (tako_listener, tako_address) = local_listener().await;
+        tokio::spawn(echo_tako_server(tako_listener, tako_key.clone()));
+        let (gate_listener, gate_address) = local_listener().await;
+        tokio::spawn(async move {
+            let (stream, _) = gate_listener.accept().await.unwrap();
+            let _ = pass_worker_to_tako(stream, gate_key, tako_key, tako_address).await;
+        });
+        gate_address
+    }
+
+    /// Connects a tako worker through both ends of the gate
+    async fn connect_worker(
+        gate_address: SocketAddr,
+        gate_key: Arc<SecretKey>,
+    ) -> (Link, tokio::task::JoinHandle<crate::Result<ClosedBy>>) {
+        let gate = GateConnection::connect(&gate_address.to_string(), gate_key)
+            .await
+            .unwrap();
+        let tako_key = gate.tako_key.clone();
+        let (listener, address) = local_listener().await;
+        let relay = tokio::spawn(gate.pass_from(listener));
+        let stream = TcpStream::connect(address).await.unwrap();
+        let link = Link::authenticate(
+            stream,
+            TAKO_PROTOCOL,
+            TAKO_WORKER_ROLE,
+            TAKO_SERVER_ROLE,
+            tako_key,
+        )
+        .await
+        .unwrap();
+        (link, relay)
+    }
+
+    async fn roundtrip(link: &mut Link, message: Vec<u8>) -> Vec<u8> {
+        let data = serialize(&message).unwrap();
+        link.tx
+            .send(seal_message(&mut link.sealer, data.into()))
+            .await
+            .unwrap();
+        let frame = link.rx.next().await.unwrap().unwrap();
+        open_message(&mut link.opener, &frame).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_gate_passes_messages() {
+        let gate_key = Arc::new(generate_key());
+        let gate_address = start_gate(gate_key.clone()).await;
+        let (mut link, relay) = connect_worker(gate_address, gate_key).await;
+
+        for size in &[0, 1, 1000, 1024 * 1024] {
+            let message = vec![7; *size];
+            assert_eq!(roundtrip(&mut link, message.clone()).await, message);
+        }
+
+        drop(link);
+        assert_eq!(relay.await.unwrap().unwrap(), ClosedBy::Tako);
+    }
+
+    /// Server end of the gate that only authenticates a single worker
+    async fn authenticating_gate(
+        key: Arc<SecretKey>,
+    ) -> (SocketAddr, tokio::task::JoinHandle<crate::Result<Link>>) {
+        let (listener, address) = local_listener().await;
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            Link::authenticate(stream, GATE_PROTOCOL, GATE_ROLE, GATE_WORKER_ROLE, key).await
+        });
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn test_gate_rejects_invalid_key() {
+        let (gate_address, _gate) = authenticating_gate(Arc::new(generate_key())).await;
+        let connection =
+            GateConnection::connect(&gate_address.to_string(), Arc::new(generate_key())).await;
+        assert!(connection.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gate_closed_by_server() {
+        let gate_key = Arc::new(generate_key());
+        let (gate_address, gate) = authenticating_gate(gate_key.clone()).await;
+        let (_link, relay) = connect_worker(gate_address, gate_key).await;
+        drop(gate.await.unwrap().unwrap());
+        assert_eq!(relay.await.unwrap().unwrap(), ClosedBy::Remote);
+    }
+
+    /// Messages pass through two more decryptions and encryptions, the throughput
+    /// of the gate has to stay far above the traffic of tako
+    #[tokio::test]
+    async fn test_gate_throughput() {
+        let gate_key = Arc::new(generate_key());
+        let gate_address = start_gate(gate_key.clone()).await;
+        let (mut link, _relay) = connect_worker(gate_address, gate_key).await;
+
+        let message = vec![1; 64 * 1024];
+        let count = 512;
+        let start = Instant::now();
+        for _ in 0..count {
+            assert_eq!(
+                roundtrip(&mut link, message.clone()).await.len(),
+                message.len()
+            );
+        }
+        let duration = start.elapsed();
+        let throughput = (2 * count * message.len()) as f64 / duration.as_secs_f64();
+        log::info!(
+            "Worker gate passed {} MiB/s",
+            throughput / (1024.0 * 1024.0)
+        );
+        assert!(duration < Duration::from_secs(30));
+    }
+}
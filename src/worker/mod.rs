@@ -2,3 +2,5 @@ pub mod hwdetect;
 pub mod output;
 pub mod parser;
 pub mod start;
+pub mod stats;
+pub mod taskwrapper;
@@ -3,6 +3,8 @@ use humantime::format_duration;
 use tako::messages::common::WorkerConfiguration;
 
 use crate::client::globalsettings::GlobalSettings;
+use crate::client::utils::format_memory;
+use crate::transfer::messages::WorkerStats;
 use crate::WorkerId;
 
 pub fn print_worker_configuration(
@@ -65,3 +67,46 @@ pub fn print_worker_configuration(
     let table = rows.table().color_choice(gsettings.color_policy());
     assert!(print_stdout(table).is_ok());
 }
+
+pub fn print_worker_stats(gsettings: &GlobalSettings, stats: &WorkerStats) {
+    let rows = vec![
+        vec![
+            "Last update".cell().bold(true),
+            stats.timestamp.format("%F %T %Z").cell(),
+        ],
+        vec![
+            "CPU usage".cell().bold(true),
+            format!(
+                "{:.0}% ({})",
+                stats.average_cpu_usage(),
+                stats
+                    .cpu_usage
+                    .iter()
+                    .map(|u| format!("{:.0}%", u))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+            .cell(),
+        ],
+        vec![
+            "Memory usage".cell().bold(true),
+            format!(
+                "{:.0}% ({} / {})",
+                stats.memory_usage(),
+                format_memory(stats.memory_used),
+                format_memory(stats.memory_total)
+            )
+            .cell(),
+        ],
+        vec![
+            "Load average".cell().bold(true),
+            format!(
+                "{:.2} {:.2} {:.2}",
+                stats.load_average[0], stats.load_average[1], stats.load_average[2]
+            )
+            .cell(),
+        ],
+    ];
+    let table = rows.table().color_choice(gsettings.color_policy());
+    assert!(print_stdout(table).is_ok());
+}
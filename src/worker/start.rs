@@ -1,26 +1,45 @@
-use std::path::PathBuf;
+use std::cmp::min;
+use std::net::Ipv4Addr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context};
 use bstr::{BString, ByteSlice};
 use clap::Clap;
-use humantime::format_rfc3339;
+use humantime::{format_duration, format_rfc3339};
 use tako::messages::common::{LauncherDefinition, ProgramDefinition, WorkerConfiguration};
 use tako::worker::launcher::pin_program;
 use tako::worker::rpc::run_worker;
 use tako::worker::task::Task;
 use tempdir::TempDir;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::mpsc;
 use tokio::task::LocalSet;
 
 use crate::client::globalsettings::GlobalSettings;
-use crate::common::env::{HQ_CPUS, HQ_JOB_ID, HQ_PIN, HQ_SUBMIT_DIR, HQ_TASK_ID};
+use crate::common::config::{cli_or_config, cli_or_optional_config, WorkerSection};
+use crate::common::env::{
+    HQ_CLEAN_ENV, HQ_CPUS, HQ_ENTRY, HQ_JOB_ID, HQ_JOB_NAME, HQ_PIN, HQ_STDIN, HQ_STDIN_ENTRY,
+    HQ_SUBMIT_DIR, HQ_TASK_ID,
+};
 use crate::common::error::error;
-use crate::common::serverdir::ServerDir;
+use crate::common::placeholders::{fill_template, Placeholder};
+use crate::common::serverdir::AccessRecord;
+use crate::common::setup::log_to_file;
 use crate::common::timeutils::ArgDuration;
+use crate::transfer::auth::{generate_key, serialize_key, AccessRole};
+use crate::transfer::messages::WORKER_TOKEN_KEY;
+use crate::transfer::workergate::{ClosedBy, GateConnection};
 use crate::worker::hwdetect::detect_resource;
 use crate::worker::output::print_worker_configuration;
 use crate::worker::parser::parse_cpu_definition;
-use crate::Map;
+use crate::worker::stats::{report_worker_stats, RecordLoader};
+use crate::worker::taskwrapper::{serve_task_wrappers, AllocatedCpus};
+use crate::{Map, WorkerId};
 use hashbrown::HashMap;
 
 #[derive(Clap)]
@@ -58,74 +77,199 @@ pub struct WorkerStartOpts {
     cpus: Option<String>,
 
     /// How often should the worker announce its existence to the server. (default: "8s")
-    #[clap(long, default_value = "8s")]
-    heartbeat: ArgDuration,
+    #[clap(long)]
+    heartbeat: Option<ArgDuration>,
 
     #[clap(long)]
     idle_timeout: Option<ArgDuration>,
 
     /// What HPC job manager should be used by the worker.
-    #[clap(long, default_value = "detect", possible_values = &["detect", "slurm", "pbs", "none"])]
-    manager: ManagerOpts,
+    /// (default: "detect")
+    #[clap(long, possible_values = &["detect", "slurm", "pbs", "none"])]
+    manager: Option<ManagerOpts>,
+
+    /// Try to reconnect (with an exponential backoff) when the connection to the server is lost.
+    /// The access record is reloaded before each attempt, so the worker follows a restarted server.
+    /// Use `--reconnect=false` to disable it when it is enabled in the configuration file.
+    #[clap(
+        long,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true",
+        possible_values = &["true", "false"]
+    )]
+    reconnect: Option<bool>,
+
+    /// Hostname/IP of the server that overrides the address from the access record
+    #[clap(long)]
+    server_host: Option<String>,
+
+    /// Port for workers that overrides the port from the access record
+    #[clap(long)]
+    server_port: Option<u16>,
+}
+
+/// Address of the server given by the user instead of the address from the access record,
+/// e.g. when the server is visible under a different address from the worker node
+struct AddressOverride {
+    host: Option<String>,
+    worker_port: Option<u16>,
+}
+
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Values of placeholders that are known when a task is started
+struct PlaceholderValues<'a> {
+    env: &'a HashMap<BString, BString>,
+    date: String,
+    hostname: &'a str,
+    worker_id: Option<WorkerId>,
+    cwd: Option<String>,
+}
+
+impl<'a> PlaceholderValues<'a> {
+    fn resolve(&self, placeholder: &Placeholder) -> Option<String> {
+        let env_value = |key: &str| {
+            self.env
+                .get(&BString::from(key))
+                .map(|value| value.to_str_lossy().into_owned())
+        };
+        match placeholder {
+            Placeholder::JobId => env_value(HQ_JOB_ID),
+            Placeholder::TaskId => env_value(HQ_TASK_ID),
+            Placeholder::JobName => env_value(HQ_JOB_NAME),
+            Placeholder::Entry => env_value(HQ_ENTRY),
+            Placeholder::SubmitDir => env_value(HQ_SUBMIT_DIR),
+            Placeholder::Cwd => self.cwd.clone(),
+            Placeholder::Date => Some(self.date.clone()),
+            Placeholder::Hostname => Some(self.hostname.to_string()),
+            Placeholder::WorkerId => self.worker_id.map(|id| id.to_string()),
+            // Variables that are not set for the task are inherited from the worker
+            Placeholder::Env(name) => Some(
+                env_value(name)
+                    .or_else(|| std::env::var(name).ok())
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    /// Placeholders are validated during submit, so an invalid template is only logged
+    fn fill(&self, template: &str) -> String {
+        fill_template(template, |placeholder| self.resolve(placeholder)).unwrap_or_else(|e| {
+            log::warn!("Cannot replace placeholders in {:?}: {}", template, e);
+            template.to_string()
+        })
+    }
+
+    /// Paths that are not valid UTF-8 are used as they are
+    fn fill_path(&self, path: &Path) -> PathBuf {
+        match path.to_str() {
+            Some(path) => self.fill(path).into(),
+            None => path.to_path_buf(),
+        }
+    }
 }
 
 /// Replace placeholders in user-defined program attributes
-fn replace_placeholders(program: &mut ProgramDefinition) {
-    let date = format_rfc3339(std::time::SystemTime::now()).to_string();
+fn replace_placeholders(
+    program: &mut ProgramDefinition,
+    hostname: &str,
+    worker_id: Option<WorkerId>,
+) {
     let submit_dir = PathBuf::from(
         program.env[&BString::from(HQ_SUBMIT_DIR)]
             .to_os_str()
             .unwrap_or_default(),
     );
-
-    let mut placeholder_map = HashMap::new();
-    placeholder_map.insert(
-        "%{JOB_ID}",
-        program.env[&BString::from(HQ_JOB_ID)].to_string(),
-    );
-    placeholder_map.insert(
-        "%{TASK_ID}",
-        program.env[&BString::from(HQ_TASK_ID)].to_string(),
-    );
-    placeholder_map.insert(
-        "%{SUBMIT_DIR}",
-        program.env[&BString::from(HQ_SUBMIT_DIR)].to_string(),
-    );
-    placeholder_map.insert("%{DATE}", date);
-
-    let replace = |replacement_map: &HashMap<&str, String>, path: &PathBuf| -> PathBuf {
-        let mut result: String = path.to_str().unwrap().into();
-        for (placeholder, replacement) in replacement_map.iter() {
-            result = result.replace(placeholder, replacement);
-        }
-        result.into()
+    let env = program.env.clone();
+    let mut values = PlaceholderValues {
+        env: &env,
+        date: format_rfc3339(std::time::SystemTime::now()).to_string(),
+        hostname,
+        worker_id,
+        cwd: None,
     };
 
     // Replace CWD
     program.cwd = program
         .cwd
         .as_ref()
-        .map(|cwd| submit_dir.join(replace(&placeholder_map, cwd)))
+        .map(|cwd| submit_dir.join(values.fill_path(cwd)))
         .or_else(|| Some(std::env::current_dir().unwrap()));
 
-    // Replace STDOUT and STDERR
-    placeholder_map.insert(
-        "%{CWD}",
-        program.cwd.as_ref().unwrap().to_str().unwrap().to_string(),
-    );
+    // Replace STDOUT, STDERR, STDIN and arguments
+    values.cwd = Some(program.cwd.as_ref().unwrap().to_string_lossy().into_owned());
 
     program.stdout = program
         .stdout
         .as_ref()
-        .map(|path| submit_dir.join(replace(&placeholder_map, path)));
+        .map(|path| submit_dir.join(values.fill_path(path)));
     program.stderr = program
         .stderr
         .as_ref()
-        .map(|path| submit_dir.join(replace(&placeholder_map, path)));
+        .map(|path| submit_dir.join(values.fill_path(path)));
+
+    let stdin_key = BString::from(HQ_STDIN);
+    if let Some(path) = env.get(&stdin_key) {
+        let path = submit_dir.join(values.fill_path(&path.to_path_lossy()));
+        program
+            .env
+            .insert(stdin_key, path.as_os_str().as_bytes().into());
+    }
+
+    for arg in program.args.iter_mut() {
+        if let Ok(value) = arg.to_str() {
+            *arg = values.fill(value).into();
+        }
+    }
+}
+
+/// Paths used for running programs of tasks through `hq worker run-task`
+struct TaskWrapper {
+    /// Path of the worker binary
+    exe: PathBuf,
+    /// Socket on which the worker receives results of tasks from wrappers
+    socket: PathBuf,
+}
+
+/// Runs the program through `hq worker run-task` that reports its resource usage to the worker.
+/// Variables that instruct the wrapper are removed from the environment of the program.
+fn wrap_program(program: &mut ProgramDefinition, wrapper: &TaskWrapper) {
+    let mut args: Vec<BString> = vec![
+        wrapper.exe.as_os_str().as_bytes().into(),
+        "worker".into(),
+        "run-task".into(),
+        "--socket".into(),
+        wrapper.socket.as_os_str().as_bytes().into(),
+    ];
+    if let Some(path) = program.env.remove(&BString::from(HQ_STDIN)) {
+        args.push("--stdin".into());
+        args.push(path);
+    }
+    if program.env.remove(&BString::from(HQ_STDIN_ENTRY)).is_some() {
+        args.push("--stdin-entry".into());
+    }
+    if program.env.remove(&BString::from(HQ_CLEAN_ENV)).is_some() {
+        args.push("--clean-env".into());
+        for key in program.env.keys() {
+            args.push("--keep-env".into());
+            args.push(key.clone());
+        }
+    }
+    args.push("--".into());
+    args.append(&mut program.args);
+    program.args = args;
 }
 
 #[allow(clippy::unnecessary_wraps)]
-fn launcher_setup(task: &Task, def: LauncherDefinition) -> tako::Result<ProgramDefinition> {
+fn launcher_setup(
+    task: &Task,
+    def: LauncherDefinition,
+    wrapper: &TaskWrapper,
+    hostname: &str,
+    worker_id: Option<WorkerId>,
+) -> tako::Result<ProgramDefinition> {
     let allocation = task
         .resource_allocation()
         .expect("Missing resource allocation for running task");
@@ -140,7 +284,8 @@ fn launcher_setup(task: &Task, def: LauncherDefinition) -> tako::Result<ProgramD
         .env
         .insert(HQ_CPUS.into(), allocation.comma_delimited_cpu_ids().into());
 
-    replace_placeholders(&mut program);
+    replace_placeholders(&mut program, hostname, worker_id);
+    wrap_program(&mut program, wrapper);
 
     Ok(program)
 }
@@ -148,31 +293,194 @@ fn launcher_setup(task: &Task, def: LauncherDefinition) -> tako::Result<ProgramD
 pub async fn start_hq_worker(
     gsettings: &GlobalSettings,
     opts: WorkerStartOpts,
+    config: &WorkerSection,
 ) -> anyhow::Result<()> {
     log::info!("Starting hyperqueue worker {}", env!("CARGO_PKG_VERSION"));
-    let server_dir =
-        ServerDir::open(gsettings.server_directory()).context("Cannot load server directory")?;
-    let record = server_dir.read_access_record().with_context(|| {
+    let reconnect = cli_or_config(
+        opts.reconnect,
+        "worker.reconnect",
+        &config.reconnect.to_string(),
+    )?;
+    let address = AddressOverride {
+        host: opts
+            .server_host
+            .clone()
+            .or_else(|| config.server_host.clone()),
+        worker_port: opts.server_port.or(config.server_port),
+    };
+    let configuration = gather_configuration(opts, config)?;
+    let load_record: RecordLoader = {
+        let gsettings = gsettings.clone();
+        Rc::new(move || load_access_record(&gsettings, &address))
+    };
+
+    if !reconnect {
+        let record = load_record()?;
+        run_worker_session(gsettings, &record, load_record, configuration).await?;
+        return Ok(());
+    }
+
+    let mut delay = RECONNECT_INITIAL_DELAY;
+    loop {
+        match load_record() {
+            Ok(record) => match run_worker_session(
+                gsettings,
+                &record,
+                load_record.clone(),
+                configuration.clone(),
+            )
+            .await
+            {
+                Ok(SessionEnd::Stopped) => return Ok(()),
+                Ok(SessionEnd::ConnectionLost) => delay = RECONNECT_INITIAL_DELAY,
+                Err(e) => log::warn!("Cannot connect to the server: {:?}", e),
+            },
+            Err(e) => log::warn!("{:?}", e),
+        }
+        log::info!("Reconnecting in {}", format_duration(delay));
+        tokio::time::sleep(delay).await;
+        delay = min(delay * 2, RECONNECT_MAX_DELAY);
+    }
+}
+
+fn load_access_record(
+    gsettings: &GlobalSettings,
+    address: &AddressOverride,
+) -> anyhow::Result<AccessRecord> {
+    let server_dir = gsettings
+        .open_server_dir()
+        .context("Cannot load server directory")?;
+    let mut record = server_dir.read_access_record().with_context(|| {
         format!(
             "Cannot load access record from {:?}",
             server_dir.access_filename()
         )
     })?;
+    record.check_version()?;
+    if let Some(host) = &address.host {
+        record.set_host(host.clone());
+    }
+    if let Some(port) = address.worker_port {
+        record.set_worker_port(port);
+    }
+    Ok(record)
+}
+
+/// Reason why a session of the worker has ended
+enum SessionEnd {
+    /// Tako has ended the session on its own, e.g. after `hq worker stop` or an idle timeout
+    Stopped,
+    /// The server has closed the connection or the connection has failed
+    ConnectionLost,
+}
+
+/// Registers the worker at the server described by `record` and runs it until
+/// the connection is closed.
+async fn run_worker_session(
+    gsettings: &GlobalSettings,
+    record: &AccessRecord,
+    load_record: RecordLoader,
+    mut configuration: WorkerConfiguration,
+) -> anyhow::Result<SessionEnd> {
+    let worker_key = match (
+        record.tako_secret_key(),
+        record.secret_key(AccessRole::Worker),
+    ) {
+        (Some(key), Some(_)) => key.clone(),
+        _ => anyhow::bail!(
+            "The access record does not allow connecting workers, \
+            the worker or the admin role is required"
+        ),
+    };
     let server_address = format!("{}:{}", record.host(), record.worker_port());
     log::info!("Connecting to: {}", server_address);
+    let gate = GateConnection::connect(&server_address, worker_key).await?;
+    let tako_secret_key = gate.tako_key.clone();
+    // Tako connects to a local socket with a key generated for this session,
+    // its connection is then passed through the gate
+    let tako_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let tako_address = tako_listener.local_addr()?.to_string();
+    let gate_task = tokio::spawn(async move {
+        match gate.pass_from(tako_listener).await {
+            Ok(ClosedBy::Tako) => SessionEnd::Stopped,
+            Ok(ClosedBy::Remote) => SessionEnd::ConnectionLost,
+            Err(e) => {
+                log::debug!("Connection to the server failed: {}", e);
+                SessionEnd::ConnectionLost
+            }
+        }
+    });
 
-    let configuration = gather_configuration(opts)?;
-    let ((worker_id, configuration), worker_future) = run_worker(
-        &server_address,
+    std::fs::create_dir_all(&configuration.work_dir)?;
+    let wrapper = TaskWrapper {
+        exe: std::env::current_exe().context("Cannot find the path of the worker binary")?,
+        socket: configuration.work_dir.join("tasks.sock"),
+    };
+    // The socket may remain from the previous session of the worker
+    let _ = std::fs::remove_file(&wrapper.socket);
+    let wrapper_listener = UnixListener::bind(&wrapper.socket)
+        .with_context(|| format!("Cannot create the socket for tasks {:?}", wrapper.socket))?;
+
+    // Authenticates messages that the worker sends over client connections
+    let token = serialize_key(&generate_key());
+    configuration
+        .extra
+        .insert(WORKER_TOKEN_KEY.to_string(), token.clone());
+
+    let hostname = configuration.hostname.clone();
+    // The id is assigned by the server during the registration, before any task is started
+    let launcher_worker_id: Arc<Mutex<Option<WorkerId>>> = Default::default();
+    let registered_worker_id = launcher_worker_id.clone();
+
+    let registration = run_worker(
+        &tako_address,
         configuration,
-        Some(record.tako_secret_key().clone()),
-        Box::new(launcher_setup),
+        Some(tako_secret_key),
+        Box::new(move |task: &Task, def: LauncherDefinition| {
+            let worker_id = *launcher_worker_id.lock().unwrap();
+            launcher_setup(task, def, &wrapper, &hostname, worker_id)
+        }),
     )
-    .await?;
+    .await;
+    let ((worker_id, configuration), worker_future) = match registration {
+        Ok(result) => result,
+        Err(e) => {
+            gate_task.abort();
+            return Err(e.into());
+        }
+    };
+    *registered_worker_id.lock().unwrap() = Some(worker_id);
+    match gsettings.open_server_dir() {
+        Ok(server_dir) => log_to_file(&server_dir.worker_log_path(worker_id)),
+        Err(e) => log::warn!("Cannot open server directory for the log file: {}", e),
+    }
+    let stats_period = configuration.heartbeat_interval;
     print_worker_configuration(gsettings, worker_id, configuration);
+    let (finished_sender, finished_receiver) = mpsc::unbounded_channel();
+    let allocated_cpus = AllocatedCpus::default();
     let local_set = LocalSet::new();
+    local_set.spawn_local(serve_task_wrappers(
+        wrapper_listener,
+        finished_sender,
+        allocated_cpus.clone(),
+    ));
+    local_set.spawn_local(report_worker_stats(
+        record.clone(),
+        load_record,
+        worker_id,
+        token,
+        stats_period,
+        finished_receiver,
+        allocated_cpus,
+    ));
     local_set.run_until(worker_future).await;
-    Ok(())
+    // The connection of tako is closed when its session ends, so the gate finishes too
+    let end = gate_task.await.unwrap_or(SessionEnd::ConnectionLost);
+    match end {
+        SessionEnd::Stopped => log::info!("Worker was stopped by the server"),
+        SessionEnd::ConnectionLost => log::warn!("Connection to the server was lost"),
+    }
+    Ok(end)
 }
 
 fn try_get_pbs_info() -> anyhow::Result<Map<String, String>> {
@@ -229,13 +537,17 @@ fn gather_manager_info(opts: ManagerOpts) -> anyhow::Result<Map<String, String>>
     }
 }
 
-fn gather_configuration(opts: WorkerStartOpts) -> anyhow::Result<WorkerConfiguration> {
+fn gather_configuration(
+    opts: WorkerStartOpts,
+    config: &WorkerSection,
+) -> anyhow::Result<WorkerConfiguration> {
     let hostname = gethostname::gethostname()
         .into_string()
         .expect("Invalid hostname");
 
     let resources = opts
         .cpus
+        .or_else(|| config.cpus.clone())
         .map(|cpus| parse_cpu_definition(&cpus))
         .unwrap_or_else(detect_resource)?;
 
@@ -244,7 +556,16 @@ fn gather_configuration(opts: WorkerStartOpts) -> anyhow::Result<WorkerConfigura
         (tmpdir.join("work"), tmpdir.join("logs"))
     };
 
-    let extra = gather_manager_info(opts.manager)?;
+    let manager = cli_or_config(opts.manager, "worker.manager", &config.manager)?;
+    let heartbeat: ArgDuration =
+        cli_or_config(opts.heartbeat, "worker.heartbeat", &config.heartbeat)?;
+    let idle_timeout: Option<ArgDuration> = cli_or_optional_config(
+        opts.idle_timeout,
+        "worker.idle-timeout",
+        config.idle_timeout.as_ref(),
+    )?;
+
+    let extra = gather_manager_info(manager)?;
 
     Ok(WorkerConfiguration {
         resources,
@@ -252,21 +573,24 @@ fn gather_configuration(opts: WorkerStartOpts) -> anyhow::Result<WorkerConfigura
         hostname,
         work_dir,
         log_dir,
-        heartbeat_interval: opts.heartbeat.into_duration(),
-        idle_timeout: opts.idle_timeout.map(|x| x.into_duration()),
+        heartbeat_interval: heartbeat.into_duration(),
+        idle_timeout: idle_timeout.map(|x| x.into_duration()),
         extra,
     })
 }
 
 #[cfg(test)]
 mod tests {
+    use bstr::BString;
     use hashbrown::HashMap;
     use tako::messages::common::ProgramDefinition;
 
-    use crate::common::env::{HQ_JOB_ID, HQ_SUBMIT_DIR, HQ_TASK_ID};
+    use crate::common::env::{
+        HQ_CLEAN_ENV, HQ_JOB_ID, HQ_JOB_NAME, HQ_STDIN, HQ_STDIN_ENTRY, HQ_SUBMIT_DIR, HQ_TASK_ID,
+    };
     use crate::{JobId, JobTaskId};
 
-    use super::replace_placeholders;
+    use super::{replace_placeholders, wrap_program, TaskWrapper};
 
     #[test]
     fn test_replace_task_id() {
@@ -278,7 +602,7 @@ mod tests {
             0,
             1,
         );
-        replace_placeholders(&mut program);
+        replace_placeholders(&mut program, "host", None);
         assert_eq!(program.cwd, Some("dir-1".into()));
         assert_eq!(program.stdout, Some("1.out".into()));
         assert_eq!(program.stderr, Some("1.err".into()));
@@ -294,7 +618,7 @@ mod tests {
             5,
             1,
         );
-        replace_placeholders(&mut program);
+        replace_placeholders(&mut program, "host", None);
         assert_eq!(program.cwd, Some("dir-5-1".into()));
         assert_eq!(program.stdout, Some("5-1.out".into()));
         assert_eq!(program.stderr, Some("5-1.err".into()));
@@ -310,7 +634,7 @@ mod tests {
             5,
             1,
         );
-        replace_placeholders(&mut program);
+        replace_placeholders(&mut program, "host", None);
         assert_eq!(program.cwd, Some("/submit-dir".into()));
         assert_eq!(program.stdout, Some("/submit-dir/out".into()));
         assert_eq!(program.stderr, Some("/submit-dir/err".into()));
@@ -326,12 +650,85 @@ mod tests {
             5,
             1,
         );
-        replace_placeholders(&mut program);
+        replace_placeholders(&mut program, "host", None);
         assert_eq!(program.cwd, Some("dir-5-1".into()));
         assert_eq!(program.stdout, Some("dir-5-1.out".into()));
         assert_eq!(program.stderr, Some("dir-5-1.err".into()));
     }
 
+    #[test]
+    fn test_replace_extended_placeholders() {
+        let mut program = program_def(
+            "dir-%{JOB_NAME}",
+            Some("%{HOSTNAME}-%{WORKER_ID:03}-%{TASK_ID:04}.out"),
+            None,
+            "",
+            5,
+            12,
+        );
+        program.env.insert(HQ_JOB_NAME.into(), "job".into());
+        program.env.insert("VAR".into(), "value".into());
+        program.args = vec!["echo".into(), "%{ENV:VAR} %{CWD} 100%".into()];
+        replace_placeholders(&mut program, "host", Some(7));
+        assert_eq!(program.cwd, Some("dir-job".into()));
+        assert_eq!(program.stdout, Some("host-007-0012.out".into()));
+        assert_eq!(program.args[1], "value dir-job 100%");
+    }
+
+    fn task_wrapper() -> TaskWrapper {
+        TaskWrapper {
+            exe: "/bin/hq".into(),
+            socket: "/work/tasks.sock".into(),
+        }
+    }
+
+    #[test]
+    fn test_wrap_program_clean_env() {
+        let mut program = program_def("", None, None, "", 5, 1);
+        program.env.insert(HQ_CLEAN_ENV.into(), "1".into());
+        program.args = vec!["env".into()];
+        wrap_program(&mut program, &task_wrapper());
+
+        assert!(!program.env.contains_key(&BString::from(HQ_CLEAN_ENV)));
+        assert_eq!(
+            program.args[..6],
+            [
+                "/bin/hq",
+                "worker",
+                "run-task",
+                "--socket",
+                "/work/tasks.sock",
+                "--clean-env"
+            ]
+        );
+        let mut kept: Vec<_> = program
+            .args
+            .windows(2)
+            .filter(|pair| pair[0] == "--keep-env")
+            .map(|pair| pair[1].to_string())
+            .collect();
+        kept.sort();
+        assert_eq!(kept, vec![HQ_JOB_ID, HQ_SUBMIT_DIR, HQ_TASK_ID]);
+        assert_eq!(program.args[program.args.len() - 2..], ["--", "env"]);
+    }
+
+    #[test]
+    fn test_wrap_program_stdin() {
+        let mut program = program_def("", None, None, "", 5, 1);
+        program.env.insert(HQ_STDIN.into(), "/submit/in.1".into());
+        program.args = vec!["cat".into()];
+        wrap_program(&mut program, &task_wrapper());
+        assert!(!program.env.contains_key(&BString::from(HQ_STDIN)));
+        assert_eq!(program.args[5..], ["--stdin", "/submit/in.1", "--", "cat"]);
+
+        let mut program = program_def("", None, None, "", 5, 1);
+        program.env.insert(HQ_STDIN_ENTRY.into(), "1".into());
+        program.args = vec!["cat".into()];
+        wrap_program(&mut program, &task_wrapper());
+        assert!(!program.env.contains_key(&BString::from(HQ_STDIN_ENTRY)));
+        assert_eq!(program.args[5..], ["--stdin-entry", "--", "cat"]);
+    }
+
     fn program_def(
         cwd: &str,
         stdout: Option<&str>,
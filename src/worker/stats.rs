@@ -0,0 +1,340 @@
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::Utc;
+use tokio::sync::mpsc;
+
+use crate::common::serverdir::AccessRecord;
+use crate::transfer::auth::AccessRole;
+use crate::transfer::connection::ClientConnection;
+use crate::transfer::messages::{
+    FromClientMessage, TaskFinishMessage, TaskFinishRecord, ToClientMessage, WorkerStats,
+    WorkerStatsMessage,
+};
+use crate::worker::taskwrapper::AllocatedCpus;
+use crate::WorkerId;
+
+/// Cumulative CPU times of a single core (in clock ticks)
+#[derive(Debug, Default, Clone, PartialEq)]
+struct CpuTimes {
+    busy: u64,
+    total: u64,
+}
+
+fn parse_cpu_times(content: &str) -> anyhow::Result<Vec<CpuTimes>> {
+    let mut result = Vec::new();
+    for line in content.lines() {
+        // Skip the aggregated "cpu" line, take only "cpuN" lines
+        let is_core = line.starts_with("cpu")
+            && line[3..]
+                .chars()
+                .next()
+                .map(|c| c.is_ascii_digit())
+                .unwrap_or(false);
+        if !is_core {
+            continue;
+        }
+        let values: Vec<u64> = line
+            .split_whitespace()
+            .skip(1)
+            .take(8)
+            .map(|v| v.parse())
+            .collect::<Result<_, _>>()?;
+        if values.len() < 4 {
+            anyhow::bail!("Invalid format of cpu line: {}", line);
+        }
+        // user, nice, system, idle, iowait, irq, softirq, steal
+        let idle = values[3] + values.get(4).copied().unwrap_or(0);
+        let total: u64 = values.iter().sum();
+        result.push(CpuTimes {
+            busy: total - idle,
+            total,
+        });
+    }
+    Ok(result)
+}
+
+/// Returns the value of a `<key> <value> kB` line (as found in /proc/meminfo or
+/// /proc/<pid>/status) in bytes
+pub fn parse_proc_kb_value(content: &str, key: &str) -> anyhow::Result<u64> {
+    let line = content
+        .lines()
+        .find(|line| line.starts_with(key))
+        .ok_or_else(|| anyhow!("Item {} not found", key))?;
+    let value: u64 = line[key.len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()?;
+    Ok(value * 1024)
+}
+
+/// Returns (total, used) memory in bytes
+fn parse_meminfo(content: &str) -> anyhow::Result<(u64, u64)> {
+    let total = parse_proc_kb_value(content, "MemTotal:")?;
+    let available = parse_proc_kb_value(content, "MemAvailable:")?;
+    Ok((total, total.saturating_sub(available)))
+}
+
+fn parse_loadavg(content: &str) -> anyhow::Result<[f32; 3]> {
+    let values: Vec<f32> = content
+        .split_whitespace()
+        .take(3)
+        .map(|v| v.parse())
+        .collect::<Result<_, _>>()?;
+    if values.len() != 3 {
+        anyhow::bail!("Invalid format of loadavg");
+    }
+    Ok([values[0], values[1], values[2]])
+}
+
+fn read_cpu_times() -> anyhow::Result<Vec<CpuTimes>> {
+    parse_cpu_times(&std::fs::read_to_string("/proc/stat")?)
+}
+
+/// Samples utilization of the node on which the worker runs.
+/// CPU usage is computed from the difference against the previous sample.
+struct StatsCollector {
+    last_cpu_times: Vec<CpuTimes>,
+}
+
+impl StatsCollector {
+    fn new() -> Self {
+        StatsCollector {
+            last_cpu_times: read_cpu_times().unwrap_or_default(),
+        }
+    }
+
+    fn collect(&mut self, allocated_cpus: u32) -> anyhow::Result<WorkerStats> {
+        let cpu_times = read_cpu_times()?;
+        let cpu_usage = cpu_times
+            .iter()
+            .enumerate()
+            .map(|(i, now)| {
+                let last = self.last_cpu_times.get(i).cloned().unwrap_or_default();
+                let total = now.total.saturating_sub(last.total);
+                if total == 0 {
+                    0.0
+                } else {
+                    (now.busy.saturating_sub(last.busy) as f32 / total as f32) * 100.0
+                }
+            })
+            .collect();
+        self.last_cpu_times = cpu_times;
+
+        let (memory_total, memory_used) =
+            parse_meminfo(&std::fs::read_to_string("/proc/meminfo")?)?;
+        let load_average = parse_loadavg(&std::fs::read_to_string("/proc/loadavg")?)?;
+
+        Ok(WorkerStats {
+            timestamp: Utc::now(),
+            cpu_usage,
+            memory_total,
+            memory_used,
+            load_average,
+            allocated_cpus,
+        })
+    }
+}
+
+/// How long the worker tries to deliver stats or finished tasks to the server (including
+/// a reconnection), so that a stalled connection does not block further reports
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Loads the current access record of the server
+pub type RecordLoader = Rc<dyn Fn() -> anyhow::Result<AccessRecord>>;
+
+/// Client connection to the server that is opened again when it fails
+struct ServerConnection {
+    record: AccessRecord,
+    load_record: RecordLoader,
+    connection: Option<ClientConnection>,
+    worker_id: WorkerId,
+    /// Token that the worker has registered at the server
+    token: String,
+}
+
+impl ServerConnection {
+    async fn get(&mut self) -> anyhow::Result<&mut ClientConnection> {
+        if self.connection.is_none() {
+            // Keys of clients may have been rotated since the last connection
+            match (self.load_record)() {
+                Ok(record) => self.record = record,
+                Err(e) => log::debug!("Cannot reload the access record: {:?}", e),
+            }
+            self.connection = Some(
+                ClientConnection::connect_to_server_as(&self.record, AccessRole::Worker).await?,
+            );
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    async fn send(&mut self, message: FromClientMessage) -> anyhow::Result<()> {
+        let result = self.get().await?.send(message).await;
+        if result.is_err() {
+            self.connection = None;
+        }
+        result.map_err(|e| e.into())
+    }
+
+    async fn send_finished_tasks(&mut self, records: Vec<TaskFinishRecord>) -> anyhow::Result<()> {
+        let message = FromClientMessage::TaskFinish(TaskFinishMessage {
+            worker_id: self.worker_id,
+            token: self.token.clone(),
+            records,
+        });
+        let result = self.get().await?.send_and_receive(message).await;
+        match result {
+            Ok(ToClientMessage::TaskFinishResponse) => Ok(()),
+            // The records would be rejected again, so they are not kept for another attempt
+            Ok(ToClientMessage::Error(e)) => {
+                log::warn!("The server has rejected results of tasks: {}", e);
+                Ok(())
+            }
+            Ok(msg) => {
+                self.connection = None;
+                Err(anyhow!("Received an invalid message {:?}", msg))
+            }
+            Err(e) => {
+                self.connection = None;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Periodically sends utilization of the node to the server and forwards results of tasks
+/// received from task wrappers as soon as they arrive. Results that cannot be delivered
+/// are kept and sent again with the next attempt.
+pub async fn report_worker_stats(
+    record: AccessRecord,
+    load_record: RecordLoader,
+    worker_id: WorkerId,
+    token: String,
+    period: Duration,
+    mut finished_tasks: mpsc::UnboundedReceiver<TaskFinishRecord>,
+    allocated_cpus: AllocatedCpus,
+) {
+    let mut collector = StatsCollector::new();
+    let mut connection = ServerConnection {
+        record,
+        load_record,
+        connection: None,
+        worker_id,
+        token,
+    };
+    let mut pending: Vec<TaskFinishRecord> = Vec::new();
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match collector.collect(allocated_cpus.get()) {
+                    Ok(stats) => {
+                        let message = FromClientMessage::WorkerStats(WorkerStatsMessage {
+                            worker_id,
+                            token: connection.token.clone(),
+                            stats,
+                        });
+                        match tokio::time::timeout(SEND_TIMEOUT, connection.send(message)).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => log::debug!("Sending worker stats failed: {:?}", e),
+                            Err(_) => {
+                                log::debug!("Sending worker stats timed out");
+                                connection.connection = None;
+                            }
+                        }
+                    }
+                    Err(e) => log::debug!("Cannot collect worker stats: {}", e),
+                }
+            }
+            task = finished_tasks.recv() => match task {
+                Some(record) => {
+                    pending.push(record);
+                    while let Ok(record) = finished_tasks.try_recv() {
+                        pending.push(record);
+                    }
+                }
+                None => return,
+            }
+        }
+
+        if !pending.is_empty() {
+            let result = tokio::time::timeout(
+                SEND_TIMEOUT,
+                connection.send_finished_tasks(pending.clone()),
+            )
+            .await;
+            match result {
+                Ok(Ok(())) => pending.clear(),
+                Ok(Err(e)) => log::warn!("Sending results of tasks failed: {:?}", e),
+                Err(_) => {
+                    log::warn!("Sending results of tasks timed out");
+                    connection.connection = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpu_times, parse_loadavg, parse_meminfo, parse_proc_kb_value, CpuTimes};
+
+    #[test]
+    fn test_parse_cpu_times() {
+        let content = "cpu  100 0 100 800 0 0 0 0 0 0
+cpu0 10 0 20 70 5 0 0 0 0 0
+cpu1 90 0 80 730 0 0 0 0 0 0
+intr 1234 0 0
+ctxt 1234
+";
+        assert_eq!(
+            parse_cpu_times(content).unwrap(),
+            vec![
+                CpuTimes {
+                    busy: 30,
+                    total: 105
+                },
+                CpuTimes {
+                    busy: 170,
+                    total: 900
+                }
+            ]
+        );
+        assert!(parse_cpu_times("cpu0 x 1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_meminfo() {
+        let content = "MemTotal:       16000 kB
+MemFree:         2000 kB
+MemAvailable:    4000 kB
+Buffers:          100 kB
+";
+        assert_eq!(
+            parse_meminfo(content).unwrap(),
+            (16000 * 1024, 12000 * 1024)
+        );
+        assert!(parse_meminfo("MemTotal: 16000 kB").is_err());
+    }
+
+    #[test]
+    fn test_parse_proc_kb_value() {
+        let content = "Name:\thq\nVmPeak:\t  20000 kB\nVmRSS:\t   1500 kB\n";
+        assert_eq!(parse_proc_kb_value(content, "VmRSS:").unwrap(), 1500 * 1024);
+        assert!(parse_proc_kb_value(content, "VmSwap:").is_err());
+    }
+
+    #[test]
+    fn test_parse_loadavg() {
+        assert_eq!(
+            parse_loadavg("0.52 1.00 1.50 2/1234 5678\n").unwrap(),
+            [0.52, 1.0, 1.5]
+        );
+        assert!(parse_loadavg("0.52").is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use std::cell::Cell;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Clap;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncBufReadExt;
+use tokio::net::{UnixListener, UnixStream as AsyncUnixStream};
+use tokio::sync::mpsc;
+
+use crate::common::env::{HQ_CPUS, HQ_ENTRY, HQ_JOB_ID, HQ_TASK_ID};
+use crate::transfer::messages::{TaskFinishRecord, TaskResourceUsage};
+use crate::{JobId, JobTaskId};
+
+/// How long a wrapper tries to pass a message to the worker before it gives up reporting
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay after a failed accept, e.g. when the worker has run out of file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Internal command that runs a program of a task and reports its resource usage.
+/// It is prepended to the command of each task by the worker.
+#[derive(Clap)]
+#[clap(setting = clap::AppSettings::TrailingVarArg)]
+pub struct RunTaskOpts {
+    /// Socket of the worker that receives the result of the task
+    #[clap(long)]
+    socket: PathBuf,
+
+    /// Start the program only with the variables given by `--keep-env`
+    #[clap(long)]
+    clean_env: bool,
+
+    /// Variable that is preserved by `--clean-env`
+    #[clap(long, multiple_occurrences(true), parse(from_os_str))]
+    keep_env: Vec<OsString>,
+
+    /// File that is passed to the standard input of the program
+    #[clap(long, parse(from_os_str), conflicts_with("stdin-entry"))]
+    stdin: Option<PathBuf>,
+
+    /// Pass the value of `HQ_ENTRY` to the standard input of the program instead of the variable
+    #[clap(long)]
+    stdin_entry: bool,
+
+    #[clap(required = true, parse(from_os_str))]
+    program: Vec<OsString>,
+}
+
+/// Lines sent by a task wrapper to the worker
+#[derive(Serialize, Deserialize)]
+enum WrapperMessage {
+    /// The task has started on the given number of CPUs
+    Start { n_cpus: u32 },
+    /// The program of the task has ended or it could not be started
+    Finish(TaskFinishRecord),
+}
+
+fn send_message(socket: &mut UnixStream, message: &WrapperMessage) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    socket.write_all(&line)?;
+    Ok(())
+}
+
+/// Connection to the worker that receives the result of the task. Reporting is best-effort,
+/// a task never fails just because its result cannot be passed to the worker.
+struct WorkerReporter {
+    connection: Option<(UnixStream, JobId, JobTaskId)>,
+}
+
+impl WorkerReporter {
+    fn connect(socket: &Path) -> WorkerReporter {
+        let connect = || -> anyhow::Result<_> {
+            let job_id = std::env::var(HQ_JOB_ID)?.parse()?;
+            let task_id = std::env::var(HQ_TASK_ID)?.parse()?;
+            let stream = UnixStream::connect(socket)
+                .with_context(|| format!("Cannot connect to the worker socket {:?}", socket))?;
+            stream.set_write_timeout(Some(REPORT_TIMEOUT))?;
+            Ok((stream, job_id, task_id))
+        };
+        match connect() {
+            Ok(connection) => WorkerReporter {
+                connection: Some(connection),
+            },
+            Err(e) => {
+                log::warn!("Resource usage of the task will not be reported: {:#}", e);
+                WorkerReporter { connection: None }
+            }
+        }
+    }
+
+    fn send(&mut self, message: WrapperMessage) {
+        if let Some((socket, _, _)) = &mut self.connection {
+            if let Err(e) = send_message(socket, &message) {
+                log::warn!("Cannot send the state of the task to the worker: {:#}", e);
+                self.connection = None;
+            }
+        }
+    }
+
+    /// The record does not wait for any confirmation, the worker reads it even after
+    /// the wrapper has closed the connection
+    fn finish(mut self, usage: Option<TaskResourceUsage>, error: Option<String>) {
+        if let Some((_, job_id, task_id)) = self.connection {
+            self.send(WrapperMessage::Finish(TaskFinishRecord {
+                job_id,
+                task_id,
+                usage,
+                error,
+            }));
+        }
+    }
+}
+
+/// Runs the program and returns its exit code.
+/// The worker is notified when the program ends or when it cannot be started.
+pub fn run_task_wrapper(opts: RunTaskOpts) -> anyhow::Result<i32> {
+    let mut reporter = WorkerReporter::connect(&opts.socket);
+    // CPUs stay allocated to the task until the connection is closed
+    let n_cpus = std::env::var(HQ_CPUS)
+        .map(|cpus| cpus.split(',').filter(|id| !id.is_empty()).count() as u32)
+        .unwrap_or(0);
+    reporter.send(WrapperMessage::Start { n_cpus });
+
+    let (mut child, entry) = match spawn_program(&opts) {
+        Ok(result) => result,
+        Err(e) => {
+            reporter.finish(None, Some(format!("{:#}", e)));
+            return Err(e);
+        }
+    };
+    if let Some(entry) = entry {
+        let mut stdin = child.stdin.take().unwrap();
+        // The program does not have to read the whole entry, so a failed write is not an error
+        std::thread::spawn(move || stdin.write_all(&entry));
+    }
+    let status = child.wait()?;
+
+    let usage = match get_children_usage() {
+        Ok(usage) => Some(usage),
+        Err(e) => {
+            log::warn!("Cannot measure resource usage of the task: {}", e);
+            None
+        }
+    };
+    reporter.finish(usage, None);
+
+    Ok(status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+}
+
+/// Starts the program, returns it together with the entry that should be written to its stdin
+fn spawn_program(opts: &RunTaskOpts) -> anyhow::Result<(Child, Option<Vec<u8>>)> {
+    let mut command = Command::new(&opts.program[0]);
+    command.args(&opts.program[1..]);
+    if opts.clean_env {
+        let kept: Vec<(OsString, OsString)> = opts
+            .keep_env
+            .iter()
+            .filter_map(|key| std::env::var_os(key).map(|value| (key.clone(), value)))
+            .collect();
+        command.env_clear();
+        command.envs(kept);
+    }
+    unsafe {
+        // The program is killed together with the wrapper (e.g. when the task is canceled)
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    if let Some(path) = &opts.stdin {
+        let file = File::open(path)
+            .with_context(|| format!("Cannot open standard input file {:?}", path))?;
+        command.stdin(file);
+    }
+    let entry = if opts.stdin_entry {
+        command.env_remove(HQ_ENTRY);
+        command.stdin(Stdio::piped());
+        let mut entry = std::env::var_os(HQ_ENTRY).unwrap_or_default().into_vec();
+        entry.push(b'\n');
+        Some(entry)
+    } else {
+        None
+    };
+
+    let child = command
+        .spawn()
+        .with_context(|| format!("Cannot start program {:?}", opts.program[0]))?;
+    Ok((child, entry))
+}
+
+fn timeval_to_duration(time: &libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+/// The wrapper has exactly one child, so the usage of children is the usage of the task
+fn get_children_usage() -> std::io::Result<TaskResourceUsage> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(TaskResourceUsage {
+        user_time: timeval_to_duration(&usage.ru_utime),
+        system_time: timeval_to_duration(&usage.ru_stime),
+        // Linux reports maxrss in kilobytes
+        max_rss: usage.ru_maxrss as u64 * 1024,
+        input_blocks: usage.ru_inblock as u64,
+        output_blocks: usage.ru_oublock as u64,
+    })
+}
+
+/// Number of CPUs allocated to tasks that are running on the worker
+#[derive(Clone, Default)]
+pub struct AllocatedCpus(Rc<Cell<u32>>);
+
+impl AllocatedCpus {
+    pub fn get(&self) -> u32 {
+        self.0.get()
+    }
+
+    fn allocate(&self, n_cpus: u32) -> CpuAllocation {
+        self.0.set(self.0.get() + n_cpus);
+        CpuAllocation {
+            cpus: self.clone(),
+            n_cpus,
+        }
+    }
+}
+
+/// CPUs of a single task, released when the allocation is dropped
+struct CpuAllocation {
+    cpus: AllocatedCpus,
+    n_cpus: u32,
+}
+
+impl Drop for CpuAllocation {
+    fn drop(&mut self) {
+        let cpus = &self.cpus.0;
+        cpus.set(cpus.get().saturating_sub(self.n_cpus));
+    }
+}
+
+/// Accepts connections of task wrappers, passes their finish records to `sender`
+/// and counts CPUs of connected wrappers in `allocated_cpus`
+pub async fn serve_task_wrappers(
+    listener: UnixListener,
+    sender: mpsc::UnboundedSender<TaskFinishRecord>,
+    allocated_cpus: AllocatedCpus,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let sender = sender.clone();
+                let allocated_cpus = allocated_cpus.clone();
+                tokio::task::spawn_local(async move {
+                    if let Err(e) = handle_task_wrapper(stream, sender, allocated_cpus).await {
+                        log::debug!("Connection of a task wrapper failed: {:?}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                log::warn!("Cannot accept a connection of a task wrapper: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// The connection is closed without a record when the wrapper is killed,
+/// e.g. when the task is canceled
+async fn handle_task_wrapper(
+    stream: AsyncUnixStream,
+    sender: mpsc::UnboundedSender<TaskFinishRecord>,
+    allocated_cpus: AllocatedCpus,
+) -> anyhow::Result<()> {
+    let mut lines = tokio::io::BufReader::new(stream).lines();
+    // Released when the connection ends
+    let mut _allocation = None;
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(&line)? {
+            WrapperMessage::Start { n_cpus } => {
+                _allocation = Some(allocated_cpus.allocate(n_cpus));
+            }
+            WrapperMessage::Finish(record) => sender
+                .send(record)
+                .map_err(|_| anyhow::anyhow!("The worker is not receiving finished tasks"))?,
+        }
+    }
+    Ok(())
+}
@@ -153,16 +153,23 @@ class HqEnv(Env):
     def kill_worker(self, worker_id):
         self.kill_process(f"worker{worker_id}")
 
-    def command(self, args, as_table=False, cwd=None):
+    def command(self, args, as_table=False, cwd=None, env=None, server_dir=None):
         if isinstance(args, str):
             args = [args]
         else:
             args = list(args)
 
-        args = [HQ_BINARY, "--server-dir", self.server_dir] + args
+        args = [HQ_BINARY, "--server-dir", server_dir or self.server_dir] + args
         try:
+            command_env = None
+            if env:
+                command_env = os.environ.copy()
+                command_env.update(env)
             output = subprocess.check_output(
-                args, stderr=subprocess.STDOUT, cwd=cwd or self.work_path
+                args,
+                stderr=subprocess.STDOUT,
+                cwd=cwd or self.work_path,
+                env=command_env,
             )
             output = output.decode()
             if as_table:
@@ -147,6 +147,31 @@ def test_job_array_cancel(hq_env: HqEnv):
     assert table[1][2] == "CANCELED"
 
 
+def test_job_array_cancel_tasks(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--array=0-9", "--", "sleep", "1"])
+    wait_for_job_state(hq_env, 1, "WAITING")
+
+    r = hq_env.command(["cancel", "1", "--tasks", "2-4,8"])
+    assert "Job 1: tasks 2-4,8 canceled" in r
+
+    r = hq_env.command(["cancel", "1", "--tasks", "3-5"])
+    assert "Job 1: tasks 5 canceled" in r
+    assert "Job 1: tasks 3-4 are already canceled" in r
+
+    table = hq_env.command(["jobs"], as_table=True)
+    assert table[1][2] == "WAITING"
+
+    hq_env.start_worker(cpus=4)
+    wait_for_job_state(hq_env, 1, "CANCELED")
+
+    table = hq_env.command(["job", "1", "--tasks"], as_table=True)
+    states = {int(row[0]): row[1] for row in table[9:]}
+    for task_id in range(10):
+        expected = "CANCELED" if task_id in (2, 3, 4, 5, 8) else "FINISHED"
+        assert states[task_id] == expected
+
+
 def test_array_reporting_state_after_worker_lost(hq_env: HqEnv):
     hq_env.start_server()
     hq_env.start_workers(1, cpus=2)
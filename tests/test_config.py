@@ -0,0 +1,90 @@
+import os
+import time
+
+import pytest
+
+from .conftest import HqEnv
+from .utils import wait_for_job_state, wait_for_worker_state
+
+
+def write_config(tmp_path, content):
+    path = os.path.join(tmp_path, "config.toml")
+    with open(path, "w") as f:
+        f.write(content)
+    return {"HQ_CONFIG": path}
+
+
+def test_config_show_defaults(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    env = write_config(tmp_path, "")
+    output = hq_env.command(["config", "show"], env=env)
+    assert 'heartbeat = "8s"' in output
+    assert 'cpus = "1"' in output
+    assert 'stdout = "stdout.%{JOB_ID}.%{TASK_ID}"' in output
+
+
+def test_config_show_merged(hq_env: HqEnv, tmp_path):
+    env = write_config(
+        tmp_path,
+        """
+[worker]
+heartbeat = "2s"
+
+[submit]
+cpus = "2"
+""",
+    )
+    hq_env.start_server()
+    output = hq_env.command(["--colors", "never", "config", "show"], env=env)
+    assert 'heartbeat = "2s"' in output
+    assert 'cpus = "2"' in output
+    assert 'colors = "never"' in output
+    assert 'manager = "detect"' in output
+
+
+def test_config_invalid(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    env = write_config(tmp_path, "[worker]\nheartbeet = 1\n")
+    with pytest.raises(Exception, match="Invalid configuration file"):
+        hq_env.command(["config", "show"], env=env)
+
+    env = write_config(tmp_path, '[submit]\ncpus = "x"\n')
+    with pytest.raises(Exception, match="submit.cpus"):
+        hq_env.command(["submit", "hostname"], env=env)
+
+
+def test_config_submit_defaults(hq_env: HqEnv, tmp_path):
+    env = write_config(
+        tmp_path,
+        """
+[submit]
+cpus = "2"
+stdout = "out.%{JOB_ID}"
+stderr = "none"
+""",
+    )
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+    hq_env.command(["submit", "--", "echo", "hello"], env=env)
+    hq_env.command(["submit", "--cpus", "1", "--", "echo", "hello"], env=env)
+    wait_for_job_state(hq_env, [1, 2], "FINISHED")
+
+    table = hq_env.command(["job", "1"], as_table=True)
+    assert table[4][1] == "2 compact"
+    table = hq_env.command(["job", "2"], as_table=True)
+    assert table[4][1] == "1 compact"
+
+    with open(os.path.join(hq_env.work_path, "out.1")) as f:
+        assert f.read() == "hello\n"
+    assert not os.path.exists(os.path.join(hq_env.work_path, "stderr.1.0"))
+
+
+def test_config_worker_reconnect_override(hq_env: HqEnv, tmp_path):
+    env = write_config(tmp_path, "[worker]\nreconnect = true\n")
+    hq_env.start_server()
+    process = hq_env.start_worker(env=env, args=["--reconnect=false"])
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+
+    hq_env.kill_process("server")
+    time.sleep(1.0)
+    hq_env.check_process_exited(process, expected_code=None)
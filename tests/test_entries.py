@@ -1,7 +1,9 @@
 import os
 
+import pytest
+
 from .conftest import HqEnv
-from .utils import wait_for_job_state
+from .utils import wait_for_job_state, wait_until
 
 
 def test_entries_no_newline(hq_env: HqEnv):
@@ -60,3 +62,102 @@ def test_entries_with_newline(hq_env: HqEnv):
 
     table = hq_env.command(["job", "1"], as_table=True)
     assert table[3][1] == "FINISHED (4)"
+
+
+def test_entries_stdin(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+
+    with open("input", "w") as f:
+        f.write("One\nTwo\n")
+
+    hq_env.command(
+        [
+            "submit",
+            "--each-line=input",
+            "--stdin-from-entry",
+            "--",
+            "bash",
+            "-c",
+            "read line; echo $line ${HQ_ENTRY:-unset}",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    for i, test in enumerate(["One unset\n", "Two unset\n"]):
+        with open(f"stdout.1.{i}") as f:
+            assert f.read() == test
+
+    with pytest.raises(Exception):
+        hq_env.command(["submit", "--stdin-from-entry", "--", "cat"])
+
+
+def test_submit_stdin_file(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+
+    for i in range(1, 3):
+        with open(f"in.{i}", "w") as f:
+            f.write(f"input {i}\n")
+
+    hq_env.command(["submit", "--array=1-2", "--stdin=in.%{TASK_ID}", "--", "cat"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    for i in range(1, 3):
+        with open(f"stdout.1.{i}") as f:
+            assert f.read() == f"input {i}\n"
+
+
+def test_submit_stdin_marker_is_removed(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker()
+
+    with open("input", "w") as f:
+        f.write("input\n")
+
+    hq_env.command(["submit", "--stdin=input", "--", "env"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    with open("stdout.1.0") as f:
+        env = f.read()
+    assert "HQ_JOB_ID=" in env
+    assert "HQ_STDIN" not in env
+
+
+def test_submit_stdin_missing_file(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker()
+
+    hq_env.command(["submit", "--stdin=missing", "--", "cat"])
+    wait_for_job_state(hq_env, 1, "FAILED")
+
+    # The error of the wrapper may arrive shortly after the failure of the task
+    wait_until(
+        lambda: "Cannot open standard input file"
+        in hq_env.command(["job", "1"], as_table=True)[11][1]
+    )
+    table = hq_env.command(["job", "1"], as_table=True)
+    assert table[11][0] == "0"
+    assert "No such file or directory" in table[11][1]
+
+
+def test_entries_large_array_cancel(hq_env: HqEnv):
+    hq_env.start_server()
+
+    with open("input", "w") as f:
+        for i in range(300_000):
+            f.write(f"entry-{i}\n")
+
+    hq_env.command(["submit", "--each-line=input", "--", "bash", "-c", "echo $HQ_ENTRY"])
+    table = hq_env.command(["job", "1"], as_table=True)
+    assert table[3][1] == "WAITING (300000)"
+
+    # Tasks that were not submitted to the scheduler yet are canceled as well
+    hq_env.command(["cancel", "1", "--tasks", "250000-250009"])
+    table = hq_env.command(["job", "1"], as_table=True)
+    states = [row[1] for row in table if len(row) > 1]
+    assert "CANCELED (10)" in states
+    assert "WAITING (299990)" in states
+
+    hq_env.command(["cancel", "1"])
+    wait_for_job_state(hq_env, 1, "CANCELED")
@@ -4,7 +4,7 @@ import time
 import pytest
 
 from .conftest import HqEnv
-from .utils import wait_for_job_state
+from .utils import wait_for_job_state, wait_until
 
 
 def test_job_submit(hq_env: HqEnv):
@@ -212,12 +212,15 @@ def test_job_fail(hq_env: HqEnv):
     assert len(table) == 2
     assert table[1][:3] == ["1", "non-existent-program", "FAILED"]
 
+    # The error of the wrapper may arrive shortly after the failure of the task
+    wait_until(
+        lambda: "No such file or directory"
+        in hq_env.command(["job", "1"], as_table=True)[11][1]
+    )
     table = hq_env.command(["job", "1"], as_table=True)
     assert table[0] == ["Id", "1"]
     assert table[2] == ["State", "FAILED"]
-
     assert table[11][0] == "0"
-    assert "No such file or directory" in table[11][1]
 
 
 def test_job_invalid(hq_env: HqEnv):
@@ -254,7 +257,7 @@ def test_cancel_running(hq_env: HqEnv):
     assert table[1][2] == "CANCELED"
 
     r = hq_env.command(["cancel", "1"])
-    assert "Canceling job 1 failed" in r
+    assert "Canceling job 1 failed; job is already canceled" in r
 
 
 def test_cancel_finished(hq_env: HqEnv):
@@ -468,3 +471,287 @@ def test_job_last(hq_env: HqEnv):
 
     table = hq_env.command(["job", "last"], as_table=True)
     assert table[0][1] == "2"
+
+
+def test_job_resource_usage(hq_env: HqEnv):
+    hq_env.start_server()
+    # Usage is sent when a task finishes, independently of the heartbeat
+    hq_env.start_worker(args=["--heartbeat", "30s"])
+    hq_env.command(
+        [
+            "submit",
+            "--array=1-4",
+            "--",
+            "python3",
+            "-c",
+            "x = b'x' * (100 * 1024 * 1024)",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    def get_task_rows():
+        table = hq_env.command(["job", "1", "--tasks"], as_table=True)
+        header = [i for (i, row) in enumerate(table) if row and row[0] == "Task Id"][0]
+        return table[header:]
+
+    # Usage may arrive shortly after the end of tasks
+    wait_until(
+        lambda: all(row[4].endswith("MiB") for row in get_task_rows()[1:])
+    )
+    rows = get_task_rows()
+    assert rows[0][3:] == ["CPU time (user / sys)", "Max RSS", "I/O blocks (in / out)"]
+    assert len(rows) == 5
+
+    table = hq_env.command(["job", "1", "--summary"], as_table=True)
+    assert table[-5] == ["Tasks with usage", "4"]
+    assert table[-4][0] == "CPU time (total)"
+    assert table[-3][0] == "Max RSS (mean)"
+    assert table[-3][1].endswith("MiB")
+    assert table[-2][0] == "Max RSS (max)"
+    assert table[-1][0] == "I/O blocks (in / out)"
+
+
+def test_job_selectors(hq_env: HqEnv):
+    hq_env.start_server()
+    for name in ["prep1", "prep2", "compute", "prep3", "compute"]:
+        hq_env.command(["submit", "--name", name, "--", "hostname"])
+
+    def selected_ids(selector):
+        table = hq_env.command(["jobs", "--select", selector], as_table=True)
+        return [int(row[0]) for row in table[1:]]
+
+    assert selected_ids("2") == [2]
+    assert selected_ids("2-4") == [2, 3, 4]
+    assert selected_ids("1,3-4") == [1, 3, 4]
+    assert selected_ids("3-100") == [3, 4, 5]
+    assert selected_ids("last") == [5]
+    assert selected_ids("last2") == [4, 5]
+    assert selected_ids("all") == [1, 2, 3, 4, 5]
+    assert selected_ids("name:prep*") == [1, 2, 4]
+    assert selected_ids("name:comp?te") == [3, 5]
+
+    r = hq_env.command(["cancel", "name:prep*"])
+    assert "Job 1 canceled" in r
+    assert "Job 2 canceled" in r
+    assert "Job 4 canceled" in r
+    table = hq_env.command(["jobs", "--select", "2-3"], as_table=True)
+    assert table[1][2] == "CANCELED"
+    assert table[2][2] == "WAITING"
+
+    result = hq_env.command(["job", "6"])
+    assert "Job 6 not found" in result
+
+    with pytest.raises(Exception):
+        hq_env.command(["job", "last0"])
+
+
+def test_submit_progress(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+
+    output = hq_env.command(["submit", "--array=1-4", "--progress", "--", "sleep", "0.2"])
+    assert "4/4 finished" in output
+    table = hq_env.command(["jobs"], as_table=True)
+    assert table[1][2] == "FINISHED"
+
+    with pytest.raises(Exception, match="Job 2 failed"):
+        hq_env.command(
+            [
+                "submit",
+                "--array=1-3",
+                "--progress",
+                "--",
+                "bash",
+                "-c",
+                "exit $((HQ_TASK_ID == 2))",
+            ]
+        )
+
+
+def test_job_follow(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(["submit", "--", "sleep", "0.5"])
+    hq_env.start_worker(cpus=1)
+
+    output = hq_env.command(["job", "last", "--follow"])
+    assert "1/1 finished" in output
+
+    with pytest.raises(Exception, match="No jobs were found"):
+        hq_env.command(["job", "5", "--follow"])
+
+
+def test_submit_export_env(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.start_worker(env={"WORKER_VAR": "w", "SUB_B": "worker"})
+
+    env_file = tmp_path / "vars.env"
+    env_file.write_text("# comment\nexport FILE_VAR=file\nSUB_B='from file'\n")
+
+    submit_env = {"SUB_A": "a", "SUB_B": "b", "OTHER": "o"}
+    command = ["bash", "-c", "echo ${WORKER_VAR:-unset} $SUB_A $SUB_B $FILE_VAR ${OTHER:-unset}"]
+    hq_env.command(
+        [
+            "submit",
+            "--export-env=SUB_*",
+            f"--env-file={env_file}",
+            "--env=FILE_VAR=cli",
+            "--",
+            *command,
+        ],
+        env=submit_env,
+    )
+    hq_env.command(
+        ["submit", "--clean-env", "--export-env=SUB_A", "--", *command],
+        env=submit_env,
+    )
+    wait_for_job_state(hq_env, [1, 2], "FINISHED")
+
+    with open(os.path.join(hq_env.work_path, "stdout.1.0")) as f:
+        assert f.read().strip() == "w a from file cli unset"
+    with open(os.path.join(hq_env.work_path, "stdout.2.0")) as f:
+        assert f.read().strip() == "unset a unset"
+
+
+def test_submit_clean_env_marker_is_removed(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker()
+
+    hq_env.command(["submit", "--clean-env", "--", "env"])
+    hq_env.command(["submit", "--", "env"])
+    wait_for_job_state(hq_env, [1, 2], "FINISHED")
+
+    for job_id in (1, 2):
+        with open(os.path.join(hq_env.work_path, f"stdout.{job_id}.0")) as f:
+            env = f.read()
+        assert "HQ_JOB_ID=" in env
+        assert "HQ_CLEAN_ENV" not in env
+
+
+def test_extended_placeholders(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker()
+
+    hq_env.command(
+        [
+            "submit",
+            "--name=pl",
+            "--array=7-7",
+            "--env=VAR=value",
+            "--stdout=%{JOB_NAME}-%{TASK_ID:03}.out",
+            "--",
+            "bash",
+            "-c",
+            "echo %{JOB_ID:02} %{ENV:VAR} %{WORKER_ID} %{HOSTNAME}",
+        ]
+    )
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    with open(os.path.join(hq_env.work_path, "pl-007.out")) as f:
+        parts = f.read().strip().split(" ")
+    assert parts[:3] == ["01", "value", "1"]
+    assert parts[3]
+
+    hq_env.command(["submit", "--", "echo", "%%{TASK_ID} %{TASK_ID}"])
+    wait_for_job_state(hq_env, 2, "FINISHED")
+    with open(os.path.join(hq_env.work_path, "stdout.2.0")) as f:
+        assert f.read() == "%{TASK_ID} 0\n"
+
+    for args in (
+        ["--stdout=%{FOO}.out", "--", "hostname"],
+        ["--cwd=%{CWD}", "--", "hostname"],
+        ["--", "echo", "%{ENTRY}"],
+        ["--", "echo", "%{TASK_ID:5}"],
+    ):
+        with pytest.raises(Exception):
+            hq_env.command(["submit", *args])
+
+
+def read_hook_output(path):
+    def read():
+        if os.path.isfile(path):
+            with open(path) as f:
+                content = f.read()
+            if content.endswith("\n"):
+                return content
+        return None
+
+    return wait_until(read)
+
+
+HOOK_COMMAND = (
+    'echo "$HQ_JOB_ID $HQ_JOB_NAME $HQ_JOB_STATUS $HQ_TASK_COUNT '
+    '$HQ_FINISHED_TASKS $HQ_FAILED_TASKS $HQ_CANCELED_TASKS" > {}'
+)
+
+
+def test_job_finish_hook(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker()
+    hq_env.command(
+        [
+            "submit",
+            "--array=1-3",
+            "--name=hooked",
+            "--on-finish",
+            HOOK_COMMAND.format("hook-$HQ_JOB_ID.txt"),
+            "--",
+            "bash",
+            "-c",
+            "test $HQ_TASK_ID != 2",
+        ]
+    )
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, [1, 2], ["FAILED", "FINISHED"])
+
+    assert read_hook_output("hook-1.txt") == "1 hooked FAILED 3 2 1 0\n"
+    time.sleep(0.2)
+    assert not os.path.exists("hook-2.txt")
+
+
+def test_job_finish_hook_canceled(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        ["submit", "--on-finish", HOOK_COMMAND.format("hook.txt"), "--", "hostname"]
+    )
+    hq_env.command(["cancel", "1"])
+    assert read_hook_output("hook.txt") == "1 hostname CANCELED 1 0 0 1\n"
+
+
+def test_server_job_finish_hook(hq_env: HqEnv):
+    hq_env.start_server(args=["--on-job-finish", HOOK_COMMAND.format("server-hook.txt")])
+    hq_env.start_worker()
+    hq_env.command(["submit", "--", "hostname"])
+    assert read_hook_output("server-hook.txt") == "1 hostname FINISHED 1 1 0 0\n"
+
+
+def test_job_finish_hook_output(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.command(
+        [
+            "submit",
+            "--on-finish",
+            "echo hook-output; head -c 100000 /dev/zero | tr '\\0' x; touch hook.txt",
+            "--",
+            "hostname",
+        ]
+    )
+    hq_env.command(["cancel", "1"])
+    wait_until(lambda: os.path.exists("hook.txt"))
+
+    def hook_logged():
+        return "[truncated]" in hq_env.command(["server", "logs"])
+
+    wait_until(hook_logged)
+    output = hq_env.command(["server", "logs"])
+    assert "hook-output" in output
+    assert "x" * 5000 not in output
+
+
+def test_job_finish_hook_requires_admin(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    submit_dir = str(tmp_path / "submit")
+    hq_env.command(["server", "export-access", "--role=submit", submit_dir])
+    with pytest.raises(Exception, match="admin role"):
+        hq_env.command(
+            ["submit", "--on-finish", "true", "--", "hostname"], server_dir=submit_dir
+        )
@@ -1,11 +1,16 @@
 import json
 import os
+import socket
+import stat
 import subprocess
+import time
+import urllib.error
+import urllib.request
 
 import pytest
 
-from .conftest import HqEnv
-from .utils import parse_table
+from .conftest import HQ_BINARY, HqEnv
+from .utils import parse_table, wait_for_job_state, wait_for_worker_state, wait_until
 
 
 def test_server_host(hq_env: HqEnv):
@@ -40,8 +45,449 @@ def test_version_mismatch(hq_env: HqEnv):
     with open(access_file, "w") as f:
         json.dump(data, f)
 
-    with pytest.raises(
-        Exception,
-        match=f"Server was started with version {version}.1, but the current version is {version}",
+    # Clients negotiate the protocol version with the server
+    hq_env.command("jobs", as_table=True)
+
+    # Workers require the same version as the server
+    process = hq_env.start_worker()
+    process.wait()
+    hq_env.check_process_exited(process, 1)
+    with open(os.path.join(hq_env.work_path, "worker1.out")) as f:
+        assert (
+            f"Server was started with version {version}.1, but the current version is {version}"
+            in f.read()
+        )
+
+
+def test_server_info(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(cpus=2)
+    hq_env.command(["submit", "--array=1-3", "--", "sleep", "0"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    table = hq_env.command(["server", "info"], as_table=True)
+    rows = dict((row[0], row[1]) for row in table)
+    assert rows["Host"]
+    assert rows["Jobs"] == "0 waiting, 0 running, 1 finished, 0 failed, 0 canceled"
+    assert rows["Tasks"] == "0 waiting, 0 running, 3 finished, 0 failed, 0 canceled"
+    assert rows["Workers"] == "1 online, 0 offline"
+    assert rows["CPUs"] == "0 used, 2 total"
+
+    data = json.loads(hq_env.command(["server", "info", "--json"]))
+    assert data["info"]["server_port"] == int(rows["HQ port"])
+    assert data["stats"]["tasks"]["finished"] == 3
+    assert data["stats"]["online_workers"] == 1
+    assert data["stats"]["total_cpus"] == 2
+
+
+def test_export_access(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.start_worker()
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    monitor_dir = str(tmp_path / "monitor")
+    submit_dir = str(tmp_path / "submit")
+    hq_env.command(["server", "export-access", monitor_dir])
+    hq_env.command(["server", "export-access", "--role=submit", submit_dir])
+
+    with open(os.path.join(monitor_dir, "access.json")) as f:
+        data = json.load(f)
+    assert "monitor_secret_key" in data
+    assert "submit_secret_key" not in data
+    assert "hq_secret_key" not in data
+    assert "tako_secret_key" not in data
+
+    table = hq_env.command("jobs", as_table=True, server_dir=monitor_dir)
+    assert table[1][:3] == ["1", "hostname", "FINISHED"]
+    hq_env.command(["server", "info"], server_dir=monitor_dir)
+    with pytest.raises(Exception, match="Permission denied"):
+        hq_env.command(["submit", "--", "hostname"], server_dir=monitor_dir)
+    with pytest.raises(Exception, match="admin role"):
+        hq_env.command(
+            ["server", "export-access", "--role=admin", str(tmp_path / "admin")],
+            server_dir=monitor_dir,
+        )
+
+    hq_env.command(["submit", "--", "hostname"], server_dir=submit_dir)
+    wait_for_job_state(hq_env, 2, "FINISHED")
+    with pytest.raises(Exception, match="Permission denied"):
+        hq_env.command(["worker", "stop", "1"], server_dir=submit_dir)
+    with pytest.raises(Exception, match="Permission denied"):
+        hq_env.command(["server", "stop"], server_dir=submit_dir)
+    hq_env.command(["server", "info"])
+    with pytest.raises(Exception, match="admin role"):
+        hq_env.command(["worker", "start"], server_dir=submit_dir)
+
+
+def test_server_export_access_worker(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    worker_dir = str(tmp_path / "worker")
+    hq_env.command(["server", "export-access", "--role=worker", worker_dir])
+
+    with open(os.path.join(worker_dir, "access.json")) as f:
+        data = json.load(f)
+    assert "worker_secret_key" in data
+    assert "tako_secret_key" in data
+    for key in ("hq_secret_key", "submit_secret_key", "monitor_secret_key"):
+        assert key not in data
+
+    hq_env.start_process(
+        "worker1",
+        [
+            HQ_BINARY,
+            "--server-dir",
+            worker_dir,
+            "worker",
+            "start",
+            "--heartbeat",
+            "500ms",
+        ],
+        env=hq_env.make_default_env(),
+    )
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    # Stats are sent with the key of the worker role
+    def has_stats():
+        table = hq_env.command(["worker", "list", "--stats"], as_table=True)
+        return table[1][6].startswith("CPU")
+
+    wait_until(has_stats)
+
+    with pytest.raises(Exception, match="Permission denied"):
+        hq_env.command("jobs", server_dir=worker_dir)
+
+
+def get_free_port():
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        return s.getsockname()[1]
+
+
+def test_server_fixed_ports(hq_env: HqEnv):
+    busy = socket.socket()
+    busy.bind(("127.0.0.1", 0))
+    busy.listen()
+    busy_port = busy.getsockname()[1]
+    client_port = get_free_port()
+    worker_port = get_free_port()
+
+    try:
+        hq_env.start_server(
+            args=[
+                "--bind",
+                "127.0.0.1",
+                f"--client-port={busy_port},{client_port}",
+                f"--worker-port={worker_port}",
+            ]
+        )
+        table = hq_env.command(["server", "info"], as_table=True)
+        rows = dict((row[0], row[1]) for row in table)
+        assert rows["Host"] == "127.0.0.1"
+        assert rows["HQ port"] == str(client_port)
+        assert rows["Workers port"] == str(worker_port)
+
+        hq_env.start_worker(
+            args=["--server-host", "localhost", "--server-port", str(worker_port)]
+        )
+        wait_for_worker_state(hq_env, 1, "RUNNING")
+    finally:
+        busy.close()
+
+
+def test_server_local_socket(hq_env: HqEnv):
+    hq_env.start_server()
+    socket_path = os.path.join(hq_env.server_dir, "hq-current", "local", "hq.sock")
+    assert stat.S_ISSOCK(os.stat(socket_path).st_mode)
+    assert stat.S_IMODE(os.stat(socket_path).st_mode) == 0o600
+    assert stat.S_IMODE(os.stat(os.path.dirname(socket_path)).st_mode) == 0o700
+
+    hq_env.start_worker()
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+    # Clients fall back to TCP when the socket is not available
+    os.unlink(socket_path)
+    table = hq_env.command("jobs", as_table=True)
+    assert table[1][:3] == ["1", "hostname", "FINISHED"]
+
+
+def test_server_rotate_keys(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.start_worker()
+
+    access_file = os.path.join(hq_env.server_dir, "hq-current", "access.json")
+    with open(access_file) as f:
+        old_record = json.load(f)
+
+    monitor_dir = str(tmp_path / "monitor")
+    hq_env.command(["server", "export-access", monitor_dir])
+    hq_env.command("jobs", server_dir=monitor_dir)
+
+    hq_env.command(["server", "rotate-keys"])
+
+    with open(access_file) as f:
+        new_record = json.load(f)
+    for key in (
+        "hq_secret_key",
+        "submit_secret_key",
+        "monitor_secret_key",
+        "worker_secret_key",
     ):
-        hq_env.command("jobs", as_table=True)
+        assert old_record[key] != new_record[key]
+    assert old_record["tako_secret_key"] == new_record["tako_secret_key"]
+
+    # Old keys are rejected
+    with pytest.raises(Exception):
+        hq_env.command("jobs", server_dir=monitor_dir)
+
+    # Connected workers keep working
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+
+def test_server_rotate_keys_workers(hq_env: HqEnv):
+    hq_env.start_server()
+    process = hq_env.start_worker()
+    hq_env.start_worker(args=["--reconnect"])
+    wait_for_worker_state(hq_env, [1, 2], "RUNNING")
+
+    access_file = os.path.join(hq_env.server_dir, "hq-current", "access.json")
+    with open(access_file) as f:
+        old_record = json.load(f)
+
+    hq_env.command(["server", "rotate-keys", "--workers"])
+
+    with open(access_file) as f:
+        new_record = json.load(f)
+    assert old_record["tako_secret_key"] != new_record["tako_secret_key"]
+
+    # Workers are disconnected, the worker with --reconnect connects with the new key
+    wait_for_worker_state(hq_env, [1, 2], "CONNECTION LOST")
+    wait_for_worker_state(hq_env, 3, "RUNNING", timeout_s=10)
+    time.sleep(0.5)
+    hq_env.check_process_exited(process, expected_code=None)
+
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+
+
+def test_server_audit(hq_env: HqEnv, tmp_path):
+    hq_env.start_server()
+    hq_env.command(["submit", "--", "sleep", "100"])
+    hq_env.command(["submit", "--array=1-4", "--", "hostname"])
+    hq_env.command(["cancel", "1"])
+
+    monitor_dir = str(tmp_path / "monitor")
+    hq_env.command(["server", "export-access", monitor_dir])
+    with pytest.raises(Exception, match="Permission denied"):
+        hq_env.command(["cancel", "2"], server_dir=monitor_dir)
+
+    table = hq_env.command(["server", "audit"], as_table=True)
+    assert table[0] == [
+        "Time",
+        "User",
+        "Role",
+        "Peer",
+        "Operation",
+        "Details",
+        "Result",
+    ]
+    rows = table[1:]
+    assert [row[4] for row in rows] == ["submit", "submit", "cancel", "cancel"]
+    assert [row[2] for row in rows] == ["admin", "admin", "admin", "monitor"]
+    assert rows[0][3] == "local"
+    assert rows[0][5] == "job 1 (1 tasks) `sleep`: sleep 100"
+    assert rows[1][5] == "job 2 (4 tasks) `hostname`: hostname"
+    assert rows[2][6] == "ok"
+    assert rows[3][6].startswith("Permission denied")
+    user = rows[0][1]
+
+    table = hq_env.command(["server", "audit", "--job=1"], as_table=True)
+    assert [row[4] for row in table[1:]] == ["submit", "cancel"]
+    table = hq_env.command(["server", "audit", "--operation=cancel"], as_table=True)
+    assert len(table) == 3
+    table = hq_env.command(["server", "audit", "--user", user], as_table=True)
+    assert len(table) == 5
+    table = hq_env.command(["server", "audit", "--limit=2", "--offset=1"], as_table=True)
+    assert [row[4] for row in table[1:]] == ["submit", "cancel"]
+
+
+def test_server_names(hq_env: HqEnv):
+    hq_env.start_server(args=["--server-name", "a"])
+    process = hq_env.start_process(
+        "server-b", HqEnv.server_args(hq_env.server_dir) + ["--server-name", "b"]
+    )
+    time.sleep(0.2)
+    hq_env.check_running_processes()
+
+    assert os.path.isdir(os.path.join(hq_env.server_dir, "hq-current-a"))
+    assert os.path.isdir(os.path.join(hq_env.server_dir, "hq-current-b"))
+    assert not os.path.exists(os.path.join(hq_env.server_dir, "hq-current"))
+
+    hq_env.command(["--server-name", "a", "submit", "--", "hostname"])
+    table = hq_env.command(["--server-name", "a", "jobs"], as_table=True)
+    assert len(table) == 2
+    table = hq_env.command(["--server-name", "b", "jobs"], as_table=True)
+    assert len(table) == 1
+    with pytest.raises(Exception, match="Cannot read access record"):
+        hq_env.command("jobs")
+
+    hq_env.command(["--server-name", "b", "server", "stop"])
+    process.wait()
+    hq_env.check_process_exited(process)
+
+    table = hq_env.command(["server", "list"], as_table=True)
+    assert table[0][:3] == ["Name", "Status", "Active"]
+    assert sorted(row[:3] for row in table[1:]) == [
+        ["a", "ONLINE", "yes"],
+        ["b", "OFFLINE", "yes"],
+    ]
+
+    output = hq_env.command(["server", "prune"])
+    assert "Removed" not in output
+    assert "active instance" in output
+    assert os.path.exists(os.path.join(hq_env.server_dir, "hq-current-b"))
+
+    output = hq_env.command(["server", "prune", "--force"])
+    assert "Removed" in output
+    assert not os.path.exists(os.path.join(hq_env.server_dir, "hq-current-b"))
+    table = hq_env.command(["server", "list"], as_table=True)
+    assert [row[:2] for row in table[1:]] == [["a", "ONLINE"]]
+
+
+def test_server_metrics(hq_env: HqEnv):
+    port = get_free_port()
+    hq_env.start_server(args=["--metrics-port", str(port)])
+    hq_env.start_worker(cpus=2)
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+    hq_env.command(["submit", "--array=1-3", "--", "hostname"])
+    hq_env.command(["submit", "--", "/non-existent-program"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+    wait_for_job_state(hq_env, 2, "FAILED")
+
+    with urllib.request.urlopen(f"http://127.0.0.1:{port}/metrics") as response:
+        assert response.headers["Content-Type"].startswith("text/plain")
+        lines = response.read().decode().splitlines()
+    assert 'hq_jobs{state="finished"} 1' in lines
+    assert 'hq_jobs{state="failed"} 1' in lines
+    assert 'hq_tasks{state="finished"} 3' in lines
+    assert "hq_submitted_tasks_total 4" in lines
+    assert "hq_finished_tasks_total 3" in lines
+    assert "hq_failed_tasks_total 1" in lines
+    assert "hq_cpus 2" in lines
+    assert any(line.startswith('hq_worker_cpus{worker="1"') for line in lines)
+    assert 'hq_client_requests_total{request="submit"} 2' in lines
+    assert any(
+        line.startswith("hq_tako_request_duration_seconds_count ") for line in lines
+    )
+
+    with pytest.raises(urllib.error.HTTPError, match="404"):
+        urllib.request.urlopen(f"http://127.0.0.1:{port}/foo")
+
+
+def test_server_metrics_worker_allocated_cpus(hq_env: HqEnv):
+    port = get_free_port()
+    hq_env.start_server(args=["--metrics-port", str(port)])
+    hq_env.start_worker(cpus=4, args=["--heartbeat", "500ms"])
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+
+    def allocated_cpus():
+        with urllib.request.urlopen(f"http://127.0.0.1:{port}/metrics") as response:
+            lines = response.read().decode().splitlines()
+        return [
+            line.split()[-1]
+            for line in lines
+            if line.startswith('hq_worker_allocated_cpus{worker="1"')
+        ]
+
+    hq_env.command(["submit", "--cpus", "3", "--", "sleep", "100"])
+    wait_until(lambda: allocated_cpus() == ["3"])
+    hq_env.command(["cancel", "1"])
+    wait_until(lambda: allocated_cpus() == ["0"])
+
+
+def test_server_drain(hq_env: HqEnv):
+    server = hq_env.start_server()
+    worker = hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--", "bash", "-c", "sleep 1; echo done"])
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "RUNNING")
+
+    drain = hq_env.start_process(
+        "drain",
+        [HQ_BINARY, "--server-dir", hq_env.server_dir, "server", "stop", "--drain"],
+    )
+    time.sleep(0.3)
+    with pytest.raises(Exception, match="new jobs are not accepted"):
+        hq_env.command(["submit", "--", "hostname"])
+
+    drain.wait(timeout=10)
+    hq_env.check_process_exited(drain)
+    server.wait(timeout=5)
+    hq_env.check_process_exited(server)
+    worker.wait(timeout=5)
+    hq_env.check_process_exited(worker)
+
+    with open("stdout.1.0") as f:
+        assert f.read() == "done\n"
+    assert not os.path.exists("stdout.2.0")
+    with open("drain.out") as f:
+        output = f.read()
+    assert "Waiting for 1 running task(s)" in output
+    assert "1 task(s) that had not started were not executed" in output
+
+
+def test_server_drain_cancel_waiting(hq_env: HqEnv):
+    server = hq_env.start_server()
+    worker = hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--", "sleep", "1"])
+    hq_env.command(["submit", "--array=1-3", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "RUNNING")
+
+    output = hq_env.command(["server", "stop", "--drain", "--cancel-waiting"])
+    assert "3 task(s) that had not started were canceled" in output
+    server.wait(timeout=5)
+    hq_env.check_process_exited(server)
+    worker.wait(timeout=5)
+    hq_env.check_process_exited(worker)
+
+
+def test_server_drain_timeout(hq_env: HqEnv):
+    server = hq_env.start_server()
+    worker = hq_env.start_worker(cpus=1)
+    hq_env.command(["submit", "--", "sleep", "100"])
+    wait_for_job_state(hq_env, 1, "RUNNING")
+
+    output = hq_env.command(["server", "stop", "--drain", "--timeout", "1s"])
+    assert "1 task(s) were still running" in output
+    server.wait(timeout=5)
+    hq_env.check_process_exited(server)
+    worker.wait(timeout=5)
+    hq_env.check_process_exited(worker)
+
+
+def test_server_logs(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker()
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+
+    instance_dir = os.path.join(hq_env.server_dir, "hq-current")
+    assert os.path.isfile(os.path.join(instance_dir, "server.log"))
+    assert os.path.isfile(os.path.join(instance_dir, "worker-1.log"))
+
+    output = hq_env.command(["server", "logs"])
+    assert "New worker id=1" in output
+
+    follow = hq_env.start_process(
+        "follow", [HQ_BINARY, "--server-dir", hq_env.server_dir, "server", "logs", "--follow"]
+    )
+    hq_env.command(["submit", "--", "hostname"])
+    wait_for_job_state(hq_env, 1, "FINISHED")
+    time.sleep(1)
+    hq_env.kill_process("follow")
+    follow.wait()
+    with open("follow.out") as f:
+        assert "Task id=1 updated" in f.read()
@@ -2,7 +2,7 @@ import time
 from socket import gethostname
 
 from .conftest import HqEnv
-from .utils import wait_for_worker_state
+from .utils import wait_for_worker_state, wait_until
 
 
 def test_worker_list(hq_env: HqEnv):
@@ -149,3 +149,48 @@ def test_worker_address(hq_env: HqEnv):
 
     output = hq_env.command(["worker", "address", "1"]).strip()
     assert output == gethostname()
+
+
+def test_worker_reconnect(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(args=["--reconnect"])
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+
+    hq_env.kill_process("server")
+    # The server directory is named by the start time, so the new server needs a new second
+    time.sleep(1.0)
+    hq_env.start_server()
+
+    wait_for_worker_state(hq_env, 1, "RUNNING", timeout_s=10)
+
+
+def test_worker_reconnect_stop(hq_env: HqEnv):
+    hq_env.start_server()
+    process = hq_env.start_worker(args=["--reconnect"])
+
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+    hq_env.command(["worker", "stop", "1"])
+    wait_for_worker_state(hq_env, 1, "STOPPED")
+    time.sleep(0.5)
+    hq_env.check_process_exited(process)
+
+
+def test_worker_stats(hq_env: HqEnv):
+    hq_env.start_server()
+    hq_env.start_worker(args=["--heartbeat", "500ms"])
+    wait_for_worker_state(hq_env, 1, "RUNNING")
+
+    table = hq_env.command(["worker", "list", "--stats"], as_table=True)
+    assert table[0][6] == "Stats"
+
+    def has_stats():
+        table = hq_env.command(["worker", "list", "--stats"], as_table=True)
+        return table[1][6].startswith("CPU")
+
+    wait_until(has_stats)
+
+    table = hq_env.command(["worker", "info", "1"], as_table=True)
+    assert table[10][0] == "Last update"
+    assert table[11][0] == "CPU usage"
+    assert table[12][0] == "Memory usage"
+    assert table[13][0] == "Load average"